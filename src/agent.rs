@@ -0,0 +1,344 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crypto::ProjectKey;
+
+/// Environment variable pointing the CLI at a running agent
+pub const AGENT_SOCK_ENV: &str = "SECRETS_MANAGER_AGENT_SOCK";
+
+/// Requests understood by the agent, sent as one JSON document per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum AgentRequest {
+    Get { project: String },
+    Add { project: String, key: String },
+    Remove { project: String },
+    Lock,
+    List,
+}
+
+/// Responses sent back by the agent
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AgentResponse {
+    Ok,
+    Key { key: String },
+    Projects { projects: Vec<String> },
+    NotFound,
+    Error { message: String },
+}
+
+/// Client side of the agent protocol
+#[derive(Clone)]
+pub struct AgentClient {
+    socket_path: PathBuf,
+}
+
+impl AgentClient {
+    pub fn new(socket_path: impl Into<PathBuf>) -> Self {
+        Self { socket_path: socket_path.into() }
+    }
+
+    /// Returns a client if `SECRETS_MANAGER_AGENT_SOCK` is set
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os(AGENT_SOCK_ENV)
+            .filter(|path| !path.is_empty())
+            .map(Self::new)
+    }
+
+    /// Fetches the cached key for a project, if the agent holds one
+    pub fn get_key(&self, project_name: &str) -> Result<Option<ProjectKey>> {
+        match self.request(&AgentRequest::Get { project: project_name.to_string() })? {
+            AgentResponse::Key { key } => {
+                let key = zeroize::Zeroizing::new(general_purpose::STANDARD.decode(key)?);
+                Ok(Some(ProjectKey::from_bytes(&key)?))
+            }
+            AgentResponse::NotFound => Ok(None),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    /// Hands a derived key to the agent for caching
+    pub fn add_key(&self, project_name: &str, key: &ProjectKey) -> Result<()> {
+        let request = AgentRequest::Add {
+            project: project_name.to_string(),
+            key: general_purpose::STANDARD.encode(key.key_bytes()),
+        };
+        self.expect_ok(&request)
+    }
+
+    /// Drops the cached key for a single project
+    pub fn remove_key(&self, project_name: &str) -> Result<()> {
+        self.expect_ok(&AgentRequest::Remove { project: project_name.to_string() })
+    }
+
+    /// Drops every cached key
+    pub fn lock(&self) -> Result<()> {
+        self.expect_ok(&AgentRequest::Lock)
+    }
+
+    /// Lists the projects the agent currently holds keys for
+    pub fn list(&self) -> Result<Vec<String>> {
+        match self.request(&AgentRequest::List)? {
+            AgentResponse::Projects { projects } => Ok(projects),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    fn expect_ok(&self, request: &AgentRequest) -> Result<()> {
+        match self.request(request)? {
+            AgentResponse::Ok => Ok(()),
+            other => Err(unexpected_response(other)),
+        }
+    }
+
+    #[cfg(unix)]
+    fn request(&self, request: &AgentRequest) -> Result<AgentResponse> {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::net::UnixStream;
+
+        let mut stream = UnixStream::connect(&self.socket_path).map_err(|e| {
+            anyhow!("Could not connect to agent at '{}': {}", self.socket_path.display(), e)
+        })?;
+
+        let mut line = zeroize::Zeroizing::new(serde_json::to_string(request)?);
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut response = zeroize::Zeroizing::new(String::new());
+        BufReader::new(stream).read_line(&mut response)?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    #[cfg(not(unix))]
+    fn request(&self, _request: &AgentRequest) -> Result<AgentResponse> {
+        Err(anyhow!("The agent is only supported on Unix platforms"))
+    }
+}
+
+fn unexpected_response(response: AgentResponse) -> anyhow::Error {
+    match response {
+        AgentResponse::Error { message } => anyhow!("Agent error: {}", message),
+        _ => anyhow!("Unexpected response from agent"),
+    }
+}
+
+/// Keys held by a running agent
+#[derive(Default)]
+struct AgentState {
+    keys: HashMap<String, ProjectKey>,
+}
+
+impl AgentState {
+    fn handle(&mut self, request: AgentRequest) -> AgentResponse {
+        match request {
+            AgentRequest::Get { project } => match self.keys.get(&project) {
+                Some(key) => AgentResponse::Key {
+                    key: general_purpose::STANDARD.encode(key.key_bytes()),
+                },
+                None => AgentResponse::NotFound,
+            },
+            AgentRequest::Add { project, key } => {
+                let parsed = general_purpose::STANDARD.decode(key).ok().and_then(|key| {
+                    ProjectKey::from_bytes(&zeroize::Zeroizing::new(key)).ok()
+                });
+                match parsed {
+                    Some(key) => {
+                        self.keys.insert(project, key);
+                        AgentResponse::Ok
+                    }
+                    None => AgentResponse::Error { message: "Invalid key material".to_string() },
+                }
+            }
+            AgentRequest::Remove { project } => {
+                self.keys.remove(&project);
+                AgentResponse::Ok
+            }
+            AgentRequest::Lock => {
+                self.keys.clear();
+                AgentResponse::Ok
+            }
+            AgentRequest::List => {
+                let mut projects: Vec<String> = self.keys.keys().cloned().collect();
+                projects.sort();
+                AgentResponse::Projects { projects }
+            }
+        }
+    }
+}
+
+/// Default socket location: a private per-process directory under the system temp dir
+pub fn default_socket_path() -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("secrets-manager-{}", std::process::id()))
+        .join("agent.sock")
+}
+
+/// How long a client may take to send its request before it is dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs the agent in the foreground until the process is killed
+///
+/// Cached keys are dropped after `idle_timeout` without any request. The
+/// socket's directory is created owner-only if it doesn't exist; an existing
+/// one must already be, and an agent still listening on the socket is left alone.
+/// `ready` is called once the socket accepts connections.
+#[cfg(unix)]
+pub fn run_agent(socket_path: &Path, idle_timeout: Duration, ready: impl FnOnce()) -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    if idle_timeout.is_zero() {
+        return Err(anyhow!("The agent's idle timeout must be at least one second"));
+    }
+
+    // Only the owner may reach the socket
+    let parent = match socket_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if parent.exists() {
+        let mode = std::fs::metadata(parent)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(anyhow!(
+                "'{}' can be reached by other users; put the agent socket in a directory only you can access",
+                parent.display()
+            ));
+        }
+    } else {
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+        std::fs::set_permissions(parent, std::fs::Permissions::from_mode(0o700))?;
+    }
+
+    if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("'{}' exists and is not a socket", socket_path.display()));
+        }
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(anyhow!("An agent is already running at '{}'", socket_path.display()));
+        }
+        // Left behind by an agent that is gone
+        std::fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(0o600))?;
+    ready();
+
+    let state = Arc::new(Mutex::new((AgentState::default(), Instant::now())));
+
+    // Expire keys once the agent has been idle for too long
+    {
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1).min(idle_timeout));
+            let mut guard = state.lock().unwrap_or_else(|e| e.into_inner());
+            if guard.1.elapsed() >= idle_timeout && !guard.0.keys.is_empty() {
+                guard.0.keys.clear();
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        // Requests are handled one at a time, so a silent client mustn't hold up the others
+        if stream.set_read_timeout(Some(CLIENT_TIMEOUT)).is_err() || stream.set_write_timeout(Some(CLIENT_TIMEOUT)).is_err() {
+            continue;
+        }
+        let mut line = zeroize::Zeroizing::new(String::new());
+        if BufReader::new(&stream).read_line(&mut line).is_err() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<AgentRequest>(&line) {
+            Ok(request) => {
+                let mut guard = state.lock().unwrap_or_else(|e| e.into_inner());
+                guard.1 = Instant::now();
+                guard.0.handle(request)
+            }
+            Err(e) => AgentResponse::Error { message: format!("Invalid request: {}", e) },
+        };
+
+        if let Ok(json) = serde_json::to_string(&response) {
+            let mut json = zeroize::Zeroizing::new(json);
+            json.push('\n');
+            let _ = stream.write_all(json.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn run_agent(_socket_path: &Path, _idle_timeout: Duration, _ready: impl FnOnce()) -> Result<()> {
+    Err(anyhow!("The agent is only supported on Unix platforms"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_agent_caches_and_locks_keys() {
+        let temp_dir = TempDir::new().unwrap();
+        let socket_path = temp_dir.path().join("agent").join("agent.sock");
+
+        let server_path = socket_path.clone();
+        std::thread::spawn(move || run_agent(&server_path, Duration::from_secs(60), || {}));
+        while !socket_path.exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = AgentClient::new(&socket_path);
+        let key = ProjectKey::from_bytes(&[7; 32]).unwrap();
+
+        assert!(client.get_key("api").unwrap().is_none());
+        client.add_key("api", &key).unwrap();
+
+        let cached = client.get_key("api").unwrap().unwrap();
+        assert_eq!(cached.key_bytes(), key.key_bytes());
+        assert_eq!(client.list().unwrap(), vec!["api".to_string()]);
+
+        client.lock().unwrap();
+        assert!(client.get_key("api").unwrap().is_none());
+
+        // A client that never finishes its request doesn't block the others
+        let _silent = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+        assert!(client.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_agent_refuses_unsafe_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let shared = temp_dir.path().join("shared");
+        std::fs::create_dir(&shared).unwrap();
+        std::fs::set_permissions(&shared, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let err = run_agent(&shared.join("agent.sock"), Duration::from_secs(60), || {}).unwrap_err();
+        assert!(err.to_string().contains("other users"));
+        assert_eq!(std::fs::metadata(&shared).unwrap().permissions().mode() & 0o777, 0o755);
+
+        assert!(run_agent(&temp_dir.path().join("agent.sock"), Duration::ZERO, || {}).is_err());
+
+        let socket_path = temp_dir.path().join("private").join("agent.sock");
+        let server_path = socket_path.clone();
+        std::thread::spawn(move || run_agent(&server_path, Duration::from_secs(60), || {}));
+        while !socket_path.exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let err = run_agent(&socket_path, Duration::from_secs(60), || {}).unwrap_err();
+        assert!(err.to_string().contains("already running"));
+        assert!(AgentClient::new(&socket_path).list().is_ok());
+    }
+}
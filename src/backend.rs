@@ -0,0 +1,536 @@
+use anyhow::{Result, anyhow};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::git::{self, GitBackend, GitRepo};
+use crate::revisions::{self, RevisionBackend};
+
+/// How long to wait for another writer before giving up on a lock
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where encrypted projects are kept
+///
+/// Backends only ever see opaque encrypted blobs keyed by project name;
+/// serialization and encryption stay in `SecretStorage`.
+pub trait StorageBackend {
+    /// Reads a project's blob, or `None` if there is no such project
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Creates or replaces a project's blob
+    fn put(&self, name: &str, data: &[u8]) -> Result<()>;
+
+    /// Reads a project's blob along with a token for the version read
+    ///
+    /// Backends that rely on `lock` rather than versions return no token.
+    fn get_versioned(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        Ok(self.get(name)?.map(|data| (data, None)))
+    }
+
+    /// Writes a blob only if the project is still at `version`, from `get_versioned`
+    ///
+    /// `None` means the project didn't exist, or the backend gave no token.
+    /// A project changed in between is `Error::Locked`.
+    fn put_if(&self, name: &str, data: &[u8], version: Option<&str>) -> Result<()> {
+        let _ = version;
+        self.put(name, data)
+    }
+
+    /// Writes several blobs; backends that can do so apply them atomically
+    fn put_many(&self, blobs: &[(String, Vec<u8>)]) -> Result<()> {
+        for (name, data) in blobs {
+            self.put(name, data)?;
+        }
+        Ok(())
+    }
+
+    /// Lists the names of all stored projects, sorted
+    fn list(&self) -> Result<Vec<String>>;
+
+    /// Removes a project's blob, returning whether it existed
+    fn delete(&self, name: &str) -> Result<bool>;
+
+    /// Takes an exclusive lock on a project, held until the guard is dropped
+    fn lock(&self, name: &str) -> Result<StorageLock>;
+
+    /// Versions of a project written concurrently with the one `get` returns
+    ///
+    /// Only backends that tolerate concurrent writers, like revision storage,
+    /// ever report forks.
+    fn forks(&self, name: &str) -> Result<Vec<Fork>> {
+        let _ = name;
+        Ok(Vec::new())
+    }
+
+    /// Writes a blob that supersedes the given forks
+    fn put_merged(&self, name: &str, data: &[u8], forks: &[String]) -> Result<()> {
+        let _ = forks;
+        self.put(name, data)
+    }
+}
+
+/// A concurrently written version of a project
+pub struct Fork {
+    pub id: String,
+    /// The fork's blob, or `None` if the project was deleted on that side
+    pub data: Option<Vec<u8>>,
+    /// The version both sides started from, if it is still known
+    pub base: Option<Vec<u8>>,
+}
+
+impl StorageBackend for Box<dyn StorageBackend> {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(name)
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        (**self).put(name, data)
+    }
+
+    fn get_versioned(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        (**self).get_versioned(name)
+    }
+
+    fn put_if(&self, name: &str, data: &[u8], version: Option<&str>) -> Result<()> {
+        (**self).put_if(name, data, version)
+    }
+
+    fn put_many(&self, blobs: &[(String, Vec<u8>)]) -> Result<()> {
+        (**self).put_many(blobs)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        (**self).list()
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        (**self).delete(name)
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        (**self).lock(name)
+    }
+
+    fn forks(&self, name: &str) -> Result<Vec<Fork>> {
+        (**self).forks(name)
+    }
+
+    fn put_merged(&self, name: &str, data: &[u8], forks: &[String]) -> Result<()> {
+        (**self).put_merged(name, data, forks)
+    }
+}
+
+impl<T: StorageBackend + ?Sized> StorageBackend for &T {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        (**self).get(name)
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        (**self).put(name, data)
+    }
+
+    fn get_versioned(&self, name: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        (**self).get_versioned(name)
+    }
+
+    fn put_if(&self, name: &str, data: &[u8], version: Option<&str>) -> Result<()> {
+        (**self).put_if(name, data, version)
+    }
+
+    fn put_many(&self, blobs: &[(String, Vec<u8>)]) -> Result<()> {
+        (**self).put_many(blobs)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        (**self).list()
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        (**self).delete(name)
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        (**self).lock(name)
+    }
+
+    fn forks(&self, name: &str) -> Result<Vec<Fork>> {
+        (**self).forks(name)
+    }
+
+    fn put_merged(&self, name: &str, data: &[u8], forks: &[String]) -> Result<()> {
+        (**self).put_merged(name, data, forks)
+    }
+}
+
+/// Guard for a lock taken with `StorageBackend::lock`
+pub struct StorageLock {
+    release: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl StorageLock {
+    pub fn new(release: impl FnOnce() + Send + 'static) -> Self {
+        Self { release: Some(Box::new(release)) }
+    }
+}
+
+impl Drop for StorageLock {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+/// The default backend: one `<name>.encrypted` file per project in a directory
+pub struct DirectoryBackend {
+    dir: PathBuf,
+}
+
+impl DirectoryBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn project_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.encrypted", name))
+    }
+}
+
+impl StorageBackend for DirectoryBackend {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.project_path(name)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated project
+        let path = self.project_path(name);
+        let temp_path = self.dir.join(format!(".{}.encrypted.tmp", name));
+        fs::write(&temp_path, data)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut projects = Vec::new();
+
+        if !self.dir.exists() {
+            return Ok(projects);
+        }
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("encrypted") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    projects.push(stem.to_string());
+                }
+            }
+        }
+
+        projects.sort();
+        Ok(projects)
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        match fs::remove_file(self.project_path(name)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        let lock_path = self.dir.join(format!("{}.lock", name));
+        let started = Instant::now();
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(_) => return Ok(StorageLock::new(move || {
+                    let _ = fs::remove_file(lock_path);
+                })),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    if started.elapsed() >= LOCK_TIMEOUT {
+                        return Err(Error::Locked(format!(
+                            "Project '{}' is locked by another process (remove '{}' if it is stale)",
+                            name, lock_path.display()
+                        )).into());
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// The default vault directory, `~/.secrets_manager`
+pub fn default_dir() -> Result<PathBuf> {
+    let home_dir = dirs::home_dir()
+        .ok_or_else(|| anyhow!("Could not find home directory"))?;
+    Ok(home_dir.join(".secrets_manager"))
+}
+
+/// File name of the SQLite vault inside the vault directory
+const SQLITE_VAULT: &str = "vault.sqlite";
+/// Names the bucket of an S3 vault; kept here so builds without the `s3` feature can report it
+pub(crate) const S3_BUCKET_ENV: &str = "SECRETS_MANAGER_S3_BUCKET";
+
+/// How projects are laid out in the vault directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageLayout {
+    /// One `<name>.encrypted` file per project
+    Files,
+    /// A single `vault.sqlite` database
+    Sqlite,
+    /// Project files in a git repository, one commit per change
+    Git,
+    /// Append-only revision files, safe in folders shared by sync tools
+    Revisions,
+    /// Objects in the S3-compatible bucket configured through the environment
+    S3,
+}
+
+impl StorageLayout {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "files" => Ok(Self::Files),
+            "sqlite" => Ok(Self::Sqlite),
+            "git" => Ok(Self::Git),
+            "revisions" => Ok(Self::Revisions),
+            "s3" => Ok(Self::S3),
+            _ => Err(anyhow!("Unsupported storage layout '{}'. Use: files, sqlite, git, revisions or s3", s)),
+        }
+    }
+
+    /// The layout of a vault directory: SQLite once `vault.sqlite` exists,
+    /// revisions once there is a `revisions` directory, git once it is a repository
+    pub fn detect(dir: &Path) -> Self {
+        if dir.join(SQLITE_VAULT).exists() {
+            Self::Sqlite
+        } else if revisions::is_revision_vault(dir) {
+            Self::Revisions
+        } else if git::is_repo(dir) {
+            Self::Git
+        } else {
+            Self::Files
+        }
+    }
+
+    /// The layout in use: S3 whenever a bucket is configured, otherwise that of the directory
+    pub fn current(dir: &Path) -> Self {
+        match std::env::var(S3_BUCKET_ENV) {
+            Ok(bucket) if !bucket.is_empty() => Self::S3,
+            _ => Self::detect(dir),
+        }
+    }
+
+    /// Opens a vault directory with this layout; S3 ignores the directory
+    pub fn open(self, dir: &Path) -> Result<Box<dyn StorageBackend>> {
+        match self {
+            Self::Files => Ok(Box::new(DirectoryBackend::new(dir)?)),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite => Ok(Box::new(crate::sqlite::SqliteBackend::open(&dir.join(SQLITE_VAULT))?)),
+            #[cfg(not(feature = "sqlite"))]
+            Self::Sqlite => Err(anyhow!(
+                "The vault in '{}' uses SQLite storage, but this build lacks the `sqlite` feature",
+                dir.display()
+            )),
+            Self::Git => Ok(Box::new(GitBackend::open(dir)?)),
+            Self::Revisions => Ok(Box::new(RevisionBackend::open(dir)?)),
+            #[cfg(feature = "s3")]
+            Self::S3 => {
+                let config = crate::s3::S3Config::from_env()?
+                    .ok_or_else(|| anyhow!("Set {} to the bucket that holds the vault", S3_BUCKET_ENV))?;
+                Ok(Box::new(crate::s3::S3Backend::new(config)))
+            }
+            #[cfg(not(feature = "s3"))]
+            Self::S3 => Err(anyhow!(
+                "{} is set, but this build lacks the `s3` feature",
+                S3_BUCKET_ENV
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Files => write!(f, "files"),
+            Self::Sqlite => write!(f, "sqlite"),
+            Self::Git => write!(f, "git"),
+            Self::Revisions => write!(f, "revisions"),
+            Self::S3 => write!(f, "s3"),
+        }
+    }
+}
+
+/// Opens the default vault with whichever layout it currently uses
+pub fn open_default() -> Result<Box<dyn StorageBackend>> {
+    let dir = default_dir()?;
+    StorageLayout::current(&dir).open(&dir)
+}
+
+/// Moves every project in a vault directory to another layout
+///
+/// All blobs are copied and read back before the old layout is removed, so
+/// an interrupted migration leaves the original projects in place.
+/// Projects copied out of an S3 bucket stay there, since others may share it.
+/// Returns the number of projects moved.
+pub fn migrate(dir: &Path, to: StorageLayout) -> Result<usize> {
+    let from = match to {
+        StorageLayout::S3 => StorageLayout::detect(dir),
+        _ => StorageLayout::current(dir),
+    };
+    if from == to {
+        return Err(anyhow!("The vault already uses {} storage", to));
+    }
+
+    // Git tracks the project files in place, so only the repository comes and goes
+    match (from, to) {
+        (StorageLayout::Files, StorageLayout::Git) => {
+            GitRepo::init(dir)?;
+            return Ok(from.open(dir)?.list()?.len());
+        }
+        (StorageLayout::Git, StorageLayout::Files) => {
+            git::remove_repo(dir)?;
+            return Ok(to.open(dir)?.list()?.len());
+        }
+        (_, StorageLayout::Git) => {
+            GitRepo::init(dir)?;
+        }
+        _ => {}
+    }
+
+    let source = from.open(dir)?;
+    let mut blobs = Vec::new();
+    for name in source.list()? {
+        let data = source.get(&name)?
+            .ok_or_else(|| anyhow!("Project '{}' disappeared during migration", name))?;
+        blobs.push((name, data));
+    }
+
+    let target = to.open(dir)?;
+    target.put_many(&blobs)?;
+    for (name, data) in &blobs {
+        if target.get(name)?.as_ref() != Some(data) {
+            return Err(anyhow!("Project '{}' did not survive migration; the old layout was kept", name));
+        }
+    }
+
+    drop(source);
+    match from {
+        StorageLayout::Files | StorageLayout::Git => {
+            for (name, _) in &blobs {
+                fs::remove_file(dir.join(format!("{}.encrypted", name)))?;
+            }
+            if from == StorageLayout::Git {
+                git::remove_repo(dir)?;
+            }
+        }
+        StorageLayout::Sqlite => fs::remove_file(dir.join(SQLITE_VAULT))?,
+        StorageLayout::Revisions => revisions::remove_revisions(dir)?,
+        StorageLayout::S3 => {}
+    }
+    Ok(blobs.len())
+}
+
+/// Keeps projects in memory; clones share the same contents
+///
+/// Meant for tests and for embedding without touching the file system.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    locks: Arc<Mutex<HashSet<String>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let blobs = self.blobs.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(blobs.get(name).cloned())
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        let mut blobs = self.blobs.lock().unwrap_or_else(|e| e.into_inner());
+        blobs.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let blobs = self.blobs.lock().unwrap_or_else(|e| e.into_inner());
+        let mut projects: Vec<String> = blobs.keys().cloned().collect();
+        projects.sort();
+        Ok(projects)
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        let mut blobs = self.blobs.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(blobs.remove(name).is_some())
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        if !locks.insert(name.to_string()) {
+            return Err(Error::Locked(format!("Project '{}' is locked", name)).into());
+        }
+
+        let locks = Arc::clone(&self.locks);
+        let name = name.to_string();
+        Ok(StorageLock::new(move || {
+            locks.lock().unwrap_or_else(|e| e.into_inner()).remove(&name);
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn exercise(backend: &dyn StorageBackend) {
+        assert!(backend.get("a").unwrap().is_none());
+        backend.put("b", b"two").unwrap();
+        backend.put("a", b"one").unwrap();
+        assert_eq!(backend.get("a").unwrap().unwrap(), b"one");
+        assert_eq!(backend.list().unwrap(), vec!["a".to_string(), "b".to_string()]);
+
+        assert!(backend.delete("a").unwrap());
+        assert!(!backend.delete("a").unwrap());
+        assert_eq!(backend.list().unwrap(), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_backends_store_blobs() {
+        let temp_dir = TempDir::new().unwrap();
+        exercise(&DirectoryBackend::new(temp_dir.path()).unwrap());
+        exercise(&MemoryBackend::new());
+    }
+
+    #[test]
+    fn test_memory_lock_is_exclusive() {
+        let backend = MemoryBackend::new();
+        let guard = backend.lock("a").unwrap();
+        assert!(backend.lock("a").is_err());
+        assert!(backend.lock("b").is_ok());
+        drop(guard);
+        assert!(backend.lock("a").is_ok());
+    }
+}
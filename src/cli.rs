@@ -1,28 +1,213 @@
 use clap::{Parser, Subcommand};
 use anyhow::Result;
-use rpassword::read_password;
-use std::io::{self, Write};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
 
-use crate::models::{Project, ExportFormat};
+use crate::agent::{self, AgentClient};
+use crate::backend::{self, StorageBackend, StorageLayout};
+use crate::console::{Output, Prompter, Terminal};
+use crate::crypto::{self, Credentials, ProjectKey};
+use crate::error::Error;
+use crate::models::{CredentialKind, EncryptedProject, KeySlot};
+use crate::identity::{self, Identity, Recipient};
+use crate::interpolate;
+use crate::git::GitRepo;
+use crate::merge::{self, Merged, SecretMerge};
+use crate::generate::{generate_recovery_code, generate_secret, GenerateKind, GenerateOptions};
+use crate::models::{Environment, Project, ExportFormat};
+use crate::secret::SecretString;
+use crate::server::{self, ApiServer, ListenAddr};
+use crate::shamir::Share;
+use crate::schema::{self, Schema};
 use crate::storage::SecretStorage;
+use crate::template;
+
+/// Exit code of a command that failed
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code for invalid command-line usage
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code when the password or other credentials don't unlock the project
+pub const EXIT_WRONG_PASSWORD: i32 = 3;
+/// Exit code when the project doesn't exist
+pub const EXIT_NOT_FOUND: i32 = 4;
+/// Exit code when a stored project can't be decoded or decrypted with its key
+pub const EXIT_CORRUPT: i32 = 5;
+/// Exit code when reading or writing files failed
+pub const EXIT_IO: i32 = 6;
+/// Exit code for a project file written in a format this version can't read
+pub const EXIT_UNSUPPORTED_VERSION: i32 = 7;
+/// Exit code when another process holds or changed the project
+pub const EXIT_LOCKED: i32 = 8;
+
+/// The exit code for a failed command, from the first library `Error` in its chain
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    for cause in error.chain() {
+        if let Some(error) = cause.downcast_ref::<Error>() {
+            return match error {
+                Error::WrongPassword | Error::InvalidCredentials(_) => EXIT_WRONG_PASSWORD,
+                Error::InvalidName(_) => EXIT_USAGE,
+                Error::NotFound(_) => EXIT_NOT_FOUND,
+                Error::Corrupt(_) | Error::StaleKey(_) => EXIT_CORRUPT,
+                Error::Io(_) => EXIT_IO,
+                Error::UnsupportedVersion(_) => EXIT_UNSUPPORTED_VERSION,
+                Error::Locked(_) => EXIT_LOCKED,
+                Error::Other(_) => continue,
+            };
+        }
+        if let Some(CommandFailed(code)) = cause.downcast_ref() {
+            return *code;
+        }
+        if cause.is::<io::Error>() {
+            return EXIT_IO;
+        }
+    }
+    EXIT_FAILURE
+}
+
+/// A command started by `run` that exited unsuccessfully
+///
+/// The CLI exits with the same status, so `run` can stand in for the command in scripts.
+#[derive(Debug)]
+pub struct CommandFailed(pub i32);
+
+impl std::fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command exited with status {}", self.0)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// How commands report their results
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON document on stdout; prompts and messages for people go to stderr
+    Json,
+}
+
+impl OutputFormat {
+    /// The format asked for on a command line that failed to parse
+    ///
+    /// The line is parsed again, ignoring errors, so only a real `--output`
+    /// option counts and not, say, a secret's value that happens to match.
+    pub fn from_args(args: &[String]) -> Self {
+        use clap::CommandFactory;
+        Cli::command()
+            .ignore_errors(true)
+            .try_get_matches_from(args)
+            .ok()
+            .and_then(|matches| matches.get_one::<OutputArg>("output").cloned())
+            .map(|output| output.format())
+            .unwrap_or_default()
+    }
+}
+
+/// What `--output` was given
+///
+/// `export`, `render` and `keygen` used to take the file to write as
+/// `--output`. A value that isn't a format is still accepted there, as a
+/// deprecated spelling of `--out-file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputArg {
+    Format(OutputFormat),
+    File(String),
+}
+
+impl OutputArg {
+    fn parse(value: &str) -> Result<Self, std::convert::Infallible> {
+        Ok(match clap::ValueEnum::from_str(value, false) {
+            Ok(format) => Self::Format(format),
+            Err(_) => Self::File(value.to_string()),
+        })
+    }
+    
+    fn format(&self) -> OutputFormat {
+        match self {
+            Self::Format(format) => *format,
+            Self::File(_) => OutputFormat::Text,
+        }
+    }
+}
+
+/// Prints a failed command's error in the requested format and returns the exit code
+///
+/// JSON errors are printed to stdout as `{"ok": false, "error": {"message", "exit_code"}}`
+/// so wrappers only ever have to parse one stream.
+pub fn report_error(output: OutputFormat, error: &anyhow::Error, exit_code: i32) -> i32 {
+    match output {
+        OutputFormat::Text => eprintln!("Error: {:#}", error),
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::json!({ "ok": false, "error": { "message": format!("{:#}", error), "exit_code": exit_code } })
+        ),
+    }
+    exit_code
+}
 
 #[derive(Parser)]
 #[command(name = "secrets-manager")]
 #[command(about = "A secure local secrets manager for development")]
 #[command(version)]
 pub struct Cli {
+    /// Output format: text or json
+    #[arg(long, global = true, default_value = "text", value_parser = OutputArg::parse)]
+    pub output: OutputArg,
+    /// Keyfile used to unlock projects (alone or together with the password)
+    #[arg(long, global = true)]
+    pub keyfile: Option<PathBuf>,
+    /// Identity file for projects shared with your public key
+    #[arg(long, global = true)]
+    pub identity: Option<PathBuf>,
     #[command(subcommand)]
     pub command: Commands,
 }
 
+impl Cli {
+    /// The output format asked for
+    pub fn output_format(&self) -> OutputFormat {
+        self.output.format()
+    }
+    
+    /// Moves a deprecated `--output <file>` to the command's `--out-file`
+    ///
+    /// Returns the deprecation warning to show. Commands that write no file
+    /// only accept a format.
+    pub fn apply_deprecated_output(&mut self) -> Result<Option<String>> {
+        let OutputArg::File(path) = &self.output else {
+            return Ok(None);
+        };
+        let path = path.clone();
+        let already_set = match &mut self.command {
+            Commands::Export { out_file, .. } | Commands::Render { out_file, .. } => out_file.replace(path.clone()).is_some(),
+            Commands::Keygen { out_file } => out_file.replace(PathBuf::from(&path)).is_some(),
+            _ => return Err(anyhow::anyhow!("Invalid output format '{}': expected text or json", path)),
+        };
+        if already_set {
+            return Err(anyhow::anyhow!("Both --output and --out-file name a file to write; use --out-file only"));
+        }
+        self.output = OutputArg::Format(OutputFormat::Text);
+        Ok(Some(format!("`--output <file>` is deprecated; use `--out-file {}`", path)))
+    }
+}
+
 #[derive(Subcommand)]
 pub enum Commands {
     /// Create a new project
     Create {
         /// Name of the project
         project_name: String,
+        /// Unlock with the keyfile only (requires --keyfile)
+        #[arg(long)]
+        no_password: bool,
+        /// Number of one-time recovery codes to generate (asks if omitted)
+        #[arg(long)]
+        recovery_codes: Option<usize>,
     },
     /// List all projects
     List,
@@ -34,6 +219,59 @@ pub enum Commands {
         key: String,
         /// Secret value (if not provided, will prompt)
         value: Option<String>,
+        /// Environment to add it to (created if it doesn't exist)
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+    /// Print the value of one secret
+    Get {
+        /// Name of the project
+        project_name: String,
+        /// Secret key
+        key: String,
+        /// Environment to read it from
+        #[arg(short, long)]
+        env: Option<String>,
+        /// Print the value as stored, without expanding ${KEY} references
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Generate a random secret and store it in a project
+    Generate {
+        /// Name of the project
+        project_name: String,
+        /// Secret key
+        key: String,
+        /// Kind of value (chars, hex, base64, uuid, diceware, jwt)
+        #[arg(short, long, default_value = "chars")]
+        kind: String,
+        /// Characters for chars, bytes for hex/base64/jwt, words for diceware
+        #[arg(short, long)]
+        length: Option<usize>,
+        /// Exclude uppercase letters (chars only)
+        #[arg(long)]
+        no_uppercase: bool,
+        /// Exclude lowercase letters (chars only)
+        #[arg(long)]
+        no_lowercase: bool,
+        /// Exclude digits (chars only)
+        #[arg(long)]
+        no_digits: bool,
+        /// Include symbols (chars only)
+        #[arg(long)]
+        symbols: bool,
+        /// Word separator (diceware only)
+        #[arg(long, default_value = "-")]
+        separator: String,
+        /// Print the generated value
+        #[arg(long)]
+        show: bool,
+        /// Overwrite the secret if it already exists
+        #[arg(short = 'F', long)]
+        force: bool,
+        /// Environment to store it in (created if it doesn't exist)
+        #[arg(short, long)]
+        env: Option<String>,
     },
     /// Remove a secret from a project
     Remove {
@@ -41,11 +279,17 @@ pub enum Commands {
         project_name: String,
         /// Secret key to remove
         key: String,
+        /// Environment to remove it from
+        #[arg(short, long)]
+        env: Option<String>,
     },
     /// List secrets in a project
     Show {
         /// Name of the project
         project_name: String,
+        /// Environment to list, including the secrets it inherits
+        #[arg(short, long)]
+        env: Option<String>,
     },
     /// Export secrets in various formats
     Export {
@@ -54,12 +298,62 @@ pub enum Commands {
         /// Export format (shell, env, json)
         #[arg(short, long, default_value = "shell")]
         format: String,
-        /// Output file (optional, defaults to stdout)
-        #[arg(short, long)]
-        output: Option<String>,
+        /// File to write (optional, defaults to stdout)
+        #[arg(short = 'o', long)]
+        out_file: Option<String>,
         /// Force export (skip .gitignore check)
         #[arg(short = 'F', long)]
         force: bool,
+        /// Environment to export, including the secrets it inherits
+        #[arg(short, long)]
+        env: Option<String>,
+        /// Export values as stored, without expanding ${KEY} references
+        #[arg(long)]
+        raw: bool,
+        /// Schema listing required keys (defaults to ./secrets.schema.toml if it exists)
+        #[arg(long)]
+        schema: Option<PathBuf>,
+    },
+    /// Check a project's secrets against a schema of required keys and formats
+    Check {
+        /// Name of the project
+        project_name: String,
+        /// Schema file (defaults to ./secrets.schema.toml)
+        #[arg(long)]
+        schema: Option<PathBuf>,
+        /// Environment to check, including the secrets it inherits
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+    /// Fill {{ KEY }} placeholders in a config file template with secrets
+    Render {
+        /// Name of the project
+        project_name: String,
+        /// Template file; placeholders may add filters, e.g. {{ KEY | base64 }} (base64, url, json)
+        template: PathBuf,
+        /// File to write (optional, defaults to stdout)
+        #[arg(short = 'o', long)]
+        out_file: Option<String>,
+        /// Environment whose secrets to use, including the ones it inherits
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+    /// Run a command with a project's secrets in its environment
+    Run {
+        /// Name of the project
+        project_name: String,
+        /// Environment whose secrets to pass, including the ones it inherits
+        #[arg(short, long)]
+        env: Option<String>,
+        /// Pass values as stored, without expanding ${KEY} references
+        #[arg(long)]
+        raw: bool,
+        /// Schema listing required keys (defaults to ./secrets.schema.toml if it exists)
+        #[arg(long)]
+        schema: Option<PathBuf>,
+        /// Command and its arguments, after `--`
+        #[arg(trailing_var_arg = true, required = true)]
+        command: Vec<String>,
     },
     /// Delete a project
     Delete {
@@ -72,358 +366,2387 @@ pub enum Commands {
         project_name: String,
         /// Path to the .env file
         env_file: String,
+        /// Environment to import into (created if it doesn't exist)
+        #[arg(short, long)]
+        env: Option<String>,
+    },
+    /// Manage a project's environments, such as dev, staging and prod
+    Env {
+        #[command(subcommand)]
+        action: EnvCommands,
+    },
+    /// Show or set the projects whose secrets a project builds on
+    Extends {
+        /// Name of the project
+        project_name: String,
+        /// Projects to extend, in order; later ones override earlier ones (shows the current list if omitted)
+        parents: Vec<String>,
+        /// Stop extending any project
+        #[arg(long, conflicts_with = "parents")]
+        clear: bool,
+    },
+    /// Show which secrets differ between a project's environments
+    DiffEnv {
+        /// Name of the project
+        project_name: String,
+        /// Environments to compare (all if omitted)
+        environments: Vec<String>,
+    },
+    /// Run a key-caching agent in the foreground
+    Agent {
+        /// Socket path (defaults to a private directory under the temp dir)
+        #[arg(short, long)]
+        socket: Option<String>,
+        /// Seconds of inactivity after which cached keys are dropped
+        #[arg(short, long, default_value = "900", value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
+    /// Cache a project's key in the running agent
+    Unlock {
+        /// Name of the project
+        project_name: String,
+    },
+    /// Drop cached keys from the running agent
+    Lock {
+        /// Name of the project (all projects if omitted)
+        project_name: Option<String>,
+    },
+    /// Manage the key slots that can unlock a project
+    Slot {
+        #[command(subcommand)]
+        action: SlotCommands,
+    },
+    /// Reset a forgotten password with a one-time recovery code
+    Recover {
+        /// Name of the project
+        project_name: String,
+    },
+    /// Generate an X25519 identity for team sharing
+    Keygen {
+        /// Identity file to write (defaults to the config directory)
+        #[arg(short = 'o', long)]
+        out_file: Option<PathBuf>,
+    },
+    /// Manage the public keys a project is shared with
+    Recipients {
+        #[command(subcommand)]
+        action: RecipientCommands,
+    },
+    /// Move every project to another storage layout
+    MigrateStorage {
+        /// Target layout (files, sqlite, git, revisions, s3)
+        #[arg(long)]
+        to: String,
+    },
+    /// Merge another copy of a project file, or concurrent revisions, into the stored project
+    Merge {
+        /// Name of the project
+        project_name: String,
+        /// The diverged copy, e.g. a sync conflict file (defaults to the project's forked revisions)
+        other_file: Option<PathBuf>,
+        /// Common ancestor of both copies, if available
+        #[arg(long)]
+        base: Option<PathBuf>,
+    },
+    /// Pull, merge and push the git-backed vault
+    Sync {
+        /// Remote to sync with, e.g. a URL or the path of a bare repository
+        #[arg(long)]
+        remote: Option<String>,
+    },
+    /// Split a project's key into M-of-N shares for key custodians
+    Split {
+        /// Name of the project
+        project_name: String,
+        /// Number of shares to hand out
+        #[arg(short = 'n', long, default_value = "5")]
+        shares: u8,
+        /// Number of shares needed to reconstruct the key
+        #[arg(short, long, default_value = "3")]
+        threshold: u8,
+    },
+    /// Reconstruct a project's key from shares and set a new password
+    Combine {
+        /// Name of the project
+        project_name: String,
+        /// File with one share per line, or `-` for stdin (prompted for if omitted)
+        #[arg(long)]
+        shares_file: Option<PathBuf>,
+        /// Rotate the data key instead of just adding a password slot
+        #[arg(long)]
+        rekey: bool,
+    },
+    /// Serve a local HTTP/JSON API for tools that shouldn't parse CLI output
+    Serve {
+        /// Unix socket to listen on
+        #[arg(long, conflicts_with = "listen", required_unless_present = "listen")]
+        socket: Option<PathBuf>,
+        /// Loopback address to listen on, e.g. 127.0.0.1:7878
+        #[arg(long)]
+        listen: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum EnvCommands {
+    /// List a project's environments
+    List {
+        /// Name of the project
+        project_name: String,
+    },
+    /// Add an environment
+    Create {
+        /// Name of the project
+        project_name: String,
+        /// Name of the environment, e.g. dev or prod
+        environment: String,
+        /// Environment whose secrets this one starts from
+        #[arg(long)]
+        inherits: Option<String>,
+    },
+    /// Remove an environment and its secrets
+    Remove {
+        /// Name of the project
+        project_name: String,
+        /// Name of the environment
+        environment: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum RecipientCommands {
+    /// Share a project with a public key
+    Add {
+        /// Name of the project
+        project_name: String,
+        /// Public key (age1...)
+        recipient: String,
+        /// Free-form label, e.g. the teammate's name
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// List the public keys a project is shared with
+    List {
+        /// Name of the project
+        project_name: String,
+    },
+    /// Stop sharing a project with a public key
+    Remove {
+        /// Name of the project
+        project_name: String,
+        /// Public key (age1...)
+        recipient: String,
+        /// Also rotate the data key, so copies of the file they already have can't be opened with it
+        #[arg(long)]
+        rekey: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum SlotCommands {
+    /// List a project's key slots
+    List {
+        /// Name of the project
+        project_name: String,
+    },
+    /// Add a key slot
+    Add {
+        /// Name of the project
+        project_name: String,
+        /// Slot type (password, keyfile, password+keyfile, recovery)
+        #[arg(short = 't', long = "type", default_value = "password")]
+        slot_type: String,
+        /// Keyfile for the new slot (created if it doesn't exist)
+        #[arg(long)]
+        new_keyfile: Option<PathBuf>,
+        /// Free-form label to tell slots apart
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// Remove a key slot
+    Remove {
+        /// Name of the project
+        project_name: String,
+        /// Id of the slot to remove
+        slot_id: u32,
+        /// Also rotate the data key, so the removed credentials can't open copies of the file either
+        #[arg(long)]
+        rekey: bool,
     },
 }
 
-pub struct SecretManager {
-    storage: SecretStorage,
+/// How a project, or one of its environments, is named in messages
+fn describe_target(project_name: &str, environment: Option<&str>) -> String {
+    match environment {
+        Some(environment) => format!("project '{}' (environment '{}')", project_name, environment),
+        None => format!("project '{}'", project_name),
+    }
+}
+
+/// The schema given on the command line, or the default one if the current directory has it
+fn find_schema(schema: Option<PathBuf>) -> Option<PathBuf> {
+    schema.or_else(|| Some(PathBuf::from(schema::DEFAULT_SCHEMA_FILE)).filter(|path| path.exists()))
+}
+
+/// Which secrets `export` and `run` hand out
+struct ExportOptions<'a> {
+    env: Option<&'a str>,
+    /// Skip expanding `${KEY}` references
+    raw: bool,
+    schema: Option<PathBuf>,
+}
+
+/// Letter standing for the `index`-th distinct value in `diff-env`
+fn variant_label(index: usize) -> String {
+    match u8::try_from(index) {
+        Ok(index) if index < 26 => char::from(b'A' + index).to_string(),
+        _ => (index + 1).to_string(),
+    }
+}
+
+/// Recovery codes generated when the user accepts the offer at `create`
+const DEFAULT_RECOVERY_CODES: usize = 5;
+
+/// Runs commands against projects kept in any `StorageBackend`
+pub struct SecretManager<B: StorageBackend = Box<dyn StorageBackend>> {
+    storage: SecretStorage<B>,
+    agent: Option<AgentClient>,
+    keyfile: Option<PathBuf>,
+    identity: Option<PathBuf>,
+    vault_dir: Option<PathBuf>,
+    output: OutputFormat,
+    prompter: Box<dyn Prompter>,
+    console: Box<dyn Output>,
 }
 
 impl SecretManager {
+    /// Uses the default directory storage and the agent from the environment, if any
     pub fn new() -> Result<Self> {
-        Ok(Self {
-            storage: SecretStorage::new()?,
+        Ok(Self::with_storage(SecretStorage::new()?).with_agent(AgentClient::from_env()))
+    }
+}
+
+impl<B: StorageBackend> SecretManager<B> {
+    pub fn with_storage(storage: SecretStorage<B>) -> Self {
+        Self {
+            storage,
+            agent: None,
+            keyfile: None,
+            identity: None,
+            vault_dir: None,
+            output: OutputFormat::Text,
+            prompter: Box::new(Terminal),
+            console: Box::new(Terminal),
+        }
+    }
+    
+    /// Sets the agent used to cache data keys
+    pub fn with_agent(mut self, agent: Option<AgentClient>) -> Self {
+        self.agent = agent;
+        self
+    }
+    
+    /// Sets the keyfile presented when unlocking projects
+    pub fn with_keyfile(mut self, keyfile: Option<PathBuf>) -> Self {
+        self.keyfile = keyfile;
+        self
+    }
+    
+    /// Sets the identity file used for projects shared with recipients
+    pub fn with_identity(mut self, identity: Option<PathBuf>) -> Self {
+        self.identity = identity;
+        self
+    }
+    
+    /// Sets the vault directory used by `migrate-storage` and `sync`, instead of `~/.secrets_manager`
+    pub fn with_vault_dir(mut self, vault_dir: Option<PathBuf>) -> Self {
+        self.vault_dir = vault_dir;
+        self
+    }
+    
+    /// Sets how commands report their results
+    pub fn with_output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+    
+    /// Sets where prompts are answered and output is written, instead of the terminal
+    pub fn with_console(mut self, prompter: impl Prompter + 'static, console: impl Output + 'static) -> Self {
+        self.prompter = Box::new(prompter);
+        self.console = Box::new(console);
+        self
+    }
+    
+    /// Prints text meant for people: to stdout, or to stderr when stdout carries JSON
+    fn say(&self, text: impl std::fmt::Display) {
+        let line = format!("{}\n", text);
+        match self.output {
+            OutputFormat::Text => self.console.print(&line),
+            OutputFormat::Json => self.console.eprint(&line),
+        }
+    }
+    
+    /// Prints a warning to stderr whatever the output format
+    fn warn(&self, text: impl std::fmt::Display) {
+        self.console.eprint(&format!("⚠️  {}\n", text));
+    }
+    
+    /// Asks a yes/no question, defaulting to no
+    fn confirm(&self, question: impl std::fmt::Display) -> Result<bool> {
+        let answer = self.prompter.read_line(&format!("{} (y/N): ", question))?;
+        let answer = answer.trim().to_lowercase();
+        Ok(answer == "y" || answer == "yes")
+    }
+    
+    /// Prints a command's result document when JSON output was asked for
+    fn emit(&self, mut document: serde_json::Value) {
+        if self.output != OutputFormat::Json {
+            return;
+        }
+        if let Some(fields) = document.as_object_mut() {
+            fields.insert("ok".to_string(), serde_json::Value::Bool(true));
+        }
+        let json = Zeroizing::new(format!("{}\n", document));
+        self.console.print(&json);
+    }
+    
+    fn vault_dir(&self) -> Result<PathBuf> {
+        match &self.vault_dir {
+            Some(dir) => Ok(dir.clone()),
+            None => backend::default_dir(),
+        }
+    }
+    
+    /// The identity file to try: the one given explicitly, or the default one if it exists
+    fn identity_path(&self) -> Option<PathBuf> {
+        self.identity.clone().or_else(|| {
+            identity::default_identity_path().ok().filter(|path| path.exists())
         })
     }
     
-    /// Prompts for password with confirmation
-    fn get_password() -> Result<String> {
-        print!("Enter master password: ");
-        io::stdout().flush()?;
-        let password = read_password()?;
+    /// Collects the credentials a project needs, prompting for the password if required
+    fn get_credentials(&self, kind: CredentialKind) -> Result<Credentials> {
+        let keyfile = if kind.needs_keyfile() {
+            let path = self.keyfile.as_ref().ok_or_else(|| {
+                anyhow::anyhow!("This project requires a keyfile. Pass it with --keyfile <path>")
+            })?;
+            Some(crypto::read_keyfile(path)?)
+        } else {
+            None
+        };
         
-        if password.is_empty() {
-            return Err(anyhow::anyhow!("Password cannot be empty"));
+        let password = if kind.needs_password() {
+            Some(self.get_password()?)
+        } else {
+            None
+        };
+        
+        Ok(Credentials::new(password, keyfile)?)
+    }
+    
+    /// Loads a project, using the agent's cached key when one is available
+    ///
+    /// Falls back to prompting for credentials, and hands the data key to the
+    /// agent so later commands don't have to ask again.
+    fn unlock(&self, project_name: &str) -> Result<(Project, ProjectKey)> {
+        let forks = self.storage.forks(project_name)?.len();
+        if forks > 0 {
+            self.warn(format!(
+                "Project '{}' has {} version(s) written concurrently on other machines; run `secrets-manager merge {}` to combine them",
+                project_name, forks, project_name
+            ));
         }
         
-        Ok(password)
+        if let Some(agent) = &self.agent {
+            match agent.get_key(project_name) {
+                Ok(Some(key)) => match self.storage.load_project_with_key(project_name, &key) {
+                    Ok(project) => return Ok((project, key)),
+                    // The project was re-keyed elsewhere; the cached key is stale
+                    Err(_) => {
+                        let _ = agent.remove_key(project_name);
+                    }
+                },
+                Ok(None) => {}
+                Err(e) => self.warn(e),
+            }
+        }
+        
+        let (encrypted, version) = self.storage.load_encrypted_with_version(project_name)?;
+        if crypto::is_legacy(&encrypted) {
+            return self.unlock_legacy(project_name, &encrypted, version);
+        }
+        
+        let (key, _) = self.unlock_key(&encrypted)?;
+        let project = self.storage.load_project_with_key(project_name, &key)?;
+        self.cache_key(project_name, &key);
+        Ok((project, key))
     }
     
-    /// Prompts for password with confirmation for new projects
-    fn get_password_with_confirmation() -> Result<String> {
-        print!("Enter master password: ");
-        io::stdout().flush()?;
-        let password = read_password()?;
+    /// Opens one of the project's key slots with the credentials at hand
+    ///
+    /// An identity file is tried first for projects shared with recipients.
+    /// Then, with `--keyfile`, a keyfile-only slot is tried, then a
+    /// password+keyfile slot; otherwise the password slots are tried.
+    fn unlock_key(&self, encrypted: &EncryptedProject) -> Result<(ProjectKey, u32)> {
+        let has_slot = |kind| encrypted.slots.iter().any(|slot| slot.kind == kind);
         
-        if password.is_empty() {
-            return Err(anyhow::anyhow!("Password cannot be empty"));
+        let mut identity_error = None;
+        if has_slot(CredentialKind::Recipient) {
+            if let Some(path) = self.identity_path() {
+                let identity = Identity::read_file(&path)?;
+                match crypto::unlock_key(encrypted, &Credentials::identity(identity)) {
+                    Ok(result) => return Ok(result),
+                    Err(e) => identity_error = Some(e),
+                }
+            }
         }
         
-        print!("Confirm master password: ");
-        io::stdout().flush()?;
-        let confirm_password = read_password()?;
+        let kinds: Vec<CredentialKind> = if self.keyfile.is_some() {
+            [CredentialKind::Keyfile, CredentialKind::PasswordAndKeyfile]
+                .into_iter()
+                .filter(|kind| has_slot(*kind))
+                .collect()
+        } else {
+            [CredentialKind::Password].into_iter().filter(|kind| has_slot(*kind)).collect()
+        };
         
-        if password != confirm_password {
-            return Err(anyhow::anyhow!("Passwords do not match"));
+        if kinds.is_empty() {
+            if let Some(e) = identity_error {
+                return Err(e.into());
+            }
+            let mut available: Vec<String> = encrypted.slots.iter()
+                .filter(|slot| slot.kind != CredentialKind::RecoveryCode)
+                .map(|slot| slot.kind.to_string())
+                .collect();
+            available.sort();
+            available.dedup();
+            return Err(Error::InvalidCredentials(format!(
+                "Project '{}' can't be unlocked this way. Available slots: {} (pass --keyfile <path> for keyfile slots, --identity <path> for recipient slots)",
+                encrypted.project_name,
+                available.join(", "),
+            )).into());
         }
         
-        Ok(password)
+        let mut last_error = None;
+        for kind in kinds {
+            let credentials = self.get_credentials(kind)?;
+            match crypto::unlock_key(encrypted, &credentials) {
+                Ok(result) => return Ok(result),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.map(anyhow::Error::from).unwrap_or_else(|| anyhow::anyhow!("Could not unlock project")))
     }
     
-    /// Prompts for secret value
-    fn get_secret_value(key: &str) -> Result<String> {
-        print!("Enter value for '{}': ", key);
-        io::stdout().flush()?;
-        let value = read_password()?;
-        Ok(value)
+    /// Unlocks a version 1 or 2 file and upgrades it to the key-slot format
+    fn unlock_legacy(&self, project_name: &str, encrypted: &EncryptedProject, version: Option<String>) -> Result<(Project, ProjectKey)> {
+        let credentials = self.get_credentials(encrypted.credentials.unwrap_or_default())?;
+        let mut project = crypto::decrypt_legacy_project(encrypted, &credentials)?;
+        if project.name != project_name {
+            return Err(anyhow::anyhow!(
+                "File '{}.encrypted' belongs to project '{}' (was it renamed?)",
+                project_name, project.name
+            ));
+        }
+        
+        let key = ProjectKey::generate();
+        let slot = crypto::create_slot(project_name, 1, &key, &credentials, None)?;
+        project.stored_version = version;
+        self.storage.save_project_with_slots(&project, &key, vec![slot])?;
+        self.console.eprint(&format!("🔄 Upgraded project '{}' to the key-slot format\n", project_name));
+        
+        self.cache_key(project_name, &key);
+        // Read it back, so saving it again is conditional on the upgraded version
+        let project = self.storage.load_project_with_key(project_name, &key)?;
+        Ok((project, key))
     }
     
-    pub fn handle_command(&self, command: Commands) -> Result<()> {
-        match command {
-            Commands::Create { project_name } => {
-                self.create_project(&project_name)?;
-            }
-            Commands::List => {
-                self.list_projects()?;
-            }
-            Commands::Add { project_name, key, value } => {
-                self.add_secret(&project_name, &key, value)?;
-            }
-            Commands::Remove { project_name, key } => {
-                self.remove_secret(&project_name, &key)?;
-            }
-            Commands::Show { project_name } => {
-                self.show_project(&project_name)?;
-            }
-            Commands::Export { project_name, format, output, force } => {
-                self.export_project(&project_name, &format, output, force)?;
-            }
-            Commands::Delete { project_name } => {
-                self.delete_project(&project_name)?;
+    /// A project's secrets, as seen from one environment, laid over those of the projects it extends
+    ///
+    /// Also returns, for every key that comes from another project, the name
+    /// of the project that stores it.
+    fn layered(&self, project: &Project, env: Option<&str>) -> Result<(Project, HashMap<String, String>)> {
+        if let Some(env) = env {
+            project.environment(env)?;
+        }
+        self.layer(project, env, &mut vec![project.name.clone()], &mut HashMap::new())
+    }
+    
+    /// Resolves the projects a project extends, recursively
+    ///
+    /// Every project contributes its environment of the same name if it has
+    /// one, and its top-level secrets otherwise. `chain` holds the projects
+    /// being resolved, to catch cycles; `parents` keeps the ones already
+    /// unlocked, so a project extended twice is only asked for once.
+    fn layer(
+        &self,
+        project: &Project,
+        env: Option<&str>,
+        chain: &mut Vec<String>,
+        parents: &mut HashMap<String, Project>,
+    ) -> Result<(Project, HashMap<String, String>)> {
+        let mut layered = project.in_environment(env.filter(|name| project.environments.contains_key(*name)))?;
+        let mut origins = HashMap::new();
+        // Later parents override earlier ones, so the first one to offer a key wins
+        for parent_name in project.extends.iter().rev() {
+            if chain.contains(parent_name) {
+                chain.push(parent_name.clone());
+                return Err(anyhow::anyhow!("Projects extend each other in a cycle: {}", chain.join(" -> ")));
             }
-            Commands::Import { project_name, env_file } => {
-                self.import_project(&project_name, &env_file)?;
+            let parent = match parents.get(parent_name) {
+                Some(parent) => parent.clone(),
+                None => {
+                    let parent = self.unlock_related(parent_name, &format!("'{}' extends '{}'", project.name, parent_name))?;
+                    parents.insert(parent_name.clone(), parent.clone());
+                    parent
+                }
+            };
+            
+            chain.push(parent_name.clone());
+            let (parent_layer, parent_origins) = self.layer(&parent, env, chain, parents)?;
+            chain.pop();
+            for (key, value) in &parent_layer.secrets {
+                if !layered.secrets.contains_key(key) {
+                    layered.secrets.insert(key.clone(), value.clone());
+                    origins.insert(key.clone(), parent_origins.get(key).unwrap_or(parent_name).clone());
+                }
             }
         }
-        Ok(())
+        Ok((layered, origins))
     }
     
-    fn create_project(&self, project_name: &str) -> Result<()> {
-        if self.storage.project_exists(project_name) {
-            return Err(anyhow::anyhow!("Project '{}' already exists", project_name));
+    /// Unlocks a project another one depends on, saying why before asking for credentials
+    fn unlock_related(&self, project_name: &str, reason: &str) -> Result<Project> {
+        let cached = self.agent.as_ref().is_some_and(|agent| matches!(agent.get_key(project_name), Ok(Some(_))));
+        if !cached {
+            self.console.eprint(&format!("🔗 {}; unlock it to continue.\n", reason));
+        }
+        let (project, _) = self.unlock(project_name)
+            .map_err(|e| e.context(format!("Could not unlock '{}' ({})", project_name, reason)))?;
+        Ok(project)
+    }
+    
+    /// The secrets `get`, `export` and `run` hand out
+    ///
+    /// They are laid over the projects the project extends and, unless `raw`,
+    /// have their `${KEY}` and `${project:KEY}` references expanded.
+    fn resolved(&self, project_name: &str, env: Option<&str>, raw: bool) -> Result<Project> {
+        let (stored, _) = self.unlock(project_name)?;
+        let (project, _) = self.layered(&stored, env)?;
+        if raw {
+            return Ok(project);
         }
+        interpolate::expand(&project, |other| self.load_referenced(project_name, other, env))
+    }
+    
+    /// The secrets `export` and `run` hand out, after the schema's defaults and required keys
+    fn exported(&self, project_name: &str, options: ExportOptions) -> Result<Project> {
+        let mut project = self.resolved(project_name, options.env, options.raw)?;
+        let schema = match options.schema {
+            Some(path) => Some((Schema::load(&path)?, path)),
+            None => self.default_schema(project_name)?,
+        };
+        let Some((schema, path)) = schema else {
+            return Ok(project);
+        };
         
-        let password = Self::get_password_with_confirmation()?;
-        let project = Project::new(project_name.to_string());
-        self.storage.save_project(&project, &password)?;
+        let report = schema.check(&project.secrets);
+        if !report.missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Secrets required by '{}' are missing from {}: {}. Run `secrets-manager check {}` for details",
+                path.display(), describe_target(project_name, options.env), report.missing.join(", "), project_name
+            ));
+        }
+        for (key, reason) in &report.invalid {
+            self.warn(format!("'{}' {} (see '{}')", key, reason, path.display()));
+        }
+        schema.apply_defaults(&mut project.secrets);
+        Ok(project)
+    }
+    
+    /// The schema in the current directory, if there is one and it names this project
+    ///
+    /// A schema that names no project, or another one, is left alone with a
+    /// note, so exporting an unrelated project from a repository isn't held to it.
+    fn default_schema(&self, project_name: &str) -> Result<Option<(Schema, PathBuf)>> {
+        let path = PathBuf::from(schema::DEFAULT_SCHEMA_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let schema = Schema::load(&path)?;
+        match schema.project.as_deref() {
+            Some(name) if name == project_name => return Ok(Some((schema, path))),
+            Some(name) => self.warn(format!(
+                "Not applying '{}', which describes project '{}'; pass --schema to use it anyway",
+                path.display(), name
+            )),
+            None => self.warn(format!(
+                "Not applying '{}', which doesn't say which project it describes; add project = \"{}\" to it or pass --schema",
+                path.display(), project_name
+            )),
+        }
+        Ok(None)
+    }
+    
+    /// Loads a project whose secrets another one refers to, in the same environment if it has one
+    fn load_referenced(&self, project_name: &str, other: &str, env: Option<&str>) -> Result<Project> {
+        let referenced = self.unlock_related(other, &format!("'{}' refers to secrets in '{}'", project_name, other))?;
+        let other_env = env.filter(|name| referenced.environments.contains_key(*name));
+        Ok(self.layered(&referenced, other_env)?.0)
+    }
+    
+    /// Hands a key to the agent if one is configured; failures only warn
+    fn cache_key(&self, project_name: &str, key: &ProjectKey) {
+        if let Some(agent) = &self.agent {
+            if let Err(e) = agent.add_key(project_name, key) {
+                self.warn(e);
+            }
+        }
+    }
+    
+    /// Prompts for password with confirmation
+    fn get_password(&self) -> Result<SecretString> {
+        let password = self.prompter.read_secret("Enter master password: ")?;
+        
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("Password cannot be empty"));
+        }
+        
+        Ok(password)
+    }
+    
+    /// Prompts for password with confirmation for new projects
+    fn get_password_with_confirmation(&self) -> Result<SecretString> {
+        let password = self.prompter.read_secret("Enter master password: ")?;
+        
+        if password.is_empty() {
+            return Err(anyhow::anyhow!("Password cannot be empty"));
+        }
+        
+        let confirm_password = self.prompter.read_secret("Confirm master password: ")?;
+        
+        if password.expose_secret() != confirm_password.expose_secret() {
+            return Err(anyhow::anyhow!("Passwords do not match"));
+        }
+        
+        Ok(password)
+    }
+    
+    /// Prompts for secret value
+    fn get_secret_value(&self, key: &str) -> Result<String> {
+        let value = self.prompter.read_secret(&format!("Enter value for '{}': ", key))?;
+        Ok(value.expose_secret().to_string())
+    }
+    
+    pub fn handle_command(&self, command: Commands) -> Result<()> {
+        match command {
+            Commands::Create { project_name, no_password, recovery_codes } => {
+                self.create_project(&project_name, no_password, recovery_codes)?;
+            }
+            Commands::List => {
+                self.list_projects()?;
+            }
+            Commands::Add { project_name, key, value, env } => {
+                self.add_secret(&project_name, env.as_deref(), &key, value)?;
+            }
+            Commands::Get { project_name, key, env, raw } => {
+                self.get_secret(&project_name, env.as_deref(), &key, raw)?;
+            }
+            Commands::Generate {
+                project_name, key, kind, length, no_uppercase, no_lowercase, no_digits,
+                symbols, separator, show, force, env,
+            } => {
+                let options = GenerateOptions {
+                    kind: GenerateKind::parse(&kind)?,
+                    length,
+                    uppercase: !no_uppercase,
+                    lowercase: !no_lowercase,
+                    digits: !no_digits,
+                    symbols,
+                    separator,
+                };
+                self.generate_secret(&project_name, env.as_deref(), &key, &options, show, force)?;
+            }
+            Commands::Remove { project_name, key, env } => {
+                self.remove_secret(&project_name, env.as_deref(), &key)?;
+            }
+            Commands::Show { project_name, env } => {
+                self.show_project(&project_name, env.as_deref())?;
+            }
+            Commands::Export { project_name, format, out_file, force, env, raw, schema } => {
+                let options = ExportOptions { env: env.as_deref(), raw, schema };
+                self.export_project(&project_name, &format, out_file, force, options)?;
+            }
+            Commands::Check { project_name, schema, env } => {
+                self.check_project(&project_name, env.as_deref(), schema)?;
+            }
+            Commands::Render { project_name, template, out_file, env } => {
+                self.render_template(&project_name, env.as_deref(), &template, out_file)?;
+            }
+            Commands::Run { project_name, env, raw, schema, command } => {
+                let options = ExportOptions { env: env.as_deref(), raw, schema };
+                self.run_command(&project_name, &command, options)?;
+            }
+            Commands::Delete { project_name } => {
+                self.delete_project(&project_name)?;
+            }
+            Commands::Import { project_name, env_file, env } => {
+                self.import_project(&project_name, env.as_deref(), &env_file)?;
+            }
+            Commands::Env { action } => match action {
+                EnvCommands::List { project_name } => {
+                    self.list_environments(&project_name)?;
+                }
+                EnvCommands::Create { project_name, environment, inherits } => {
+                    self.create_environment(&project_name, &environment, inherits.as_deref())?;
+                }
+                EnvCommands::Remove { project_name, environment } => {
+                    self.remove_environment(&project_name, &environment)?;
+                }
+            },
+            Commands::Extends { project_name, parents, clear } => {
+                self.set_extends(&project_name, parents, clear)?;
+            }
+            Commands::DiffEnv { project_name, environments } => {
+                self.diff_environments(&project_name, &environments)?;
+            }
+            Commands::Agent { socket, timeout } => {
+                self.run_agent(socket, timeout)?;
+            }
+            Commands::Unlock { project_name } => {
+                self.unlock_project(&project_name)?;
+            }
+            Commands::Lock { project_name } => {
+                self.lock_projects(project_name.as_deref())?;
+            }
+            Commands::Slot { action } => match action {
+                SlotCommands::List { project_name } => {
+                    self.list_slots(&project_name)?;
+                }
+                SlotCommands::Add { project_name, slot_type, new_keyfile, label } => {
+                    let kind = CredentialKind::parse(&slot_type)?;
+                    self.add_slot(&project_name, kind, new_keyfile.as_deref(), label)?;
+                }
+                SlotCommands::Remove { project_name, slot_id, rekey } => {
+                    self.remove_slot(&project_name, slot_id, rekey)?;
+                }
+            },
+            Commands::Recover { project_name } => {
+                self.recover_project(&project_name)?;
+            }
+            Commands::Keygen { out_file } => {
+                self.generate_identity(out_file)?;
+            }
+            Commands::Recipients { action } => match action {
+                RecipientCommands::Add { project_name, recipient, label } => {
+                    self.add_recipient(&project_name, &recipient, label)?;
+                }
+                RecipientCommands::List { project_name } => {
+                    self.list_recipients(&project_name)?;
+                }
+                RecipientCommands::Remove { project_name, recipient, rekey } => {
+                    self.remove_recipient(&project_name, &recipient, rekey)?;
+                }
+            },
+            Commands::MigrateStorage { to } => {
+                self.migrate_storage(StorageLayout::parse(&to)?)?;
+            }
+            Commands::Merge { project_name, other_file, base } => {
+                match other_file {
+                    Some(other_file) => self.merge_project(&project_name, &other_file, base.as_deref())?,
+                    None => self.merge_forks(&project_name)?,
+                }
+            }
+            Commands::Sync { remote } => {
+                self.sync_vault(remote)?;
+            }
+            Commands::Split { project_name, shares, threshold } => {
+                self.split_project(&project_name, shares, threshold)?;
+            }
+            Commands::Combine { project_name, shares_file, rekey } => {
+                self.combine_project(&project_name, shares_file.as_deref(), rekey)?;
+            }
+            Commands::Serve { socket, listen } => {
+                self.serve(socket, listen)?;
+            }
+        }
+        Ok(())
+    }
+    
+    fn create_project(&self, project_name: &str, no_password: bool, recovery_codes: Option<usize>) -> Result<()> {
+        if self.storage.project_exists(project_name) {
+            return Err(anyhow::anyhow!("Project '{}' already exists", project_name));
+        }
+        
+        // A missing keyfile is created so a fresh one can be provisioned in one step
+        let keyfile = match &self.keyfile {
+            Some(path) => {
+                if !path.exists() {
+                    crypto::create_keyfile(path)?;
+                    self.say(format!("🔑 Created keyfile '{}'", path.display()));
+                }
+                Some(crypto::read_keyfile(path)?)
+            }
+            None if no_password => {
+                return Err(anyhow::anyhow!("--no-password requires --keyfile <path>"));
+            }
+            None => None,
+        };
+        
+        let password = if no_password {
+            None
+        } else {
+            Some(self.get_password_with_confirmation()?)
+        };
+        let credentials = Credentials::new(password, keyfile)?;
+        let project_key = ProjectKey::generate();
+        let mut slots = vec![crypto::create_slot(project_name, 1, &project_key, &credentials, None)?];
+        
+        // Keyfile-only projects are meant for automation, so only offer codes when asked explicitly
+        let recovery_count = match recovery_codes {
+            Some(count) => count,
+            None if no_password => 0,
+            None => {
+                if self.confirm("Generate printable recovery codes in case the password is forgotten?")? {
+                    DEFAULT_RECOVERY_CODES
+                } else {
+                    0
+                }
+            }
+        };
+        let codes = Self::add_recovery_slots(project_name, &project_key, &mut slots, 2, recovery_count, None)?;
+        
+        let project = Project::new(project_name.to_string());
+        self.storage.save_project_with_slots(&project, &project_key, slots)?;
+        self.cache_key(project_name, &project_key);
+        
+        self.say(format!("✅ Project '{}' created successfully!", project_name));
+        self.print_recovery_codes(&codes);
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "recovery_codes": codes.iter().map(SecretString::expose_secret).collect::<Vec<_>>(),
+        }));
+        Ok(())
+    }
+    
+    /// Appends `count` recovery code slots, numbered from `first_id`, and returns the codes to show the user
+    fn add_recovery_slots(
+        project_name: &str,
+        project_key: &ProjectKey,
+        slots: &mut Vec<KeySlot>,
+        first_id: u32,
+        count: usize,
+        label: Option<String>,
+    ) -> Result<Vec<SecretString>> {
+        let mut codes = Vec::with_capacity(count);
+        for slot_id in (first_id..).take(count) {
+            let code = generate_recovery_code();
+            let credentials = Credentials::recovery_code(code.clone());
+            slots.push(crypto::create_slot(project_name, slot_id, project_key, &credentials, label.clone())?);
+            codes.push(code);
+        }
+        Ok(codes)
+    }
+    
+    /// Shows recovery codes in text output; JSON output carries them in the document
+    fn print_recovery_codes(&self, codes: &[SecretString]) {
+        if codes.is_empty() || self.output != OutputFormat::Text {
+            return;
+        }
+        
+        self.say("");
+        self.say("🆘 Recovery codes (print or store them offline; they won't be shown again):");
+        for code in codes {
+            self.console.print(&Zeroizing::new(format!("  {}\n", code.expose_secret())));
+        }
+        self.say("Each code works once with: secrets-manager recover <project>");
+    }
+    
+    fn list_projects(&self) -> Result<()> {
+        let projects = self.storage.list_projects()?;
+        self.emit(serde_json::json!({ "projects": projects }));
+        
+        if projects.is_empty() {
+            self.say("No projects found. Create one with: secrets-manager create <project-name>");
+            return Ok(());
+        }
+        
+        self.say("📁 Available projects:");
+        for project in projects {
+            self.say(format!("  • {}", project));
+        }
+        Ok(())
+    }
+    
+    fn add_secret(&self, project_name: &str, env: Option<&str>, key: &str, value: Option<String>) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        
+        let secret_value = match value {
+            Some(v) => v,
+            None => self.get_secret_value(key)?,
+        };
+        
+        project.add_secret_to(env, key.to_string(), secret_value);
+        self.storage.save_project_with_key(&project, &project_key)?;
+        
+        self.say(format!("✅ Secret '{}' added to {}", key, describe_target(project_name, env)));
+        self.emit(serde_json::json!({ "project": project_name, "environment": env, "key": key }));
+        Ok(())
+    }
+    
+    fn get_secret(&self, project_name: &str, env: Option<&str>, key: &str, raw: bool) -> Result<()> {
+        let (stored, _) = self.unlock(project_name)?;
+        let (project, _) = self.layered(&stored, env)?;
+        let value = project.get_secret(key).ok_or_else(|| {
+            anyhow::anyhow!("Secret '{}' not found in {}", key, describe_target(project_name, env))
+        })?;
+        // Only this secret is expanded, so a broken reference elsewhere doesn't get in the way
+        let value = if raw {
+            Zeroizing::new(value.clone())
+        } else {
+            interpolate::expand_secret(&project, key, |other| self.load_referenced(project_name, other, env))?
+        };
+        
+        match self.output {
+            OutputFormat::Text => self.console.print(&Zeroizing::new(format!("{}\n", value.as_str()))),
+            OutputFormat::Json => self.emit(serde_json::json!({
+                "project": project_name,
+                "environment": env,
+                "key": key,
+                "value": value.as_str(),
+            })),
+        }
+        Ok(())
+    }
+    
+    fn generate_secret(
+        &self,
+        project_name: &str,
+        env: Option<&str>,
+        key: &str,
+        options: &GenerateOptions,
+        show: bool,
+        force: bool,
+    ) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        
+        if project.own_secret(env, key).is_some() && !force {
+            return Err(anyhow::anyhow!(
+                "Secret '{}' already exists in {}. Use --force to overwrite",
+                key, describe_target(project_name, env)
+            ));
+        }
+        
+        let value = generate_secret(options)?;
+        project.add_secret_to(env, key.to_string(), value.expose_secret().to_string());
+        self.storage.save_project_with_key(&project, &project_key)?;
+        
+        self.say(format!("✅ Generated secret '{}' in {}", key, describe_target(project_name, env)));
+        match self.output {
+            OutputFormat::Text if show => self.console.print(&Zeroizing::new(format!("{}\n", value.expose_secret()))),
+            OutputFormat::Text => {}
+            OutputFormat::Json => self.emit(serde_json::json!({
+                "project": project_name,
+                "environment": env,
+                "key": key,
+                "value": show.then(|| value.expose_secret()),
+            })),
+        }
+        Ok(())
+    }
+    
+    fn remove_secret(&self, project_name: &str, env: Option<&str>, key: &str) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        if let Some(env) = env {
+            project.environment(env)?;
+        }
+        
+        let removed = project.remove_secret_from(env, key).is_some();
+        if removed {
+            self.storage.save_project_with_key(&project, &project_key)?;
+            self.say(format!("✅ Secret '{}' removed from {}", key, describe_target(project_name, env)));
+        } else {
+            self.say(format!("❌ Secret '{}' not found in {}", key, describe_target(project_name, env)));
+        }
+        self.emit(serde_json::json!({ "project": project_name, "environment": env, "key": key, "removed": removed }));
+        Ok(())
+    }
+    
+    fn show_project(&self, project_name: &str, env: Option<&str>) -> Result<()> {
+        let (stored, _) = self.unlock(project_name)?;
+        let (project, extended) = self.layered(&stored, env)?;
+        let mut keys = project.list_secrets();
+        keys.sort();
+        // Where each inherited key comes from; the environment's own keys aren't listed
+        let mut inherited = std::collections::BTreeMap::new();
+        if let Some(env) = env {
+            for (name, environment) in stored.environment_chain(env)?.into_iter().skip(1) {
+                for key in environment.secrets.keys() {
+                    if stored.own_secret(Some(env), key).is_none() {
+                        inherited.entry(key.as_str()).or_insert(name);
+                    }
+                }
+            }
+        }
+        self.emit(serde_json::json!({
+            "project": project.name,
+            "environment": env,
+            "created_at": project.created_at,
+            "updated_at": project.updated_at,
+            "keys": keys,
+            "inherited": inherited,
+            "extended": extended.iter().collect::<BTreeMap<_, _>>(),
+            "environments": stored.environments.keys().collect::<Vec<_>>(),
+            "extends": stored.extends,
+        }));
+        
+        self.say(format!("🔐 Project: {}", project.name));
+        if let Some(env) = env {
+            self.say(format!("🌱 Environment: {}", env));
+        }
+        if !stored.extends.is_empty() {
+            self.say(format!("🔗 Extends: {}", stored.extends.join(", ")));
+        }
+        self.say(format!("📅 Created: {}", project.created_at.format("%Y-%m-%d %H:%M:%S UTC")));
+        self.say(format!("📅 Updated: {}", project.updated_at.format("%Y-%m-%d %H:%M:%S UTC")));
+        self.say("");
+        
+        if project.secrets.is_empty() {
+            let flag = env.map(|env| format!(" --env {}", env)).unwrap_or_default();
+            self.say(format!("No secrets found. Add one with: secrets-manager add {} <key>{}", project_name, flag));
+        } else {
+            self.say("Secrets:");
+            for key in keys {
+                match (inherited.get(key.as_str()), extended.get(key)) {
+                    (Some(origin), _) => self.say(format!("  • {} (from {})", key, origin)),
+                    (None, Some(origin)) => self.say(format!("  • {} (from project {})", key, origin)),
+                    (None, None) => self.say(format!("  • {}", key)),
+                }
+            }
+        }
+        if env.is_none() && !stored.environments.is_empty() {
+            self.say("");
+            let names: Vec<&str> = stored.environments.keys().map(String::as_str).collect();
+            self.say(format!("Environments: {} (show one with --env <name>)", names.join(", ")));
+        }
+        Ok(())
+    }
+    
+    fn export_project(&self, project_name: &str, format: &str, output: Option<String>, force: bool, options: ExportOptions) -> Result<()> {
+        let env = options.env;
+        let project = self.exported(project_name, options)?;
+        
+        let export_format = ExportFormat::parse(format)?;
+        let content = Zeroizing::new(export_format.format(&project)?);
+        
+        match output {
+            Some(file_path) => {
+                // Check .gitignore guardrail before writing file
+                if force {
+                    self.say(format!("⚠️  WARNING: Exporting to '{}' without checking .gitignore!", file_path));
+                    self.say("   This may result in accidental commits.");
+                    self.say("");
+                } else {
+                    self.check_gitignore_guardrail(&file_path)?;
+                }
+                
+                std::fs::write(&file_path, content.as_bytes())?;
+                self.say(format!("✅ Exported to: {}", file_path));
+                self.emit(serde_json::json!({ "project": project_name, "environment": env, "format": format, "path": file_path }));
+            }
+            None => match self.output {
+                OutputFormat::Text => self.console.print(&content),
+                OutputFormat::Json => self.emit(serde_json::json!({
+                    "project": project_name,
+                    "environment": env,
+                    "format": format,
+                    "content": content.as_str(),
+                })),
+            },
+        }
+        Ok(())
+    }
+    
+    /// Renders a template with the secrets `export` would write, references expanded
+    fn render_template(&self, project_name: &str, env: Option<&str>, template_path: &Path, output: Option<String>) -> Result<()> {
+        let source = fs::read_to_string(template_path).map_err(|e| io::Error::new(
+            e.kind(),
+            format!("Could not read template '{}': {}", template_path.display(), e),
+        ))?;
+        let project = self.resolved(project_name, env, false)?;
+        let rendered = template::render(&source, &project.secrets)?;
+        
+        match output {
+            Some(file_path) => {
+                self.check_gitignore_guardrail(&file_path)?;
+                fs::write(&file_path, rendered.as_bytes())?;
+                self.say(format!("✅ Rendered '{}' to: {}", template_path.display(), file_path));
+                self.emit(serde_json::json!({
+                    "project": project_name,
+                    "environment": env,
+                    "template": template_path,
+                    "path": file_path,
+                }));
+            }
+            None => match self.output {
+                OutputFormat::Text => self.console.print(&rendered),
+                OutputFormat::Json => self.emit(serde_json::json!({
+                    "project": project_name,
+                    "environment": env,
+                    "template": template_path,
+                    "content": rendered.as_str(),
+                })),
+            },
+        }
+        Ok(())
+    }
+    
+    /// Runs a command with the project's secrets added to its environment
+    ///
+    /// The command's output isn't captured, so no result document is printed
+    /// with `--output json`; a failing command makes the CLI exit with its status.
+    fn run_command(&self, project_name: &str, command: &[String], options: ExportOptions) -> Result<()> {
+        let project = self.exported(project_name, options)?;
+        let (program, args) = command.split_first().ok_or_else(|| anyhow::anyhow!("No command given"))?;
+        
+        let status = std::process::Command::new(program)
+            .args(args)
+            .envs(&project.secrets)
+            .status()
+            .map_err(|e| anyhow::anyhow!("Could not run '{}': {}", program, e))?;
+        if !status.success() {
+            return Err(CommandFailed(status.code().unwrap_or(EXIT_FAILURE)).into());
+        }
+        Ok(())
+    }
+    
+    fn import_project(&self, project_name: &str, env: Option<&str>, env_file: &str) -> Result<()> {
+        // Check if the .env file exists
+        if !Path::new(env_file).exists() {
+            return Err(anyhow::anyhow!("File '{}' not found", env_file));
+        }
+
+        // Load the project
+        let (mut project, project_key) = self.unlock(project_name)?;
+
+        // Parse the .env file
+        let env_content = Zeroizing::new(fs::read_to_string(env_file)?);
+        let env_vars = self.parse_env_file(&env_content)?;
+
+        if env_vars.is_empty() {
+            self.say(format!("No environment variables found in '{}'", env_file));
+            self.emit(serde_json::json!({ "project": project_name, "environment": env, "imported": [], "skipped": [] }));
+            return Ok(());
+        }
+
+        self.say(format!("Found {} environment variables in '{}'", env_vars.len(), env_file));
+        
+        let mut imported = Vec::new();
+        let mut skipped = Vec::new();
+
+        for (key, value) in env_vars {
+            if project.own_secret(env, &key).is_some() {
+                // Key already exists, prompt for confirmation
+                if self.confirm(format!("Key '{}' already exists. Overwrite?", key))? {
+                    project.add_secret_to(env, key.clone(), value);
+                    self.say(format!("✅ Imported '{}'", key));
+                    imported.push(key);
+                } else {
+                    self.say(format!("⏭️  Skipped '{}'", key));
+                    skipped.push(key);
+                }
+            } else {
+                // Key doesn't exist, add it directly
+                project.add_secret_to(env, key.clone(), value);
+                self.say(format!("✅ Imported '{}'", key));
+                imported.push(key);
+            }
+        }
+
+        // Save the updated project
+        self.storage.save_project_with_key(&project, &project_key)?;
+
+        self.say("");
+        self.say("📊 Import Summary:");
+        self.say(format!("  • Imported: {}", imported.len()));
+        self.say(format!("  • Skipped: {}", skipped.len()));
+        self.say(format!("  • Total processed: {}", imported.len() + skipped.len()));
+        imported.sort();
+        skipped.sort();
+        self.emit(serde_json::json!({ "project": project_name, "environment": env, "imported": imported, "skipped": skipped }));
+
+        Ok(())
+    }
+
+    /// Parses a .env file content and returns a HashMap of key-value pairs
+    fn parse_env_file(&self, content: &str) -> Result<std::collections::HashMap<String, String>> {
+        let mut env_vars = std::collections::HashMap::new();
+        
+        for line in content.lines() {
+            let line = line.trim();
+            
+            // Skip empty lines and comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            
+            // Find the first '=' character
+            if let Some(equal_pos) = line.find('=') {
+                let key = line[..equal_pos].trim().to_string();
+                let value = line[equal_pos + 1..].trim().to_string();
+                
+                // Remove quotes if present
+                let value = if (value.starts_with('"') && value.ends_with('"')) || 
+                              (value.starts_with('\'') && value.ends_with('\'')) {
+                    value[1..value.len()-1].to_string()
+                } else {
+                    value
+                };
+                
+                if !key.is_empty() {
+                    env_vars.insert(key, value);
+                }
+            }
+        }
+        
+        Ok(env_vars)
+    }
+
+    fn check_project(&self, project_name: &str, env: Option<&str>, schema: Option<PathBuf>) -> Result<()> {
+        let path = find_schema(schema).ok_or_else(|| {
+            anyhow::anyhow!("No schema given and no {} in the current directory", schema::DEFAULT_SCHEMA_FILE)
+        })?;
+        let schema = Schema::load(&path)?;
+        let project = self.resolved(project_name, env, false)?;
+        let report = schema.check(&project.secrets);
+        
+        self.say(format!("📋 Checking {} against '{}'", describe_target(project_name, env), path.display()));
+        for key in &report.missing {
+            match schema.keys[key].description.as_deref() {
+                Some(description) => self.say(format!("  ❌ {} is missing: {}", key, description)),
+                None => self.say(format!("  ❌ {} is missing", key)),
+            }
+        }
+        for (key, reason) in &report.invalid {
+            self.say(format!("  ⚠️  {} {}", key, reason));
+        }
+        for key in &report.defaulted {
+            self.say(format!("  ➖ {} is not set; its default will be used", key));
+        }
+        for key in &report.extra {
+            self.say(format!("  ➕ {} is not in the schema", key));
+        }
+        
+        if report.is_valid() {
+            self.say(format!("✅ Secrets of {} match the schema", describe_target(project_name, env)));
+            self.emit(serde_json::json!({
+                "project": project_name,
+                "environment": env,
+                "schema": path,
+                "valid": true,
+                "defaulted": report.defaulted,
+                "extra": report.extra,
+            }));
+            return Ok(());
+        }
+        
+        if !report.missing.is_empty() {
+            let flag = env.map(|env| format!(" --env {}", env)).unwrap_or_default();
+            self.say("");
+            self.say("Add the missing secrets with:");
+            for key in &report.missing {
+                self.say(format!("  secrets-manager add {} {}{}", project_name, key, flag));
+            }
+        }
+        let mut problems = Vec::new();
+        if !report.missing.is_empty() {
+            problems.push(format!("missing {}", report.missing.join(", ")));
+        }
+        problems.extend(report.invalid.iter().map(|(key, reason)| format!("{} {}", key, reason)));
+        Err(anyhow::anyhow!("Secrets of {} don't match '{}': {}", describe_target(project_name, env), path.display(), problems.join("; ")))
+    }
+    
+    fn set_extends(&self, project_name: &str, parents: Vec<String>, clear: bool) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        if parents.is_empty() && !clear {
+            self.emit(serde_json::json!({ "project": project_name, "extends": project.extends }));
+            if project.extends.is_empty() {
+                self.say(format!("Project '{}' doesn't extend any project.", project_name));
+            } else {
+                self.say(format!("🔗 '{}' extends: {}", project_name, project.extends.join(", ")));
+            }
+            return Ok(());
+        }
+        
+        for parent in &parents {
+            if !self.storage.project_exists(parent) {
+                return Err(Error::NotFound(parent.clone()).into());
+            }
+        }
+        project.extends = parents;
+        // Unlocking every parent now catches cycles and parents that can't be opened
+        self.layered(&project, None)?;
+        project.updated_at = chrono::Utc::now();
+        self.storage.save_project_with_key(&project, &project_key)?;
+        
+        if project.extends.is_empty() {
+            self.say(format!("✅ Project '{}' no longer extends any project", project_name));
+        } else {
+            self.say(format!("✅ Project '{}' now extends: {}", project_name, project.extends.join(", ")));
+        }
+        self.emit(serde_json::json!({ "project": project_name, "extends": project.extends }));
+        Ok(())
+    }
+    
+    fn list_environments(&self, project_name: &str) -> Result<()> {
+        let (project, _) = self.unlock(project_name)?;
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "environments": project.environments.iter().map(|(name, environment)| serde_json::json!({
+                "name": name,
+                "inherits": environment.inherits,
+                "secrets": environment.secrets.len(),
+            })).collect::<Vec<_>>(),
+        }));
+        
+        if project.environments.is_empty() {
+            self.say(format!("Project '{}' has no environments. Add one with: secrets-manager env create {} <name>", project_name, project_name));
+            return Ok(());
+        }
+        
+        self.say(format!("🌱 Environments of '{}':", project_name));
+        for (name, environment) in &project.environments {
+            match &environment.inherits {
+                Some(base) => self.say(format!("  • {} ({} secret(s), inherits from {})", name, environment.secrets.len(), base)),
+                None => self.say(format!("  • {} ({} secret(s))", name, environment.secrets.len())),
+            }
+        }
+        Ok(())
+    }
+    
+    fn create_environment(&self, project_name: &str, environment: &str, inherits: Option<&str>) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        project.add_environment(environment, inherits)?;
+        self.storage.save_project_with_key(&project, &project_key)?;
+        
+        match inherits {
+            Some(base) => self.say(format!("✅ Environment '{}' added to project '{}', inheriting from '{}'", environment, project_name, base)),
+            None => self.say(format!("✅ Environment '{}' added to project '{}'", environment, project_name)),
+        }
+        self.emit(serde_json::json!({ "project": project_name, "environment": environment, "inherits": inherits }));
+        Ok(())
+    }
+    
+    fn remove_environment(&self, project_name: &str, environment: &str) -> Result<()> {
+        let (mut project, project_key) = self.unlock(project_name)?;
+        let count = project.environment(environment)?.secrets.len();
+        
+        let removed = self.confirm(format!(
+            "⚠️  Are you sure you want to delete environment '{}' of project '{}' and its {} secret(s)?",
+            environment, project_name, count
+        ))?;
+        if removed {
+            project.remove_environment(environment)?;
+            self.storage.save_project_with_key(&project, &project_key)?;
+            self.say(format!("✅ Environment '{}' removed from project '{}'", environment, project_name));
+        } else {
+            self.say("❌ Deletion cancelled");
+        }
+        self.emit(serde_json::json!({ "project": project_name, "environment": environment, "removed": removed }));
+        Ok(())
+    }
+    
+    /// Compares the secrets each environment ends up with, inherited ones included
+    ///
+    /// Values are never printed: each distinct value of a key gets a letter,
+    /// so environments showing the same letter share the value.
+    fn diff_environments(&self, project_name: &str, environments: &[String]) -> Result<()> {
+        let (project, _) = self.unlock(project_name)?;
+        let names: Vec<String> = if environments.is_empty() {
+            project.environments.keys().cloned().collect()
+        } else {
+            environments.to_vec()
+        };
+        if names.len() < 2 {
+            return Err(anyhow::anyhow!(
+                "Need at least two environments to compare; project '{}' has {}",
+                project_name, project.environments.len()
+            ));
+        }
+        let views = names.iter()
+            .map(|name| project.in_environment(Some(name)))
+            .collect::<Result<Vec<Project>>>()?;
+        
+        let keys: std::collections::BTreeSet<&String> = views.iter().flat_map(|view| view.secrets.keys()).collect();
+        let mut same = Vec::new();
+        let mut differs = Vec::new();
+        for key in keys {
+            let values: Vec<Option<&String>> = views.iter().map(|view| view.secrets.get(key)).collect();
+            if values.iter().all(|value| *value == values[0]) {
+                same.push(key);
+                continue;
+            }
+            let mut distinct: Vec<&String> = Vec::new();
+            let labels: Vec<Option<String>> = values.iter().map(|value| value.map(|value| {
+                let index = distinct.iter().position(|seen| *seen == value).unwrap_or_else(|| {
+                    distinct.push(value);
+                    distinct.len() - 1
+                });
+                variant_label(index)
+            })).collect();
+            differs.push((key, labels));
+        }
+        
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "environments": names,
+            "same": same,
+            "differs": differs.iter().map(|(key, labels)| serde_json::json!({
+                "key": key,
+                "values": names.iter().zip(labels).collect::<std::collections::BTreeMap<_, _>>(),
+            })).collect::<Vec<_>>(),
+        }));
+        
+        self.say(format!("🔀 Environments of '{}': {}", project_name, names.join(", ")));
+        self.say(format!("Same everywhere: {} secret(s)", same.len()));
+        if differs.is_empty() {
+            self.say("No differences.");
+            return Ok(());
+        }
+        
+        let key_width = differs.iter().map(|(key, _)| key.len()).max().unwrap_or(0).max(3);
+        let widths: Vec<usize> = names.iter().map(|name| name.len().max(1)).collect();
+        let row = |first: &str, cells: Vec<&str>| {
+            let cells: Vec<String> = cells.iter().zip(&widths).map(|(cell, width)| format!("{:<width$}", cell, width = width)).collect();
+            format!("  {:<width$}  {}", first, cells.join("  "), width = key_width).trim_end().to_string()
+        };
+        self.say("");
+        self.say(row("Key", names.iter().map(String::as_str).collect()));
+        for (key, labels) in &differs {
+            self.say(row(key, labels.iter().map(|label| label.as_deref().unwrap_or("-")).collect()));
+        }
+        self.say("");
+        self.say("Environments with the same letter share a value; - means missing.");
+        Ok(())
+    }
+    
+    fn delete_project(&self, project_name: &str) -> Result<()> {
+        if !self.storage.project_exists(project_name) {
+            return Err(Error::NotFound(project_name.to_string()).into());
+        }
+        
+        let deleted = self.confirm(format!("⚠️  Are you sure you want to delete project '{}'?", project_name))?;
+        if deleted {
+            self.storage.delete_project(project_name)?;
+            if let Some(agent) = &self.agent {
+                let _ = agent.remove_key(project_name);
+            }
+            self.say(format!("✅ Project '{}' deleted successfully!", project_name));
+        } else {
+            self.say("❌ Deletion cancelled");
+        }
+        self.emit(serde_json::json!({ "project": project_name, "deleted": deleted }));
+        Ok(())
+    }
+
+    fn run_agent(&self, socket: Option<String>, timeout: u64) -> Result<()> {
+        let socket_path = socket.map(std::path::PathBuf::from).unwrap_or_else(agent::default_socket_path);
+        
+        // Announced only once the socket is bound, so the line can be waited for
+        agent::run_agent(&socket_path, std::time::Duration::from_secs(timeout), || match self.output {
+            OutputFormat::Text => self.say(format!("{}={}; export {};", agent::AGENT_SOCK_ENV, socket_path.display(), agent::AGENT_SOCK_ENV)),
+            OutputFormat::Json => self.emit(serde_json::json!({ "socket": socket_path })),
+        })
+    }
+    
+    fn unlock_project(&self, project_name: &str) -> Result<()> {
+        if self.agent.is_none() {
+            return Err(anyhow::anyhow!(
+                "No agent configured. Start one with 'secrets-manager agent' and set {}",
+                agent::AGENT_SOCK_ENV
+            ));
+        }
+        
+        self.unlock(project_name)?;
+        self.say(format!("🔓 Project '{}' unlocked in agent", project_name));
+        self.emit(serde_json::json!({ "project": project_name }));
+        Ok(())
+    }
+    
+    fn lock_projects(&self, project_name: Option<&str>) -> Result<()> {
+        let agent = self.agent.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("No agent configured ({} is not set)", agent::AGENT_SOCK_ENV)
+        })?;
+        
+        match project_name {
+            Some(name) => {
+                agent.remove_key(name)?;
+                self.say(format!("🔒 Project '{}' locked", name));
+            }
+            None => {
+                agent.lock()?;
+                self.say("🔒 All projects locked");
+            }
+        }
+        self.emit(serde_json::json!({ "project": project_name }));
+        Ok(())
+    }
+
+    fn list_slots(&self, project_name: &str) -> Result<()> {
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        if crypto::is_legacy(&encrypted) {
+            self.say(format!("Project '{}' predates key slots; unlock it once to upgrade.", project_name));
+            self.emit(serde_json::json!({ "project": project_name, "legacy": true, "slots": [] }));
+            return Ok(());
+        }
+        
+        self.say(format!("🔑 Key slots for '{}':", project_name));
+        for slot in &encrypted.slots {
+            let label = slot.label.as_deref().map(|l| format!(" ({})", l)).unwrap_or_default();
+            self.say(format!("  • {} {}{} — added {}", slot.id, slot.kind, label, slot.created_at.format("%Y-%m-%d %H:%M:%S UTC")));
+        }
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "legacy": false,
+            "slots": encrypted.slots.iter().map(|slot| serde_json::json!({
+                "id": slot.id,
+                "kind": slot.kind,
+                "label": slot.label,
+                "recipient": slot.recipient,
+                "created_at": slot.created_at,
+            })).collect::<Vec<_>>(),
+        }));
+        Ok(())
+    }
+    
+    fn add_slot(&self, project_name: &str, kind: CredentialKind, new_keyfile: Option<&Path>, label: Option<String>) -> Result<()> {
+        let (project, project_key) = self.unlock(project_name)?;
+        
+        let keyfile = if kind.needs_keyfile() {
+            let path = new_keyfile.ok_or_else(|| {
+                anyhow::anyhow!("A {} slot needs --new-keyfile <path>", kind)
+            })?;
+            if !path.exists() {
+                crypto::create_keyfile(path)?;
+                self.say(format!("🔑 Created keyfile '{}'", path.display()));
+            }
+            Some(crypto::read_keyfile(path)?)
+        } else {
+            None
+        };
+        
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        let slot_id = encrypted.new_slot_id();
+        let mut slots = encrypted.slots;
+        
+        let codes = if kind == CredentialKind::RecoveryCode {
+            Self::add_recovery_slots(project_name, &project_key, &mut slots, slot_id, 1, label)?
+        } else {
+            let password = if kind.needs_password() {
+                self.say("Choose the password for the new slot.");
+                Some(self.get_password_with_confirmation()?)
+            } else {
+                None
+            };
+            let credentials = Credentials::new(password, keyfile)?;
+            slots.push(crypto::create_slot(project_name, slot_id, &project_key, &credentials, label)?);
+            Vec::new()
+        };
+        self.storage.save_project_with_slots(&project, &project_key, slots)?;
+        
+        self.say(format!("✅ Added {} slot {} to project '{}'", kind, slot_id, project_name));
+        self.print_recovery_codes(&codes);
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "slot": slot_id,
+            "kind": kind,
+            "recovery_codes": codes.iter().map(SecretString::expose_secret).collect::<Vec<_>>(),
+        }));
+        Ok(())
+    }
+    
+    /// Unlocks a project with a one-time recovery code and sets a new password
+    fn recover_project(&self, project_name: &str) -> Result<()> {
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        if crypto::is_legacy(&encrypted) {
+            return Err(anyhow::anyhow!("Project '{}' predates recovery codes", project_name));
+        }
+        
+        let kinds = Self::replacement_kinds(&encrypted.slots, &[CredentialKind::Password, CredentialKind::PasswordAndKeyfile]);
+        let keyfile = self.keyfile_for(project_name, &kinds)?;
+        
+        let code = self.prompter.read_secret("Enter recovery code: ")?;
+        let (project_key, used_slot) = crypto::unlock_key(&encrypted, &Credentials::recovery_code(code))?;
+        let project = self.storage.load_project_with_key(project_name, &project_key)?;
+        
+        self.say("Recovery code accepted. Choose a new master password.");
+        let password = self.get_password_with_confirmation()?;
+        
+        // The code is spent, and every slot the forgotten password opened goes
+        let first_id = encrypted.new_slot_id();
+        let mut slots = encrypted.slots;
+        slots.retain(|slot| slot.id != used_slot && !slot.kind.needs_password());
+        slots.extend(Self::create_slots(project_name, first_id, &project_key, &kinds, Some(&password), keyfile.as_ref())?);
+        
+        let remaining = slots.iter().filter(|slot| slot.kind == CredentialKind::RecoveryCode).count();
+        self.storage.save_project_with_slots(&project, &project_key, slots)?;
+        self.cache_key(project_name, &project_key);
+        
+        self.say(format!("✅ Password for project '{}' has been reset", project_name));
+        self.say(format!("   {} recovery code(s) left", remaining));
+        self.emit(serde_json::json!({ "project": project_name, "recovery_codes_left": remaining }));
+        Ok(())
+    }
+    
+    /// Works out which slots to recreate when a project's password is replaced
+    ///
+    /// Each of the `candidates` the project has a slot for is kept, so a project
+    /// that needed a keyfile still does. A project with none of them gets a
+    /// password slot.
+    fn replacement_kinds(slots: &[KeySlot], candidates: &[CredentialKind]) -> Vec<CredentialKind> {
+        let mut kinds: Vec<CredentialKind> = candidates.iter()
+            .copied()
+            .filter(|kind| slots.iter().any(|slot| slot.kind == *kind))
+            .collect();
+        if kinds.is_empty() {
+            kinds.push(CredentialKind::Password);
+        }
+        kinds
+    }
+    
+    /// Reads the `--keyfile` the given slot kinds need, if any of them needs one
+    fn keyfile_for(&self, project_name: &str, kinds: &[CredentialKind]) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        let Some(kind) = kinds.iter().find(|kind| kind.needs_keyfile()) else {
+            return Ok(None);
+        };
+        let path = self.keyfile.as_ref().ok_or_else(|| anyhow::anyhow!(
+            "Project '{}' requires {}. Pass the keyfile with --keyfile <path> so the new slots keep requiring it",
+            project_name,
+            crypto::describe_credentials(*kind)
+        ))?;
+        Ok(Some(crypto::read_keyfile(path)?))
+    }
+    
+    /// Wraps a data key into one new slot per kind, numbered from `first_id`
+    fn create_slots(
+        project_name: &str,
+        first_id: u32,
+        project_key: &ProjectKey,
+        kinds: &[CredentialKind],
+        password: Option<&SecretString>,
+        keyfile: Option<&Zeroizing<Vec<u8>>>,
+    ) -> Result<Vec<KeySlot>> {
+        let mut slots = Vec::new();
+        for (slot_id, kind) in (first_id..).zip(kinds) {
+            let password = if kind.needs_password() { password.cloned() } else { None };
+            let keyfile = if kind.needs_keyfile() { keyfile.cloned() } else { None };
+            let credentials = Credentials::new(password, keyfile)?;
+            slots.push(crypto::create_slot(project_name, slot_id, project_key, &credentials, None)?);
+        }
+        Ok(slots)
+    }
+    
+    /// Removes a key slot, optionally rotating the data key as well
+    ///
+    /// A removed slot can't open the file any more, but whoever used it may
+    /// have kept the data key. With `rekey` the key is replaced like
+    /// `combine --rekey` does, so it is useless from then on.
+    fn remove_slot(&self, project_name: &str, slot_id: u32, rekey: bool) -> Result<()> {
+        let (project, project_key) = self.unlock(project_name)?;
+        
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        let first_id = encrypted.new_slot_id();
+        let mut slots = encrypted.slots;
+        let before = slots.len();
+        slots.retain(|slot| slot.id != slot_id);
+        if slots.len() == before {
+            return Err(anyhow::anyhow!("Slot {} not found in project '{}'", slot_id, project_name));
+        }
+        if !slots.iter().any(|slot| slot.kind != CredentialKind::RecoveryCode) {
+            return Err(anyhow::anyhow!(
+                "Refusing to remove slot {}: at least one slot besides recovery codes must remain",
+                slot_id
+            ));
+        }
+        
+        if rekey {
+            let kinds = Self::replacement_kinds(&slots, &Self::REKEYED_KINDS);
+            let keyfile = self.keyfile_for(project_name, &kinds)?;
+            let password = if kinds.iter().any(|kind| kind.needs_password()) {
+                self.say("The data key will be replaced. Choose a new master password.");
+                Some(self.get_password_with_confirmation()?)
+            } else {
+                None
+            };
+            
+            let new_key = ProjectKey::generate();
+            let rekeyed = Self::rekey_slots(project_name, &slots, first_id, &new_key, &kinds, password.as_ref(), keyfile.as_ref())?;
+            let dropped = slots.len() + 1 - rekeyed.iter().filter(|slot| slot.kind == CredentialKind::Recipient).count();
+            self.storage.save_project_with_slots(&project, &new_key, rekeyed)?;
+            self.cache_key(project_name, &new_key);
+            self.say(format!("✅ Removed slot {} from project '{}' and re-keyed it", slot_id, project_name));
+            self.say(format!("   {} old slot(s) were replaced; recovery codes and shares no longer work", dropped));
+        } else {
+            self.storage.save_project_with_slots(&project, &project_key, slots)?;
+            self.say(format!("✅ Removed slot {} from project '{}'", slot_id, project_name));
+            self.say("   The data key is unchanged, so copies of the file made before still open with it; pass --rekey to rotate it");
+        }
+        self.emit(serde_json::json!({ "project": project_name, "slot": slot_id, "rekeyed": rekey }));
+        Ok(())
+    }
+    
+    /// Slot kinds recreated for a new data key; recipient slots are re-wrapped instead
+    const REKEYED_KINDS: [CredentialKind; 3] = [
+        CredentialKind::Password,
+        CredentialKind::PasswordAndKeyfile,
+        CredentialKind::Keyfile,
+    ];
+    
+    /// Builds the slots for a rotated data key
+    ///
+    /// Recipient slots are re-wrapped under their ids, since only the public
+    /// key is needed for that; one slot per kind is created from `first_id` on.
+    /// Every other slot, such as recovery codes, is dropped.
+    fn rekey_slots(
+        project_name: &str,
+        slots: &[KeySlot],
+        first_id: u32,
+        new_key: &ProjectKey,
+        kinds: &[CredentialKind],
+        password: Option<&SecretString>,
+        keyfile: Option<&Zeroizing<Vec<u8>>>,
+    ) -> Result<Vec<KeySlot>> {
+        let mut rekeyed = Vec::new();
+        for slot in slots.iter().filter(|slot| slot.kind == CredentialKind::Recipient) {
+            let recipient = Recipient::parse(slot.recipient.as_deref().unwrap_or_default())?;
+            rekeyed.push(crypto::create_recipient_slot(project_name, slot.id, new_key, &recipient, slot.label.clone())?);
+        }
+        rekeyed.extend(Self::create_slots(project_name, first_id, new_key, kinds, password, keyfile)?);
+        Ok(rekeyed)
+    }
+
+    fn generate_identity(&self, output: Option<PathBuf>) -> Result<()> {
+        let path = match output {
+            Some(path) => path,
+            None => identity::default_identity_path()?,
+        };
+        
+        let identity = Identity::generate();
+        identity.write_file(&path)?;
+        
+        self.say(format!("🔑 Identity written to '{}'", path.display()));
+        self.say(format!("Public key: {}", identity.recipient()));
+        self.emit(serde_json::json!({ "path": path, "recipient": identity.recipient().to_string() }));
+        Ok(())
+    }
+    
+    fn add_recipient(&self, project_name: &str, recipient: &str, label: Option<String>) -> Result<()> {
+        let recipient = Recipient::parse(recipient)?;
+        let (project, project_key) = self.unlock(project_name)?;
+        
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        let slot_id = encrypted.new_slot_id();
+        let mut slots = encrypted.slots;
+        let encoded = recipient.to_string();
+        if slots.iter().any(|slot| slot.recipient.as_deref() == Some(encoded.as_str())) {
+            return Err(anyhow::anyhow!("Project '{}' is already shared with {}", project_name, encoded));
+        }
+        
+        slots.push(crypto::create_recipient_slot(project_name, slot_id, &project_key, &recipient, label)?);
+        self.storage.save_project_with_slots(&project, &project_key, slots)?;
+        
+        self.say(format!("✅ Project '{}' shared with {}", project_name, encoded));
+        self.emit(serde_json::json!({ "project": project_name, "recipient": encoded, "slot": slot_id }));
+        Ok(())
+    }
+    
+    fn list_recipients(&self, project_name: &str) -> Result<()> {
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        let recipients: Vec<_> = encrypted.slots.iter()
+            .filter(|slot| slot.kind == CredentialKind::Recipient)
+            .collect();
+        
+        self.emit(serde_json::json!({
+            "project": project_name,
+            "recipients": recipients.iter().map(|slot| serde_json::json!({
+                "recipient": slot.recipient,
+                "label": slot.label,
+                "slot": slot.id,
+            })).collect::<Vec<_>>(),
+        }));
+        
+        if recipients.is_empty() {
+            self.say(format!("Project '{}' isn't shared with any public keys.", project_name));
+            return Ok(());
+        }
+        
+        self.say(format!("👥 Recipients of '{}':", project_name));
+        for slot in recipients {
+            let label = slot.label.as_deref().map(|l| format!(" ({})", l)).unwrap_or_default();
+            self.say(format!("  • {}{}", slot.recipient.as_deref().unwrap_or("?"), label));
+        }
+        Ok(())
+    }
+    
+    fn remove_recipient(&self, project_name: &str, recipient: &str, rekey: bool) -> Result<()> {
+        let encoded = Recipient::parse(recipient)?.to_string();
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        let slot = encrypted.slots.iter()
+            .find(|slot| slot.recipient.as_deref() == Some(encoded.as_str()))
+            .ok_or_else(|| anyhow::anyhow!("Project '{}' isn't shared with {}", project_name, encoded))?;
+        
+        self.remove_slot(project_name, slot.id, rekey)
+    }
+
+    fn migrate_storage(&self, to: StorageLayout) -> Result<()> {
+        let dir = self.vault_dir()?;
+        let from = StorageLayout::current(&dir);
+        let moved = backend::migrate(&dir, to)?;
+        if to == StorageLayout::S3 {
+            self.say(format!("✅ Moved {} project(s) to the S3 bucket", moved));
+        } else {
+            self.say(format!("✅ Moved {} project(s) to {} storage in '{}'", moved, to, dir.display()));
+        }
+        if from == StorageLayout::S3 {
+            self.say("💡 The bucket was left untouched; unset SECRETS_MANAGER_S3_BUCKET to use the local vault");
+        }
+        self.emit(serde_json::json!({ "from": from.to_string(), "to": to.to_string(), "moved": moved }));
+        Ok(())
+    }
+    
+    /// Pulls from the vault's remote, merges diverged projects and pushes
+    ///
+    /// Project files can't be merged as text, so projects changed on both
+    /// sides are decrypted and merged key by key against the common ancestor.
+    fn sync_vault(&self, remote: Option<String>) -> Result<()> {
+        let dir = self.vault_dir()?;
+        match StorageLayout::detect(&dir) {
+            StorageLayout::Git => {}
+            StorageLayout::Files if remote.is_some() => {
+                backend::migrate(&dir, StorageLayout::Git)?;
+                self.say(format!("📦 Turned '{}' into a git repository", dir.display()));
+            }
+            layout => {
+                return Err(anyhow::anyhow!(
+                    "The vault uses {} storage; run `migrate-storage --to git` first",
+                    layout
+                ));
+            }
+        }
+        
+        let repo = GitRepo::open(&dir);
+        if let Some(url) = &remote {
+            repo.set_remote(url)?;
+        }
+        if repo.remote_url().is_none() {
+            return Err(anyhow::anyhow!("No remote configured. Pass --remote <url> (a bare repository path works too)"));
+        }
+        repo.fetch()?;
+        
+        let ours = repo.head()?;
+        let Some(theirs) = repo.remote_head() else {
+            repo.push()?;
+            self.say("⬆️  Pushed the vault to the remote");
+            self.emit(serde_json::json!({ "action": "pushed", "merged_projects": [] }));
+            return Ok(());
+        };
+        if ours == theirs {
+            self.say("✅ Vault is up to date");
+            self.emit(serde_json::json!({ "action": "up_to_date", "merged_projects": [] }));
+            return Ok(());
+        }
+        
+        let base = repo.merge_base(&ours, &theirs);
+        if base.as_deref() == Some(theirs.as_str()) {
+            repo.push()?;
+            self.say("⬆️  Pushed local changes");
+            self.emit(serde_json::json!({ "action": "pushed", "merged_projects": [] }));
+            return Ok(());
+        }
+        if base.as_deref() == Some(ours.as_str()) {
+            repo.fast_forward(&theirs)?;
+            self.say("⬇️  Pulled remote changes");
+            self.emit(serde_json::json!({ "action": "pulled", "merged_projects": [] }));
+            return Ok(());
+        }
+        // A freshly initialized vault simply adopts the remote history
+        if repo.commit_count()? == 1 && self.storage.list_projects()?.is_empty() {
+            repo.reset_to(&theirs)?;
+            self.say("⬇️  Pulled the vault from the remote");
+            self.emit(serde_json::json!({ "action": "pulled", "merged_projects": [] }));
+            return Ok(());
+        }
+        
+        // Work out every merged project before touching the working tree
+        let mut merged = Vec::new();
+        for name in repo.changed_projects(base.as_deref(), &ours, &theirs)? {
+            let b = base.as_deref().and_then(|base| repo.show_project(base, &name));
+            let o = repo.show_project(&ours, &name);
+            let t = repo.show_project(&theirs, &name);
+            let blob = match merge::three_way(b.as_ref(), o.as_ref(), t.as_ref()) {
+                Merged::Resolved(blob) if blob == o => continue,
+                Merged::Resolved(blob) => blob,
+                Merged::Conflict => match (o, t) {
+                    (Some(_), Some(t)) => {
+                        let parse = |blob: &[u8]| serde_json::from_slice::<EncryptedProject>(blob);
+                        let base = b.as_deref().map(parse).transpose()?;
+                        let (project, key, slots) = self.merge_project_versions(&name, base.as_ref(), &parse(&t)?)?;
+                        let encrypted = crypto::encrypt_project_with_key(&project, &key, slots)?;
+                        Some(serde_json::to_string_pretty(&encrypted)?.into_bytes())
+                    }
+                    (o, t) => {
+                        let answer = self.prompter.read_line(&format!(
+                            "Project '{}' was deleted on one side and changed on the other. Keep it? (Y/n): ",
+                            name
+                        ))?;
+                        if answer.trim().eq_ignore_ascii_case("n") { None } else { o.or(t) }
+                    }
+                },
+            };
+            merged.push((name, blob));
+        }
+        
+        repo.start_merge(&theirs)?;
+        let finish = || -> Result<()> {
+            for (name, blob) in &merged {
+                let path = dir.join(format!("{}.encrypted", name));
+                match blob {
+                    Some(blob) => fs::write(&path, blob)?,
+                    None if path.exists() => fs::remove_file(&path)?,
+                    None => {}
+                }
+            }
+            let names: Vec<&str> = merged.iter().map(|(name, _)| name.as_str()).collect();
+            repo.add_projects(&names)?;
+            repo.commit(&format!("Merge remote vault changes ({} project(s))", merged.len()))
+        };
+        if let Err(e) = finish() {
+            repo.abort_merge();
+            return Err(e);
+        }
+        repo.push()?;
         
-        println!("✅ Project '{}' created successfully!", project_name);
+        self.say(format!("🔀 Merged remote changes into {} project(s) and pushed", merged.len()));
+        self.emit(serde_json::json!({
+            "action": "merged",
+            "merged_projects": merged.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+        }));
         Ok(())
     }
     
-    fn list_projects(&self) -> Result<()> {
-        let projects = self.storage.list_projects()?;
-        
-        if projects.is_empty() {
-            println!("No projects found. Create one with: secrets-manager create <project-name>");
-            return Ok(());
+    /// Opens another version of a project, trying known data keys before prompting
+    ///
+    /// Legacy files have no data key, so `None` is returned for them.
+    fn open_version(&self, encrypted: &EncryptedProject, known_keys: &[&ProjectKey]) -> Result<(Project, Option<ProjectKey>)> {
+        if crypto::is_legacy(encrypted) {
+            self.say(format!("Another version of '{}' predates key slots; unlock it to continue.", encrypted.project_name));
+            let credentials = self.get_credentials(encrypted.credentials.unwrap_or_default())?;
+            return Ok((crypto::decrypt_legacy_project(encrypted, &credentials)?, None));
         }
-        
-        println!("📁 Available projects:");
-        for project in projects {
-            println!("  • {}", project);
+        for key in known_keys {
+            if let Ok(project) = crypto::decrypt_project_with_key(encrypted, key) {
+                return Ok((project, Some(ProjectKey::from_bytes(key.key_bytes())?)));
+            }
         }
-        Ok(())
+        self.say(format!("Another version of '{}' uses a different key; unlock it to continue.", encrypted.project_name));
+        let (key, _) = self.unlock_key(encrypted)?;
+        Ok((crypto::decrypt_project_with_key(encrypted, &key)?, Some(key)))
     }
     
-    fn add_secret(&self, project_name: &str, key: &str, value: Option<String>) -> Result<()> {
-        let password = Self::get_password()?;
-        let mut project = self.storage.load_project(project_name, &password)?;
+    /// Three-way merges another version of a stored project into it, prompting for conflicts
+    ///
+    /// Without a base, nothing counts as removed: keys only one side has are kept.
+    /// The result keeps our data key and slots, taking slot changes from their
+    /// side, unless their side was re-keyed: then its key and slots win.
+    fn merge_project_versions(
+        &self,
+        project_name: &str,
+        base: Option<&EncryptedProject>,
+        theirs: &EncryptedProject,
+    ) -> Result<(Project, ProjectKey, Vec<KeySlot>)> {
+        let (our_project, our_key) = self.unlock(project_name)?;
+        let our_slots = self.storage.load_encrypted(project_name)?.slots;
+        let (their_project, their_key) = self.open_version(theirs, &[&our_key])?;
+        if their_project.name != project_name {
+            return Err(anyhow::anyhow!(
+                "The other version holds project '{}', not '{}'",
+                their_project.name, project_name
+            ));
+        }
+        let base_project = match base {
+            Some(encrypted) => {
+                let mut known = vec![&our_key];
+                known.extend(their_key.as_ref());
+                Some(self.open_version(encrypted, &known)?.0)
+            }
+            None => None,
+        };
         
-        let secret_value = match value {
-            Some(v) => v,
-            None => Self::get_secret_value(key)?,
+        let empty = std::collections::HashMap::new();
+        let base_secrets = base_project.as_ref().map_or(&empty, |project| &project.secrets);
+        let mut merge = merge::merge_secrets(base_secrets, &our_project.secrets, &their_project.secrets);
+        for (key, change) in &merge.applied {
+            self.say(format!("  • {}: {} on the other side", key, change));
+        }
+        self.resolve_conflicts(project_name, &mut merge)?;
+        let environments = self.merge_environments(project_name, base_project.as_ref(), &our_project, &their_project)?;
+        let base_extends = base_project.as_ref().map(|project| &project.extends);
+        let extends = match merge::three_way(base_extends, Some(&our_project.extends), Some(&their_project.extends)) {
+            Merged::Resolved(extends) => extends.unwrap_or_default(),
+            Merged::Conflict => {
+                self.say(format!("⚠️  The projects '{}' extends changed on both sides; keeping ours", project_name));
+                our_project.extends.clone()
+            }
         };
         
-        project.add_secret(key.to_string(), secret_value);
-        self.storage.save_project(&project, &password)?;
+        let project = Project {
+            name: our_project.name.clone(),
+            secrets: std::mem::take(&mut merge.merged),
+            environments,
+            extends,
+            created_at: our_project.created_at,
+            updated_at: chrono::Utc::now(),
+            stored_version: our_project.stored_version.clone(),
+        };
         
-        println!("✅ Secret '{}' added to project '{}'", key, project_name);
-        Ok(())
-    }
-    
-    fn remove_secret(&self, project_name: &str, key: &str) -> Result<()> {
-        let password = Self::get_password()?;
-        let mut project = self.storage.load_project(project_name, &password)?;
+        let Some(their_key) = their_key else {
+            return Ok((project, our_key, our_slots));
+        };
+        if their_key.key_bytes() != our_key.key_bytes() {
+            self.say(format!("⚠️  '{}' was re-keyed on the other side; keeping its key slots", project_name));
+            return Ok((project, their_key, theirs.slots.clone()));
+        }
         
-        if project.remove_secret(key).is_some() {
-            self.storage.save_project(&project, &password)?;
-            println!("✅ Secret '{}' removed from project '{}'", key, project_name);
-        } else {
-            println!("❌ Secret '{}' not found in project '{}'", key, project_name);
+        let base_slots = base.map(|encrypted| encrypted.slots.clone()).unwrap_or_default();
+        let mut ids: Vec<u32> = base_slots.iter()
+            .chain(&our_slots)
+            .chain(&theirs.slots)
+            .map(|slot| slot.id)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        let find = |slots: &[KeySlot], id: u32| slots.iter().find(|slot| slot.id == id).cloned();
+        let mut slots = Vec::new();
+        for id in ids {
+            let (b, o, t) = (find(&base_slots, id), find(&our_slots, id), find(&theirs.slots, id));
+            match merge::three_way(b.as_ref(), o.as_ref(), t.as_ref()) {
+                Merged::Resolved(slot) => slots.extend(slot),
+                Merged::Conflict => {
+                    self.say(format!("⚠️  Key slot {} of '{}' changed on both sides; keeping ours", id, project_name));
+                    slots.extend(o);
+                }
+            }
         }
-        Ok(())
+        if slots.is_empty() {
+            slots = our_slots;
+        }
+        Ok((project, our_key, slots))
     }
     
-    fn show_project(&self, project_name: &str) -> Result<()> {
-        let password = Self::get_password()?;
-        let project = self.storage.load_project(project_name, &password)?;
+    /// Merges every environment the way the top-level secrets are merged
+    ///
+    /// An environment removed on one side stays removed unless the other side
+    /// changed its base; a base changed differently on both sides keeps ours.
+    fn merge_environments(
+        &self,
+        project_name: &str,
+        base: Option<&Project>,
+        ours: &Project,
+        theirs: &Project,
+    ) -> Result<BTreeMap<String, Environment>> {
+        let names: BTreeSet<&String> = base.iter()
+            .flat_map(|project| project.environments.keys())
+            .chain(ours.environments.keys())
+            .chain(theirs.environments.keys())
+            .collect();
         
-        println!("🔐 Project: {}", project.name);
-        println!("📅 Created: {}", project.created_at.format("%Y-%m-%d %H:%M:%S UTC"));
-        println!("📅 Updated: {}", project.updated_at.format("%Y-%m-%d %H:%M:%S UTC"));
-        println!();
-        
-        if project.secrets.is_empty() {
-            println!("No secrets found. Add one with: secrets-manager add {} <key>", project_name);
-        } else {
-            println!("Secrets:");
-            for key in project.list_secrets() {
-                println!("  • {}", key);
+        let empty = std::collections::HashMap::new();
+        let mut environments = BTreeMap::new();
+        for name in names {
+            let (b, o, t) = (
+                base.and_then(|project| project.environments.get(name)),
+                ours.environments.get(name),
+                theirs.environments.get(name),
+            );
+            let label = format!("{} ({})", project_name, name);
+            let inherits = |environment: Option<&Environment>| environment.map(|environment| environment.inherits.clone());
+            let inherits = match merge::three_way(inherits(b).as_ref(), inherits(o).as_ref(), inherits(t).as_ref()) {
+                Merged::Resolved(Some(inherits)) => inherits,
+                Merged::Resolved(None) => continue,
+                Merged::Conflict => {
+                    self.say(format!("⚠️  Environment '{}' of '{}' changed on both sides; keeping our base", name, project_name));
+                    inherits(o).or_else(|| inherits(t)).flatten()
+                }
+            };
+            
+            let [b, o, t] = [b, o, t].map(|environment| environment.map_or(&empty, |environment| &environment.secrets));
+            let mut merge = merge::merge_secrets(b, o, t);
+            for (key, change) in &merge.applied {
+                self.say(format!("  • {} ({}): {} on the other side", key, name, change));
             }
+            self.resolve_conflicts(&label, &mut merge)?;
+            environments.insert(name.clone(), Environment { inherits, secrets: std::mem::take(&mut merge.merged) });
         }
-        Ok(())
+        Ok(environments)
     }
     
-    fn export_project(&self, project_name: &str, format: &str, output: Option<String>, force: bool) -> Result<()> {
-        let password = Self::get_password()?;
-        let project = self.storage.load_project(project_name, &password)?;
-        
-        let export_format = match format.to_lowercase().as_str() {
-            "shell" => ExportFormat::Shell,
-            "env" => ExportFormat::EnvFile,
-            "json" => ExportFormat::Json,
-            _ => return Err(anyhow::anyhow!("Invalid format. Use: shell, env, or json")),
+    /// Merges another copy of a project file into the stored project
+    fn merge_project(&self, project_name: &str, other_file: &Path, base_file: Option<&Path>) -> Result<()> {
+        let read = |path: &Path| -> Result<EncryptedProject> {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Could not read '{}': {}", path.display(), e))?;
+            serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("'{}' is not a project file: {}", path.display(), e))
         };
+        let theirs = read(other_file)?;
+        let base = base_file.map(read).transpose()?;
+        if base.is_none() {
+            self.say("No --base given: keys missing on one side are kept, not removed.");
+        }
         
-        let content = self.format_export(&project, &export_format)?;
+        let (project, project_key, slots) = self.merge_project_versions(project_name, base.as_ref(), &theirs)?;
+        self.storage.save_project_with_slots(&project, &project_key, slots)?;
+        self.cache_key(project_name, &project_key);
         
-        match output {
-            Some(file_path) => {
-                // Check .gitignore guardrail before writing file
-                if force {
-                    println!("⚠️  WARNING: Exporting to '{}' without checking .gitignore!", file_path);
-                    println!("   This may result in accidental commits.");
-                    println!();
-                } else {
-                    self.check_gitignore_guardrail(&file_path)?;
-                }
-                
-                std::fs::write(&file_path, content)?;
-                println!("✅ Exported to: {}", file_path);
-            }
-            None => {
-                print!("{}", content);
-            }
-        }
+        self.say(format!("✅ Merged '{}' into project '{}'", other_file.display(), project_name));
+        self.emit(serde_json::json!({ "project": project_name, "merged_versions": 1 }));
         Ok(())
     }
     
-    fn format_export(&self, project: &Project, format: &ExportFormat) -> Result<String> {
-        match format {
-            ExportFormat::Shell => {
-                let mut output = String::new();
-                for (key, value) in &project.secrets {
-                    output.push_str(&format!("export {}='{}'\n", key, value));
+    /// Merges revisions written concurrently on other machines into the stored project
+    fn merge_forks(&self, project_name: &str) -> Result<()> {
+        let forks = self.storage.forks(project_name)?;
+        if forks.is_empty() {
+            self.say(format!("Project '{}' has no concurrent versions to merge.", project_name));
+            self.emit(serde_json::json!({ "project": project_name, "merged_versions": 0 }));
+            return Ok(());
+        }
+        
+        let parse = |blob: &[u8]| serde_json::from_slice::<EncryptedProject>(blob);
+        for fork in &forks {
+            let (project, project_key, slots) = match &fork.data {
+                Some(data) => {
+                    let base = fork.base.as_deref().map(parse).transpose()?;
+                    self.merge_project_versions(project_name, base.as_ref(), &parse(data)?)?
                 }
-                Ok(output)
-            }
-            ExportFormat::EnvFile => {
-                let mut output = String::new();
-                for (key, value) in &project.secrets {
-                    output.push_str(&format!("{}={}\n", key, value));
+                None => {
+                    self.say(format!("Another machine deleted '{}' while it was changed here; keeping it.", project_name));
+                    let (project, project_key) = self.unlock(project_name)?;
+                    let slots = self.storage.load_encrypted(project_name)?.slots;
+                    (project, project_key, slots)
                 }
-                Ok(output)
-            }
-            ExportFormat::Json => {
-                serde_json::to_string_pretty(&project.secrets).map_err(|e| e.into())
-            }
+            };
+            self.storage.save_merged(&project, &project_key, slots, std::slice::from_ref(&fork.id))?;
         }
+        
+        self.say(format!("✅ Merged {} concurrent version(s) into project '{}'", forks.len(), project_name));
+        self.emit(serde_json::json!({ "project": project_name, "merged_versions": forks.len() }));
+        Ok(())
     }
     
-    fn import_project(&self, project_name: &str, env_file: &str) -> Result<()> {
-        // Check if the .env file exists
-        if !Path::new(env_file).exists() {
-            return Err(anyhow::anyhow!("File '{}' not found", env_file));
-        }
-
-        // Load the project
-        let password = Self::get_password()?;
-        let mut project = self.storage.load_project(project_name, &password)?;
-
-        // Parse the .env file
-        let env_content = fs::read_to_string(env_file)?;
-        let env_vars = self.parse_env_file(&env_content)?;
-
-        if env_vars.is_empty() {
-            println!("No environment variables found in '{}'", env_file);
-            return Ok(());
+    /// Asks which side wins for every secret both sides changed differently
+    fn resolve_conflicts(&self, project_name: &str, merge: &mut SecretMerge) -> Result<()> {
+        for conflict in std::mem::take(&mut merge.conflicts) {
+            self.say(format!("⚠️  Conflict in '{}': '{}' was changed differently on both sides", project_name, conflict.key));
+            self.say(format!("   ours:   {}", merge::fingerprint(conflict.ours.as_deref())));
+            self.say(format!("   theirs: {}", merge::fingerprint(conflict.theirs.as_deref())));
+            let value = loop {
+                let answer = self.prompter.read_line("Keep [o]urs, [t]heirs, [s]how both values or [a]bort? ")?;
+                match answer.trim().to_lowercase().as_str() {
+                    "o" | "ours" => break conflict.ours.clone(),
+                    "t" | "theirs" => break conflict.theirs.clone(),
+                    // Next to the prompt on stderr, so the values never end up in redirected output
+                    "s" | "show" => self.console.eprint(&Zeroizing::new(format!(
+                        "   ours:   {}\n   theirs: {}\n",
+                        conflict.ours.as_deref().unwrap_or("(deleted)"),
+                        conflict.theirs.as_deref().unwrap_or("(deleted)"),
+                    ))),
+                    "a" | "abort" | "" => return Err(anyhow::anyhow!("Merge aborted; nothing was changed")),
+                    _ => {}
+                }
+            };
+            merge.resolve(&conflict.key, value);
         }
-
-        println!("Found {} environment variables in '{}'", env_vars.len(), env_file);
+        Ok(())
+    }
+    
+    fn split_project(&self, project_name: &str, shares: u8, threshold: u8) -> Result<()> {
+        let (_, project_key) = self.unlock(project_name)?;
+        let shares = crypto::split_project_key(&project_key, threshold, shares)?;
         
-        let mut imported_count = 0;
-        let mut skipped_count = 0;
-
-        for (key, value) in env_vars {
-            if project.get_secret(&key).is_some() {
-                // Key already exists, prompt for confirmation
-                print!("Key '{}' already exists. Overwrite? (y/N): ", key);
-                io::stdout().flush()?;
-                
-                let mut confirmation = String::new();
-                io::stdin().read_line(&mut confirmation)?;
-                
-                if confirmation.trim().to_lowercase() == "y" || confirmation.trim().to_lowercase() == "yes" {
-                    project.add_secret(key.clone(), value);
-                    imported_count += 1;
-                    println!("✅ Imported '{}'", key);
-                } else {
-                    skipped_count += 1;
-                    println!("⏭️  Skipped '{}'", key);
+        let encoded: Vec<Zeroizing<String>> = shares.iter().map(Share::encode).collect();
+        match self.output {
+            OutputFormat::Text => {
+                self.say(format!("🧩 Key shares for '{}' (any {} of {} unlock it; hand each to a different custodian):", project_name, threshold, shares.len()));
+                for share in &encoded {
+                    self.say(format!("  {}", share.as_str()));
                 }
-            } else {
-                // Key doesn't exist, add it directly
-                project.add_secret(key.clone(), value);
-                imported_count += 1;
-                println!("✅ Imported '{}'", key);
+                self.say("Shares stay valid until the project is re-keyed with `combine --rekey`.");
             }
+            OutputFormat::Json => self.emit(serde_json::json!({
+                "project": project_name,
+                "threshold": threshold,
+                "shares": encoded.iter().map(|share| share.as_str()).collect::<Vec<_>>(),
+            })),
         }
-
-        // Save the updated project
-        self.storage.save_project(&project, &password)?;
-
-        println!();
-        println!("📊 Import Summary:");
-        println!("  • Imported: {}", imported_count);
-        println!("  • Skipped: {}", skipped_count);
-        println!("  • Total processed: {}", imported_count + skipped_count);
-
         Ok(())
     }
-
-    /// Parses a .env file content and returns a HashMap of key-value pairs
-    fn parse_env_file(&self, content: &str) -> Result<std::collections::HashMap<String, String>> {
-        let mut env_vars = std::collections::HashMap::new();
+    
+    /// Reads shares from `--shares-file` or, one at a time, from the terminal
+    ///
+    /// Every share is checksummed as it is read so a typo is reported right away.
+    fn read_shares(&self, file: Option<&Path>) -> Result<Vec<Share>> {
+        let mut shares = Vec::new();
+        if let Some(path) = file {
+            // Never from argv, where shares would end up in shell history and `ps`
+            let content = Zeroizing::new(if path == Path::new("-") {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Could not read '{}': {}", path.display(), e))?
+            });
+            for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+                let share = Share::decode(line.trim())
+                    .map_err(|e| anyhow::anyhow!("Share on line {}: {}", i + 1, e))?;
+                shares.push(share);
+            }
+            if shares.is_empty() {
+                return Err(anyhow::anyhow!("No shares found in '{}'", path.display()));
+            }
+            return Ok(shares);
+        }
         
-        for line in content.lines() {
-            let line = line.trim();
-            
-            // Skip empty lines and comments
-            if line.is_empty() || line.starts_with('#') {
-                continue;
+        loop {
+            let needed = shares.first().map(|share: &Share| share.threshold as usize);
+            if needed.is_some_and(|needed| shares.len() >= needed) {
+                return Ok(shares);
             }
-            
-            // Find the first '=' character
-            if let Some(equal_pos) = line.find('=') {
-                let key = line[..equal_pos].trim().to_string();
-                let value = line[equal_pos + 1..].trim().to_string();
-                
-                // Remove quotes if present
-                let value = if (value.starts_with('"') && value.ends_with('"')) || 
-                              (value.starts_with('\'') && value.ends_with('\'')) {
-                    value[1..value.len()-1].to_string()
-                } else {
-                    value
-                };
-                
-                if !key.is_empty() {
-                    env_vars.insert(key, value);
+            let prompt = match needed {
+                Some(needed) => format!("Enter share {} of {}: ", shares.len() + 1, needed),
+                None => "Enter share 1: ".to_string(),
+            };
+            let encoded = self.prompter.read_secret(&prompt)?;
+            if encoded.expose_secret().trim().is_empty() {
+                return Err(anyhow::anyhow!("Combining cancelled"));
+            }
+            match Share::decode(encoded.expose_secret()) {
+                Ok(share) if shares.iter().any(|s: &Share| s.index == share.index) => {
+                    self.say(format!("⚠️  Share {} was already entered", share.index));
                 }
+                Ok(share) => shares.push(share),
+                Err(e) => self.say(format!("⚠️  {}", e)),
             }
         }
-        
-        Ok(env_vars)
     }
-
-    fn delete_project(&self, project_name: &str) -> Result<()> {
-        if !self.storage.project_exists(project_name) {
-            return Err(anyhow::anyhow!("Project '{}' not found", project_name));
+    
+    /// Reconstructs a project's key from shares and sets a new password
+    ///
+    /// The new password replaces the old one in every kind of slot that held it.
+    /// With `rekey`, the data key is rotated: recipient slots are re-wrapped,
+    /// password and keyfile slots are recreated and every other slot is dropped,
+    /// invalidating the old shares.
+    fn combine_project(&self, project_name: &str, shares_file: Option<&Path>, rekey: bool) -> Result<()> {
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        if crypto::is_legacy(&encrypted) {
+            return Err(anyhow::anyhow!("Project '{}' predates key slots; unlock it once to upgrade", project_name));
         }
         
-        print!("⚠️  Are you sure you want to delete project '{}'? (y/N): ", project_name);
-        io::stdout().flush()?;
+        // Keyfile slots survive a password reset, but not a new data key
+        let candidates: &[CredentialKind] = if rekey {
+            &Self::REKEYED_KINDS
+        } else {
+            &[CredentialKind::Password, CredentialKind::PasswordAndKeyfile]
+        };
+        let kinds = Self::replacement_kinds(&encrypted.slots, candidates);
+        let keyfile = self.keyfile_for(project_name, &kinds)?;
         
-        let mut confirmation = String::new();
-        io::stdin().read_line(&mut confirmation)?;
+        let shares = self.read_shares(shares_file)?;
+        let project_key = crypto::combine_project_key(&shares)?;
+        let project = self.storage.load_project_with_key(project_name, &project_key)
+            .map_err(|_| anyhow::anyhow!("The shares don't reconstruct the key of project '{}'", project_name))?;
         
-        if confirmation.trim().to_lowercase() == "y" || confirmation.trim().to_lowercase() == "yes" {
-            self.storage.delete_project(project_name)?;
-            println!("✅ Project '{}' deleted successfully!", project_name);
+        let password = if kinds.iter().any(|kind| kind.needs_password()) {
+            self.say("Shares accepted. Choose a new master password.");
+            Some(self.get_password_with_confirmation()?)
+        } else {
+            None
+        };
+        
+        if rekey {
+            let new_key = ProjectKey::generate();
+            let slots = Self::rekey_slots(project_name, &encrypted.slots, encrypted.new_slot_id(), &new_key, &kinds, password.as_ref(), keyfile.as_ref())?;
+            let dropped = encrypted.slots.len() - slots.iter().filter(|slot| slot.kind == CredentialKind::Recipient).count();
+            
+            self.storage.save_project_with_slots(&project, &new_key, slots)?;
+            self.cache_key(project_name, &new_key);
+            self.say(format!("✅ Project '{}' re-keyed", project_name));
+            self.say(format!("   {} old slot(s) were replaced; previous shares no longer work", dropped));
+            self.emit(serde_json::json!({ "project": project_name, "rekeyed": true, "removed_slots": dropped }));
         } else {
-            println!("❌ Deletion cancelled");
+            let first_id = encrypted.new_slot_id();
+            let mut slots = encrypted.slots;
+            slots.retain(|slot| !slot.kind.needs_password());
+            slots.extend(Self::create_slots(project_name, first_id, &project_key, &kinds, password.as_ref(), keyfile.as_ref())?);
+            
+            self.storage.save_project_with_slots(&project, &project_key, slots)?;
+            self.cache_key(project_name, &project_key);
+            self.say(format!("✅ Password for project '{}' has been reset", project_name));
+            self.emit(serde_json::json!({ "project": project_name, "rekeyed": false, "removed_slots": 0 }));
         }
         Ok(())
     }
 
+    /// Serves the JSON API until the process is killed, printing its address and token
+    fn serve(&self, socket: Option<PathBuf>, listen: Option<String>) -> Result<()> {
+        let addr = match (socket, listen) {
+            (Some(path), _) => ListenAddr::Unix(path),
+            (None, Some(addr)) => {
+                // The API hands out secrets, so it must not be reachable from other machines
+                let resolved: Vec<std::net::SocketAddr> = std::net::ToSocketAddrs::to_socket_addrs(addr.as_str())
+                    .map_err(|e| anyhow::anyhow!("Invalid listen address '{}': {}", addr, e))?
+                    .collect();
+                if resolved.is_empty() || resolved.iter().any(|a| !a.ip().is_loopback()) {
+                    return Err(anyhow::anyhow!("Refusing to listen on '{}': only loopback addresses are allowed", addr));
+                }
+                ListenAddr::Tcp(addr)
+            }
+            (None, None) => return Err(anyhow::anyhow!("Pass --socket <path> or --listen 127.0.0.1:<port>")),
+        };
+        
+        let token = match std::env::var(server::API_TOKEN_ENV) {
+            Ok(token) if !token.is_empty() => SecretString::new(token),
+            _ => server::generate_token(),
+        };
+        let url = match &addr {
+            ListenAddr::Tcp(addr) => format!("http://{}", addr),
+            ListenAddr::Unix(path) => format!("unix:{}", path.display()),
+        };
+        
+        // Same eval-able shape as the agent's output, printed once the server listens
+        let announce = || match self.output {
+            OutputFormat::Text => self.say(format!(
+                "{}={}; export {};\n{}={}; export {};",
+                server::API_ADDR_ENV, url, server::API_ADDR_ENV,
+                server::API_TOKEN_ENV, token.expose_secret(), server::API_TOKEN_ENV
+            )),
+            OutputFormat::Json => self.emit(serde_json::json!({ "url": url, "token": token.expose_secret() })),
+        };
+        ApiServer::new(SecretStorage::with_backend(self.storage.backend()), token.clone())
+            .with_agent(self.agent.clone())
+            .run(&addr, announce)
+    }
+    
     /// Checks if the output file is properly ignored by .gitignore to prevent accidental commits
     fn check_gitignore_guardrail(&self, file_path: &str) -> Result<()> {
         let gitignore_path = ".gitignore";
         
         // Check if .gitignore exists
         if !Path::new(gitignore_path).exists() {
-            println!("⚠️  WARNING: No .gitignore file found in current directory!");
-            println!("   Exporting secrets to '{}' may result in accidental commits.", file_path);
-            println!("   Consider creating a .gitignore file and adding this file pattern.");
-            println!();
-            
-            print!("Do you want to continue with the export? (y/N): ");
-            io::stdout().flush()?;
+            self.say("⚠️  WARNING: No .gitignore file found in current directory!");
+            self.say(format!("   Exporting secrets to '{}' may result in accidental commits.", file_path));
+            self.say("   Consider creating a .gitignore file and adding this file pattern.");
+            self.say("");
             
-            let mut confirmation = String::new();
-            io::stdin().read_line(&mut confirmation)?;
-            
-            if confirmation.trim().to_lowercase() != "y" && confirmation.trim().to_lowercase() != "yes" {
+            if !self.confirm("Do you want to continue with the export?")? {
                 return Err(anyhow::anyhow!("Export cancelled by user"));
             }
             return Ok(());
@@ -433,17 +2756,17 @@ impl SecretManager {
         let gitignore_content = match fs::read_to_string(gitignore_path) {
             Ok(content) => content,
             Err(_) => {
-                println!("⚠️  WARNING: Could not read .gitignore file!");
+                self.say("⚠️  WARNING: Could not read .gitignore file!");
                 return self.prompt_for_unsafe_export(file_path);
             }
         };
         
         // Check if the file is ignored
         if !self.is_file_ignored(file_path, &gitignore_content) {
-            println!("⚠️  WARNING: '{}' is not listed in .gitignore!", file_path);
-            println!("   This file may be accidentally committed to version control.");
-            println!("   Consider adding this file pattern to your .gitignore file.");
-            println!();
+            self.say(format!("⚠️  WARNING: '{}' is not listed in .gitignore!", file_path));
+            self.say("   This file may be accidentally committed to version control.");
+            self.say("   Consider adding this file pattern to your .gitignore file.");
+            self.say("");
             
             return self.prompt_for_unsafe_export(file_path);
         }
@@ -454,13 +2777,7 @@ impl SecretManager {
     
     /// Prompts user for confirmation when exporting to an unsafe location
     fn prompt_for_unsafe_export(&self, _file_path: &str) -> Result<()> {
-        print!("Do you want to continue with the export? (y/N): ");
-        io::stdout().flush()?;
-        
-        let mut confirmation = String::new();
-        io::stdin().read_line(&mut confirmation)?;
-        
-        if confirmation.trim().to_lowercase() != "y" && confirmation.trim().to_lowercase() != "yes" {
+        if !self.confirm("Do you want to continue with the export?")? {
             return Err(anyhow::anyhow!("Export cancelled by user"));
         }
         
@@ -480,8 +2797,7 @@ impl SecretManager {
             }
             
             // Handle directory patterns (ending with /)
-            if pattern.ends_with('/') {
-                let dir_pattern = &pattern[..pattern.len() - 1];
+            if let Some(dir_pattern) = pattern.strip_suffix('/') {
                 if file_path.starts_with(dir_pattern) {
                     return true;
                 }
@@ -542,3 +2858,541 @@ impl SecretManager {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::ScriptedConsole;
+    use serde_json::{json, Value};
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    /// A vault in a temporary directory, driven by command lines the way the binary is
+    struct Vault {
+        dir: TempDir,
+        agent: Option<AgentClient>,
+    }
+
+    impl Vault {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            fs::create_dir(dir.path().join("vault")).unwrap();
+            Self { dir, agent: None }
+        }
+
+        fn vault_dir(&self) -> PathBuf {
+            self.dir.path().join("vault")
+        }
+
+        /// A path for scratch files next to the vault
+        fn path(&self, name: &str) -> String {
+            self.dir.path().join(name).display().to_string()
+        }
+
+        /// Runs a command line, answering its prompts in order; all answers must be used
+        fn run(&self, line: &str, answers: &[&str]) -> Result<ScriptedConsole> {
+            let console = ScriptedConsole::new(answers.iter().copied());
+            execute(&self.vault_dir(), self.agent.clone(), line, console.clone())?;
+            assert_eq!(console.remaining_answers(), 0, "unused answers for '{}'", line);
+            Ok(console)
+        }
+
+        /// Runs a command line with `--output json` and returns its result document
+        fn json(&self, line: &str, answers: &[&str]) -> Value {
+            let console = self.run(&format!("--output json {}", line), answers).unwrap();
+            let document: Value = serde_json::from_str(&console.stdout()).unwrap();
+            assert_eq!(document["ok"], true);
+            document
+        }
+
+        /// Starts a command that runs until the process exits, like `agent` and `serve`
+        fn spawn(&self, line: &str) -> ScriptedConsole {
+            let console = ScriptedConsole::default();
+            let (dir, agent, line, output) = (self.vault_dir(), self.agent.clone(), line.to_string(), console.clone());
+            std::thread::spawn(move || execute(&dir, agent, &line, output));
+            console
+        }
+    }
+
+    fn execute(dir: &Path, agent: Option<AgentClient>, line: &str, console: ScriptedConsole) -> Result<()> {
+        let mut cli = Cli::try_parse_from(std::iter::once("secrets-manager").chain(line.split_whitespace()))?;
+        if let Some(warning) = cli.apply_deprecated_output()? {
+            console.eprint(&format!("⚠️  {}\n", warning));
+        }
+        let output = cli.output_format();
+        let storage = SecretStorage::with_backend(StorageLayout::detect(dir).open(dir)?);
+        SecretManager::with_storage(storage)
+            .with_agent(agent)
+            .with_keyfile(cli.keyfile)
+            .with_identity(cli.identity)
+            .with_vault_dir(Some(dir.to_path_buf()))
+            .with_output(output)
+            .with_console(console.clone(), console)
+            .handle_command(cli.command)
+    }
+
+    fn wait_for(mut ready: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !ready() {
+            assert!(started.elapsed() < Duration::from_secs(10), "timed out waiting");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_create_add_generate_show_remove_list() {
+        let vault = Vault::new();
+        let created = vault.run("create api", &["pw", "pw", "n"]).unwrap();
+        assert!(created.stdout().contains("Project 'api' created"));
+        assert!(vault.run("create api", &[]).is_err());
+
+        vault.run("add api HOST localhost", &["pw"]).unwrap();
+        vault.run("add api TOKEN", &["pw", "s3cret"]).unwrap();
+        let generated = vault.json("generate api SEED --kind hex --length 4 --show", &["pw"]);
+        assert_eq!(generated["value"].as_str().unwrap().len(), 8);
+        assert!(vault.run("generate api SEED", &["pw"]).is_err());
+
+        assert_eq!(vault.json("list", &[])["projects"], json!(["api"]));
+        assert_eq!(vault.json("show api", &["pw"])["keys"], json!(["HOST", "SEED", "TOKEN"]));
+        assert_eq!(vault.json("remove api HOST", &["pw"])["removed"], true);
+        assert_eq!(vault.json("remove api HOST", &["pw"])["removed"], false);
+
+        let err = vault.run("show api", &["wrong"]).unwrap_err();
+        assert_eq!(exit_code(&err), EXIT_WRONG_PASSWORD);
+    }
+
+    #[test]
+    fn test_import_export_delete() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api A 1", &["pw"]).unwrap();
+
+        let env_file = vault.path("import.env");
+        fs::write(&env_file, "# comment\nA=2\nB=\"two words\"\n").unwrap();
+        let imported = vault.json(&format!("import api {}", env_file), &["pw", "n"]);
+        assert_eq!((&imported["imported"], &imported["skipped"]), (&json!(["B"]), &json!(["A"])));
+
+        let exported = vault.run("export api --format env", &["pw"]).unwrap().stdout();
+        let mut lines: Vec<&str> = exported.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["A=1", "B=two words"]);
+
+        let output = vault.path("secrets.json");
+        vault.json(&format!("export api --format json --out-file {} --force", output), &["pw"]);
+        let written: Value = serde_json::from_str(&fs::read_to_string(&output).unwrap()).unwrap();
+        assert_eq!(written, json!({ "A": "1", "B": "two words" }));
+
+        // The file used to be given with --output, which still works for now
+        let env_file = vault.path("secrets.env");
+        let exported = vault.run(&format!("export api --format env --output {} --force", env_file), &["pw"]).unwrap();
+        assert!(exported.stderr().contains("deprecated"));
+        assert!(fs::read_to_string(&env_file).unwrap().contains("A=1\n"));
+        assert!(vault.run(&format!("show api --output {}", env_file), &[]).is_err());
+
+        assert_eq!(vault.json("delete api", &["n"])["deleted"], false);
+        assert_eq!(vault.json("delete api", &["y"])["deleted"], true);
+        assert_eq!(exit_code(&vault.run("delete api", &[]).unwrap_err()), EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_environments() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("env create api base", &["pw"]).unwrap();
+        vault.run("env create api prod --inherits base", &["pw"]).unwrap();
+        vault.run("add api LOG_LEVEL debug --env base", &["pw"]).unwrap();
+        vault.run("add api CODE 0 --env base", &["pw"]).unwrap();
+        vault.run("add api LOG_LEVEL warn --env prod", &["pw"]).unwrap();
+        vault.run("add api CODE 3 --env staging", &["pw"]).unwrap();
+
+        assert_eq!(vault.json("get api LOG_LEVEL --env prod", &["pw"])["value"], "warn");
+        assert_eq!(vault.run("get api CODE --env prod", &["pw"]).unwrap().stdout(), "0\n");
+        assert!(vault.run("get api CODE", &["pw"]).is_err());
+        let shown = vault.json("show api --env prod", &["pw"]);
+        assert_eq!((&shown["keys"], &shown["inherited"]), (&json!(["CODE", "LOG_LEVEL"]), &json!({ "CODE": "base" })));
+
+        let env_file = vault.path("prod.env");
+        fs::write(&env_file, "LOG_LEVEL=error\nREGION=eu\n").unwrap();
+        vault.run(&format!("import api {} --env prod", env_file), &["pw", "y"]).unwrap();
+        let exported = vault.run("export api --env prod --format env", &["pw"]).unwrap().stdout();
+        let mut lines: Vec<&str> = exported.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["CODE=0", "LOG_LEVEL=error", "REGION=eu"]);
+
+        let diff = vault.json("diff-env api", &["pw"]);
+        assert_eq!(diff["environments"], json!(["base", "prod", "staging"]));
+        assert_eq!(diff["differs"][0], json!({ "key": "CODE", "values": { "base": "A", "prod": "A", "staging": "B" } }));
+        assert_eq!(diff["differs"].as_array().unwrap().len(), 3);
+
+        let script = vault.path("exit.sh");
+        fs::write(&script, "exit $CODE\n").unwrap();
+        vault.run(&format!("run api --env prod -- sh {}", script), &["pw"]).unwrap();
+        let err = vault.run(&format!("run api --env staging -- sh {}", script), &["pw"]).unwrap_err();
+        assert_eq!(exit_code(&err), 3);
+
+        assert!(vault.run("env remove api base", &["pw"]).is_err());
+        assert_eq!(vault.json("env remove api staging", &["pw", "y"])["removed"], true);
+        assert_eq!(vault.json("env list api", &["pw"])["environments"][1]["inherits"], "base");
+    }
+
+    #[test]
+    fn test_extends() {
+        let vault = Vault::new();
+        vault.run("create shared --recovery-codes 0", &["spw", "spw"]).unwrap();
+        vault.run("add shared SENTRY_DSN sentry", &["spw"]).unwrap();
+        vault.run("add shared REGISTRY_TOKEN shared", &["spw"]).unwrap();
+        vault.run("add shared SENTRY_DSN sentry-prod --env prod", &["spw"]).unwrap();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api REGISTRY_TOKEN own", &["pw"]).unwrap();
+        vault.run("env create api prod", &["pw"]).unwrap();
+
+        assert!(vault.run("extends api missing", &["pw"]).is_err());
+        assert_eq!(vault.json("extends api shared", &["pw", "spw"])["extends"], json!(["shared"]));
+        let shown = vault.json("show api", &["pw", "spw"]);
+        assert_eq!((&shown["keys"], &shown["extended"]), (&json!(["REGISTRY_TOKEN", "SENTRY_DSN"]), &json!({ "SENTRY_DSN": "shared" })));
+
+        let exported = vault.run("export api --format env", &["pw", "spw"]).unwrap().stdout();
+        let mut lines: Vec<&str> = exported.lines().collect();
+        lines.sort();
+        assert_eq!(lines, ["REGISTRY_TOKEN=own", "SENTRY_DSN=sentry"]);
+        assert_eq!(vault.json("get api SENTRY_DSN --env prod", &["pw", "spw"])["value"], "sentry-prod");
+
+        let err = vault.run("get api SENTRY_DSN", &["pw", "wrong"]).unwrap_err();
+        assert_eq!(exit_code(&err), EXIT_WRONG_PASSWORD);
+        let err = vault.run("extends shared api", &["spw", "pw"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("cycle: shared -> api -> shared"));
+        assert_eq!(vault.json("extends shared", &["spw"])["extends"], json!([]));
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let vault = Vault::new();
+        vault.run("create shared --recovery-codes 0", &["spw", "spw"]).unwrap();
+        vault.run("add shared SENTRY_DSN https://sentry", &["spw"]).unwrap();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api DB_USER app", &["pw"]).unwrap();
+        vault.run("add api DB_HOST db.internal", &["pw"]).unwrap();
+        vault.run("add api DATABASE_URL postgres://${DB_USER}@${DB_HOST}/app", &["pw"]).unwrap();
+        vault.run("add api SENTRY_DSN ${shared:SENTRY_DSN}", &["pw"]).unwrap();
+
+        assert_eq!(vault.json("get api DATABASE_URL", &["pw"])["value"], "postgres://app@db.internal/app");
+        assert_eq!(vault.json("get api DATABASE_URL --raw", &["pw"])["value"], "postgres://${DB_USER}@${DB_HOST}/app");
+        let exported = vault.run("export api --format env", &["pw", "spw"]).unwrap().stdout();
+        assert!(exported.lines().any(|line| line == "SENTRY_DSN=https://sentry"));
+
+        vault.run("add api A ${B}", &["pw"]).unwrap();
+        vault.run("add api B ${A}", &["pw"]).unwrap();
+        let err = vault.run("export api --format env", &["pw"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("cycle"));
+        assert!(vault.run("export api --format env --raw", &["pw"]).unwrap().stdout().contains("A=${B}"));
+        assert_eq!(vault.json("get api DB_HOST", &["pw"])["value"], "db.internal");
+    }
+
+    #[test]
+    fn test_render() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api DB_PASS pa$$word", &["pw"]).unwrap();
+        vault.run("add api PORT 8080", &["pw"]).unwrap();
+
+        let template = vault.path("app.properties.tmpl");
+        fs::write(&template, "port={{ PORT }}\npassword={{ DB_PASS }}\nurl=db://u:{{ DB_PASS | url }}@h\n").unwrap();
+        let rendered = vault.run(&format!("render api {}", template), &["pw"]).unwrap().stdout();
+        assert_eq!(rendered, "port=8080\npassword=pa$$word\nurl=db://u:pa%24%24word@h\n");
+
+        let output = vault.path("app.properties");
+        let written = vault.run(&format!("render api {} --out-file {}", template, output), &["pw", "y"]).unwrap();
+        assert!(written.prompts().iter().any(|prompt| prompt.contains("continue")));
+        assert_eq!(fs::read_to_string(&output).unwrap(), rendered);
+
+        fs::write(&template, "{{ MISSING }}").unwrap();
+        let err = vault.run(&format!("render api {}", template), &["pw"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Unknown secret 'MISSING'"));
+    }
+
+    #[test]
+    fn test_check_schema() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api DATABASE_URL localhost", &["pw"]).unwrap();
+        vault.run("add api OLD_TOKEN x", &["pw"]).unwrap();
+        let schema = vault.path("secrets.schema.toml");
+        fs::write(&schema, concat!(
+            "[DATABASE_URL]\nformat = \"url\"\n",
+            "[API_KEY]\ndescription = \"Key for the payments API\"\n",
+            "[PORT]\nformat = \"int\"\ndefault = \"8080\"\n",
+        )).unwrap();
+
+        let err = vault.run(&format!("check api --schema {}", schema), &["pw"]).unwrap_err();
+        assert!(format!("{:#}", err).ends_with("missing API_KEY; DATABASE_URL is not a URL"));
+        let err = vault.run(&format!("export api --schema {}", schema), &["pw"]).unwrap_err();
+        assert!(format!("{:#}", err).contains("are missing from project 'api': API_KEY"));
+        assert!(vault.run(&format!("run api --schema {} -- true", schema), &["pw"]).is_err());
+
+        vault.run("add api API_KEY k", &["pw"]).unwrap();
+        vault.run("add api DATABASE_URL postgres://db/app", &["pw"]).unwrap();
+        let checked = vault.json(&format!("check api --schema {}", schema), &["pw"]);
+        assert_eq!((&checked["valid"], &checked["defaulted"], &checked["extra"]), (&json!(true), &json!(["PORT"]), &json!(["OLD_TOKEN"])));
+        let exported = vault.run(&format!("export api --format env --schema {}", schema), &["pw"]).unwrap().stdout();
+        assert!(exported.lines().any(|line| line == "PORT=8080"));
+        vault.run(&format!("run api --schema {} -- true", schema), &["pw"]).unwrap();
+    }
+
+    #[test]
+    fn test_agent_unlock_lock() {
+        let mut vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        assert!(vault.run("unlock api", &[]).is_err());
+
+        let socket = vault.path("agent/agent.sock");
+        let console = vault.spawn(&format!("agent --socket {} --timeout 60", socket));
+        wait_for(|| !console.stdout().is_empty());
+        assert!(console.stdout().starts_with(agent::AGENT_SOCK_ENV));
+        let agent = AgentClient::new(&socket);
+        assert!(agent.list().is_ok());
+        vault.agent = Some(agent);
+
+        assert_eq!(vault.json("unlock api", &["pw"])["project"], "api");
+        vault.run("show api", &[]).unwrap();
+        assert_eq!(vault.json("lock api", &[])["project"], "api");
+        vault.run("show api", &["pw"]).unwrap();
+        assert_eq!(vault.json("lock", &[])["project"], Value::Null);
+        vault.run("show api", &["pw"]).unwrap();
+    }
+
+    #[test]
+    fn test_slots_and_recover() {
+        let vault = Vault::new();
+        let created = vault.json("create api --recovery-codes 1", &["pw", "pw"]);
+        let code = created["recovery_codes"][0].as_str().unwrap().to_string();
+        assert_eq!(vault.json("slot list api", &[])["slots"].as_array().unwrap().len(), 2);
+
+        let keyfile = vault.path("api.key");
+        let added = vault.json(&format!("slot add api --type keyfile --new-keyfile {}", keyfile), &["pw"]);
+        assert_eq!(added["slot"], 3);
+        vault.run(&format!("show api --keyfile {}", keyfile), &[]).unwrap();
+
+        vault.json("slot remove api 3", &["pw"]);
+        assert!(vault.run(&format!("show api --keyfile {}", keyfile), &[]).is_err());
+
+        // Ids of removed slots aren't handed out again
+        let other_keyfile = vault.path("other.key");
+        let added = vault.json(&format!("slot add api --type keyfile --new-keyfile {}", other_keyfile), &["pw"]);
+        assert_eq!(added["slot"], 4);
+
+        let recovered = vault.json("recover api", &[&code, "new", "new"]);
+        assert_eq!(recovered["recovery_codes_left"], 0);
+        let ids: Vec<u64> = vault.json("slot list api", &[])["slots"].as_array().unwrap().iter()
+            .map(|slot| slot["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, [4, 5]);
+        vault.run("show api", &["new"]).unwrap();
+        assert_eq!(exit_code(&vault.run("show api", &["pw"]).unwrap_err()), EXIT_WRONG_PASSWORD);
+    }
+
+    #[test]
+    fn test_recover_keeps_keyfile_requirement() {
+        let vault = Vault::new();
+        let keyfile = vault.path("api.key");
+        let created = vault.json(&format!("create api --recovery-codes 1 --keyfile {}", keyfile), &["pw", "pw"]);
+        let code = created["recovery_codes"][0].as_str().unwrap().to_string();
+
+        let err = vault.run("recover api", &[]).unwrap_err();
+        assert!(err.to_string().contains("--keyfile"), "{}", err);
+
+        vault.json(&format!("recover api --keyfile {}", keyfile), &[&code, "new", "new"]);
+        let slots = vault.json("slot list api", &[])["slots"].clone();
+        let kinds: Vec<&str> = slots.as_array().unwrap().iter().map(|slot| slot["kind"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["password_and_keyfile"]);
+        vault.run(&format!("show api --keyfile {}", keyfile), &["new"]).unwrap();
+        assert!(vault.run(&format!("show api --keyfile {}", keyfile), &["pw"]).is_err());
+        assert!(vault.run("show api", &[]).is_err());
+    }
+
+    #[test]
+    fn test_keygen_and_recipients() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+
+        let identity = vault.path("identity.txt");
+        let recipient = vault.json(&format!("keygen --out-file {}", identity), &[])["recipient"]
+            .as_str().unwrap().to_string();
+        let added = vault.json(&format!("recipients add api {} --label alice", recipient), &["pw"]);
+        assert_eq!(added["slot"], 2);
+        assert_eq!(vault.json("recipients list api", &[])["recipients"][0]["label"], "alice");
+        vault.run(&format!("show api --identity {}", identity), &[]).unwrap();
+
+        vault.json(&format!("recipients remove api {}", recipient), &["pw"]);
+        assert_eq!(vault.json("recipients list api", &[])["recipients"], json!([]));
+    }
+
+    #[test]
+    fn test_remove_recipient_with_rekey() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 1", &["pw", "pw"]).unwrap();
+        let (alice, bob) = (vault.path("alice.txt"), vault.path("bob.txt"));
+        let alice_key = vault.json(&format!("keygen --out-file {}", alice), &[])["recipient"].as_str().unwrap().to_string();
+        let bob_key = vault.json(&format!("keygen --out-file {}", bob), &[])["recipient"].as_str().unwrap().to_string();
+        vault.run(&format!("recipients add api {}", alice_key), &["pw"]).unwrap();
+        vault.run(&format!("recipients add api {}", bob_key), &["pw"]).unwrap();
+
+        // Whatever Alice could unwrap before she was removed
+        let read = || -> EncryptedProject {
+            serde_json::from_slice(&fs::read(vault.vault_dir().join("api.encrypted")).unwrap()).unwrap()
+        };
+        let copy = read();
+        let identity = Identity::read_file(Path::new(&alice)).unwrap();
+        let (old_key, _) = crypto::unlock_key(&copy, &Credentials::identity(identity)).unwrap();
+
+        let removed = vault.json(&format!("recipients remove api {} --rekey", alice_key), &["pw", "new", "new"]);
+        assert_eq!(removed["rekeyed"], true);
+        let stored = read();
+        assert!(crypto::decrypt_project_with_key(&stored, &old_key).is_err());
+        let kinds: Vec<CredentialKind> = stored.slots.iter().map(|slot| slot.kind).collect();
+        assert_eq!(kinds, [CredentialKind::Recipient, CredentialKind::Password]);
+
+        assert!(vault.run(&format!("show api --identity {}", alice), &[]).is_err());
+        vault.run(&format!("show api --identity {}", bob), &[]).unwrap();
+        vault.run("show api", &["new"]).unwrap();
+    }
+
+    #[test]
+    fn test_split_and_combine() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+
+        let split = vault.json("split api --shares 3 --threshold 2", &["pw"]);
+        let shares: Vec<&str> = split["shares"].as_array().unwrap().iter().map(|s| s.as_str().unwrap()).collect();
+        let shares_file = vault.path("shares.txt");
+        fs::write(&shares_file, format!("{}\n\n{}\n", shares[0], shares[2])).unwrap();
+        let combined = vault.json(&format!("combine api --shares-file {}", shares_file), &["new", "new"]);
+        assert_eq!(combined["rekeyed"], false);
+        vault.run("show api", &["new"]).unwrap();
+
+        // Shares are prompted for when not given, and re-keying invalidates them
+        let combined = vault.json("combine api --rekey", &[shares[1], shares[2], "newer", "newer"]);
+        assert_eq!(combined["rekeyed"], true);
+        vault.run("show api", &["newer"]).unwrap();
+        fs::write(&shares_file, format!("{}\n{}\n", shares[0], shares[1])).unwrap();
+        assert!(vault.run(&format!("combine api --shares-file {}", shares_file), &[]).is_err());
+    }
+
+    #[test]
+    fn test_combine_keeps_keyfile_requirement() {
+        let vault = Vault::new();
+        let keyfile = vault.path("api.key");
+        vault.run(&format!("create api --recovery-codes 0 --keyfile {}", keyfile), &["pw", "pw"]).unwrap();
+        let split = vault.json(&format!("split api --shares 2 --threshold 2 --keyfile {}", keyfile), &["pw"]);
+        let shares: Vec<&str> = split["shares"].as_array().unwrap().iter().map(|s| s.as_str().unwrap()).collect();
+        let shares_file = vault.path("shares.txt");
+        fs::write(&shares_file, shares.join("\n")).unwrap();
+
+        let err = vault.run(&format!("combine api --shares-file {}", shares_file), &[]).unwrap_err();
+        assert!(err.to_string().contains("--keyfile"), "{}", err);
+
+        vault.json(&format!("combine api --shares-file {} --keyfile {}", shares_file, keyfile), &["new", "new"]);
+        let slots = vault.json("slot list api", &[])["slots"].clone();
+        let kinds: Vec<&str> = slots.as_array().unwrap().iter().map(|slot| slot["kind"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["password_and_keyfile"]);
+        assert!(vault.run("show api", &[]).is_err());
+        assert!(vault.run(&format!("show api --keyfile {}", keyfile), &["pw"]).is_err());
+        vault.run(&format!("show api --keyfile {}", keyfile), &["new"]).unwrap();
+
+        let combined = vault.run(&format!("combine api --shares-file {} --keyfile {} --rekey", shares_file, keyfile), &["newer", "newer"]).unwrap();
+        assert!(combined.stdout().contains("previous shares no longer work"));
+        assert!(vault.run("show api", &[]).is_err());
+        vault.run(&format!("show api --keyfile {}", keyfile), &["newer"]).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        vault.run("add api A 1", &["pw"]).unwrap();
+        let base = vault.path("base.encrypted");
+        fs::copy(vault.vault_dir().join("api.encrypted"), &base).unwrap();
+
+        // Another copy of the vault diverges from the same starting point
+        let other = Vault::new();
+        fs::copy(&base, other.vault_dir().join("api.encrypted")).unwrap();
+        other.run("add api A 3", &["pw"]).unwrap();
+        other.run("add api C 1", &["pw"]).unwrap();
+        vault.run("add api A 2", &["pw"]).unwrap();
+        vault.run("add api B 1", &["pw"]).unwrap();
+
+        let theirs = other.vault_dir().join("api.encrypted");
+        let console = vault.run(&format!("merge api {} --base {}", theirs.display(), base), &["pw", "s", "t"]).unwrap();
+        assert!(console.stdout().contains(&format!("theirs: {}", merge::fingerprint(Some("3")))));
+        assert!(console.stderr().contains("ours:   2\n   theirs: 3\n"));
+        let exported = vault.json("export api --format json", &["pw"]);
+        let secrets: Value = serde_json::from_str(exported["content"].as_str().unwrap()).unwrap();
+        assert_eq!(secrets, json!({ "A": "3", "B": "1", "C": "1" }));
+
+        assert_eq!(vault.json("merge api", &[])["merged_versions"], 0);
+    }
+
+    #[test]
+    fn test_migrate_storage_and_sync() {
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+
+        let migrated = vault.json("migrate-storage --to revisions", &[]);
+        assert_eq!((&migrated["from"], &migrated["moved"]), (&json!("files"), &json!(1)));
+        vault.run("show api", &["pw"]).unwrap();
+        vault.json("migrate-storage --to files", &[]);
+
+        let remote = vault.path("remote.git");
+        let status = std::process::Command::new("git").args(["init", "--bare", "--quiet", &remote]).status().unwrap();
+        assert!(status.success());
+        assert_eq!(vault.json(&format!("sync --remote {}", remote), &[])["action"], "pushed");
+        assert_eq!(vault.json("sync", &[])["action"], "up_to_date");
+        assert_eq!(vault.json("list", &[])["projects"], json!(["api"]));
+    }
+
+    #[test]
+    fn test_serve() {
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+
+        let vault = Vault::new();
+        vault.run("create api --recovery-codes 0", &["pw", "pw"]).unwrap();
+        assert!(vault.run("serve --listen 0.0.0.0:7878", &[]).is_err());
+
+        let socket = vault.path("api.sock");
+        let console = vault.spawn(&format!("--output json serve --socket {}", socket));
+        // The address is only announced once the server listens
+        wait_for(|| !console.stdout().is_empty());
+        let started: Value = serde_json::from_str(&console.stdout()).unwrap();
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        write!(
+            stream,
+            "GET /v1/projects HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nConnection: close\r\n\r\n",
+            started["token"].as_str().unwrap()
+        ).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("\"api\""));
+    }
+
+    #[test]
+    fn test_output_format_is_found_in_unparsed_args() {
+        let args = |line: &str| line.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(OutputFormat::from_args(&args("sm --output json bogus")), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_args(&args("sm --output=json bogus")), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_args(&args("sm export p --output json --bogus")), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_args(&args("sm export p --output json.txt")), OutputFormat::Text);
+        assert_eq!(OutputFormat::from_args(&args("sm add p KEY --output json --bogus")), OutputFormat::Json);
+        assert_eq!(OutputFormat::from_args(&args("sm add p -- --output json --bogus")), OutputFormat::Text);
+    }
+
+    #[test]
+    fn test_exit_code_follows_library_errors() {
+        let not_found = anyhow::Error::from(Error::NotFound("api".to_string())).context("Could not export");
+        assert_eq!(exit_code(&not_found), EXIT_NOT_FOUND);
+        assert_eq!(exit_code(&Error::WrongPassword.into()), EXIT_WRONG_PASSWORD);
+        assert_eq!(exit_code(&io::Error::from(io::ErrorKind::PermissionDenied).into()), EXIT_IO);
+        assert_eq!(exit_code(&anyhow::anyhow!("Something else")), EXIT_FAILURE);
+    }
+}
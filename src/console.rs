@@ -0,0 +1,160 @@
+use anyhow::{Result, anyhow};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::secret::SecretString;
+
+/// Asks the person running a command for input
+pub trait Prompter {
+    /// Shows a prompt and reads one line of input, without its line ending
+    fn read_line(&self, prompt: &str) -> Result<String>;
+
+    /// Shows a prompt and reads a line without echoing it, for passwords and secret values
+    fn read_secret(&self, prompt: &str) -> Result<SecretString>;
+}
+
+/// Receives everything a command prints
+///
+/// Results such as JSON documents and exported files go to `print`; messages
+/// meant for people go to either stream, depending on the output format.
+pub trait Output {
+    /// Writes to standard output
+    fn print(&self, text: &str);
+
+    /// Writes to standard error
+    fn eprint(&self, text: &str);
+}
+
+/// The real terminal: prompts on the TTY or stderr, input from the TTY or stdin
+///
+/// Prompts never go to stdout, so redirecting a command's output doesn't capture them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Terminal;
+
+impl Prompter for Terminal {
+    fn read_line(&self, prompt: &str) -> Result<String> {
+        eprint!("{}", prompt);
+        io::stderr().flush()?;
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    fn read_secret(&self, prompt: &str) -> Result<SecretString> {
+        Ok(SecretString::new(rpassword::prompt_password(prompt)?))
+    }
+}
+
+impl Output for Terminal {
+    fn print(&self, text: &str) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn eprint(&self, text: &str) {
+        let mut stderr = io::stderr().lock();
+        let _ = stderr.write_all(text.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+/// Answers prompts from a fixed script and records all output, for tests
+///
+/// Clones share the same script and recordings, so one clone can be handed
+/// to a `SecretManager` while another inspects what it printed.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedConsole {
+    state: Arc<Mutex<Script>>,
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    answers: VecDeque<String>,
+    prompts: Vec<String>,
+    stdout: String,
+    stderr: String,
+}
+
+impl ScriptedConsole {
+    /// A console that answers prompts, secret or not, with `answers` in order
+    pub fn new<I, S>(answers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let script = Script { answers: answers.into_iter().map(Into::into).collect(), ..Script::default() };
+        Self { state: Arc::new(Mutex::new(script)) }
+    }
+
+    /// Everything written to standard output so far
+    pub fn stdout(&self) -> String {
+        self.state().stdout.clone()
+    }
+
+    /// Everything written to standard error so far
+    pub fn stderr(&self) -> String {
+        self.state().stderr.clone()
+    }
+
+    /// The prompts shown so far
+    pub fn prompts(&self) -> Vec<String> {
+        self.state().prompts.clone()
+    }
+
+    /// Answers not used yet
+    pub fn remaining_answers(&self) -> usize {
+        self.state().answers.len()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, Script> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next_answer(&self, prompt: &str) -> Result<String> {
+        let mut state = self.state();
+        state.prompts.push(prompt.to_string());
+        state.answers.pop_front()
+            .ok_or_else(|| anyhow!("No scripted answer for prompt '{}'", prompt.trim()))
+    }
+}
+
+impl Prompter for ScriptedConsole {
+    fn read_line(&self, prompt: &str) -> Result<String> {
+        self.next_answer(prompt)
+    }
+
+    fn read_secret(&self, prompt: &str) -> Result<SecretString> {
+        self.next_answer(prompt).map(SecretString::new)
+    }
+}
+
+impl Output for ScriptedConsole {
+    fn print(&self, text: &str) {
+        self.state().stdout.push_str(text);
+    }
+
+    fn eprint(&self, text: &str) {
+        self.state().stderr.push_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_console_answers_in_order() {
+        let console = ScriptedConsole::new(["pw", "y"]);
+
+        assert_eq!(console.read_secret("Password: ").unwrap().expose_secret(), "pw");
+        assert_eq!(console.read_line("Sure? ").unwrap(), "y");
+        assert!(console.read_line("Again? ").is_err());
+        assert_eq!(console.prompts(), ["Password: ", "Sure? ", "Again? "]);
+
+        console.clone().print("out\n");
+        console.eprint("err\n");
+        assert_eq!((console.stdout(), console.stderr()), ("out\n".to_string(), "err\n".to_string()));
+    }
+}
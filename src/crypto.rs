@@ -1,66 +1,480 @@
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use aes_gcm::aead::{Aead, AeadCore, KeyInit};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, Payload};
 use base64::{Engine as _, engine::general_purpose};
 use rand::{RngCore, rngs::OsRng};
-use anyhow::{Result, anyhow};
+use anyhow::anyhow;
+use zeroize::Zeroizing;
 
-use crate::models::{Project, EncryptedProject};
+use crate::error::{Error, Result};
+use crate::models::{Project, EncryptedProject, CredentialKind, KeySlot, FORMAT_VERSION};
+use crate::identity::{Identity, Recipient};
+use crate::secret::SecretString;
+use crate::shamir::{self, Share};
 
-/// Derives a key from a password using PBKDF2
-fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32]> {
+/// Derives a key from credential material using PBKDF2
+///
+/// The key is wiped from memory when the returned buffer is dropped.
+fn derive_key(material: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
     use pbkdf2::pbkdf2_hmac;
     use sha2::Sha256;
     
-    let mut key = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, 100_000, &mut key);
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2_hmac::<Sha256>(material, salt, 100_000, key.as_mut());
     Ok(key)
 }
 
-/// Encrypts a project with the given password
-pub fn encrypt_project(project: &Project, password: &str) -> Result<EncryptedProject> {
-    // Generate random salt and nonce
+/// Reads a keyfile from disk
+pub fn read_keyfile(path: &std::path::Path) -> Result<Zeroizing<Vec<u8>>> {
+    let bytes = Zeroizing::new(std::fs::read(path).map_err(|e| std::io::Error::new(
+        e.kind(),
+        format!("Could not read keyfile '{}': {}", path.display(), e),
+    ))?);
+    if bytes.is_empty() {
+        return Err(Error::InvalidCredentials(format!("Keyfile '{}' is empty", path.display())));
+    }
+    Ok(bytes)
+}
+
+/// Writes a new keyfile filled with random bytes, readable only by the owner
+pub fn create_keyfile(path: &std::path::Path) -> Result<()> {
+    use std::io::Write;
+    
+    let mut bytes = Zeroizing::new(vec![0u8; 64]);
+    OsRng.fill_bytes(&mut bytes);
+    
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(&bytes)?;
+    Ok(())
+}
+
+/// The secrets a user presents to unlock a project: a password, a keyfile,
+/// both, a recovery code, or an X25519 identity
+pub struct Credentials {
+    password: Option<SecretString>,
+    keyfile: Option<Zeroizing<Vec<u8>>>,
+    recovery_code: Option<SecretString>,
+    identity: Option<Identity>,
+}
+
+impl Credentials {
+    pub fn new(password: Option<SecretString>, keyfile: Option<Zeroizing<Vec<u8>>>) -> Result<Self> {
+        if password.is_none() && keyfile.is_none() {
+            return Err(Error::InvalidCredentials("A password or keyfile is required".to_string()));
+        }
+        Ok(Self { password, keyfile, recovery_code: None, identity: None })
+    }
+    
+    pub fn password(password: &str) -> Self {
+        Self { password: Some(SecretString::from(password)), keyfile: None, recovery_code: None, identity: None }
+    }
+    
+    pub fn recovery_code(code: SecretString) -> Self {
+        Self { password: None, keyfile: None, recovery_code: Some(code), identity: None }
+    }
+    
+    pub fn identity(identity: Identity) -> Self {
+        Self { password: None, keyfile: None, recovery_code: None, identity: Some(identity) }
+    }
+    
+    pub fn kind(&self) -> CredentialKind {
+        if self.identity.is_some() {
+            return CredentialKind::Recipient;
+        }
+        match (&self.password, &self.keyfile, &self.recovery_code) {
+            (_, _, Some(_)) => CredentialKind::RecoveryCode,
+            (Some(_), Some(_), _) => CredentialKind::PasswordAndKeyfile,
+            (None, Some(_), _) => CredentialKind::Keyfile,
+            _ => CredentialKind::Password,
+        }
+    }
+    
+    /// Input to the KDF
+    ///
+    /// Password-only credentials use the password directly, as every format
+    /// version always has. Anything involving a keyfile is combined as
+    /// `SHA-256(domain || SHA-256(password) || SHA-256(keyfile))`, and
+    /// recovery codes are normalised so dashes and case don't matter.
+    fn key_material(&self) -> Zeroizing<Vec<u8>> {
+        use sha2::{Digest, Sha256};
+        
+        if let Some(code) = &self.recovery_code {
+            let normalized: Zeroizing<String> = Zeroizing::new(code.expose_secret()
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .map(|c| c.to_ascii_uppercase())
+                .collect());
+            let mut hasher = Sha256::new();
+            hasher.update(b"secrets-manager:recovery-code:v1");
+            hasher.update(normalized.as_bytes());
+            return Zeroizing::new(hasher.finalize().to_vec());
+        }
+        
+        match (&self.password, &self.keyfile) {
+            (Some(password), None) => Zeroizing::new(password.expose_secret().as_bytes().to_vec()),
+            (password, keyfile) => {
+                let mut hasher = Sha256::new();
+                hasher.update(b"secrets-manager:composite-key:v1");
+                if let Some(password) = password {
+                    hasher.update(Sha256::digest(password.expose_secret().as_bytes()));
+                }
+                if let Some(keyfile) = keyfile {
+                    hasher.update(Sha256::digest(keyfile.as_slice()));
+                }
+                Zeroizing::new(hasher.finalize().to_vec())
+            }
+        }
+    }
+}
+
+/// Builds the associated data that binds a ciphertext to its project name and format version
+fn associated_data(version: u32, project_name: &str) -> Vec<u8> {
+    format!("secrets-manager:v{}:{}", version, project_name).into_bytes()
+}
+
+/// Version of the key slot format, bound into every slot's associated data
+///
+/// Slots appeared in format version 3. This stays fixed when `FORMAT_VERSION`
+/// moves on, so slots written before keep opening.
+const SLOT_FORMAT_VERSION: u32 = 3;
+
+/// Builds the associated data that binds a wrapped data key to its project and slot
+fn slot_associated_data(project_name: &str, slot_id: u32) -> Vec<u8> {
+    format!("secrets-manager:slot:v{}:{}:{}", SLOT_FORMAT_VERSION, project_name, slot_id).into_bytes()
+}
+
+/// A project's data encryption key
+///
+/// The payload of every project is encrypted with a random data key. The key
+/// itself is only ever stored wrapped inside key slots, and is what the agent
+/// caches once a project has been unlocked.
+#[derive(Clone)]
+pub struct ProjectKey {
+    key: Zeroizing<[u8; 32]>,
+}
+
+impl ProjectKey {
+    /// Generates a fresh random data key
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        Self { key }
+    }
+    
+    /// Rebuilds a key from raw bytes, e.g. as handed back by the agent
+    pub fn from_bytes(key: &[u8]) -> Result<Self> {
+        let key: [u8; 32] = key.try_into()
+            .map_err(|_| Error::Corrupt("Invalid key length".to_string()))?;
+        Ok(Self { key: Zeroizing::new(key) })
+    }
+    
+    pub fn key_bytes(&self) -> &[u8] {
+        self.key.as_ref()
+    }
+}
+
+impl std::fmt::Debug for ProjectKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProjectKey([REDACTED])")
+    }
+}
+
+/// Human readable description of a credential kind
+pub fn describe_credentials(kind: CredentialKind) -> &'static str {
+    match kind {
+        CredentialKind::Password => "a password",
+        CredentialKind::Keyfile => "a keyfile",
+        CredentialKind::PasswordAndKeyfile => "a password and a keyfile",
+        CredentialKind::RecoveryCode => "a recovery code",
+        CredentialKind::Recipient => "an identity file",
+    }
+}
+
+/// Wraps a data key into a new key slot protected by the given credentials
+pub fn create_slot(
+    project_name: &str,
+    slot_id: u32,
+    project_key: &ProjectKey,
+    credentials: &Credentials,
+    label: Option<String>,
+) -> Result<KeySlot> {
+    if credentials.identity.is_some() {
+        return Err(anyhow!("Recipient slots are created from a public key").into());
+    }
+    
     let mut salt = [0u8; 16];
     OsRng.fill_bytes(&mut salt);
     let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
     
-    // Derive key from password and salt
-    let key_bytes = derive_key(password, &salt)?;
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
+    let slot_key = derive_key(&credentials.key_material(), &salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(slot_key.as_ref()));
+    let aad = slot_associated_data(project_name, slot_id);
+    let wrapped = cipher.encrypt(&nonce, Payload { msg: project_key.key_bytes(), aad: &aad })
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    
+    Ok(KeySlot {
+        id: slot_id,
+        kind: credentials.kind(),
+        label,
+        salt: general_purpose::STANDARD.encode(salt),
+        nonce: general_purpose::STANDARD.encode(nonce),
+        wrapped_key: general_purpose::STANDARD.encode(wrapped),
+        recipient: None,
+        ephemeral_key: None,
+        created_at: chrono::Utc::now(),
+    })
+}
+
+/// Derives the key wrapping a data key for a recipient
+///
+/// The wrapping key is `HKDF-SHA256(ikm = X25519(ephemeral, recipient),
+/// salt = ephemeral_public || recipient, info = "secrets-manager:recipient:v1")`
+/// and wraps the data key with AES-256-GCM. Only the key encoding is shared
+/// with `age`; the slot itself isn't an age stanza.
+fn recipient_wrap_key(shared_secret: &[u8], ephemeral: &[u8], recipient: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+    
+    let salt = [ephemeral, recipient].concat();
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(b"secrets-manager:recipient:v1", key.as_mut())
+        .map_err(|_| anyhow!("Key derivation failed"))?;
+    Ok(key)
+}
+
+/// Wraps a data key into a new key slot for an X25519 public key
+pub fn create_recipient_slot(
+    project_name: &str,
+    slot_id: u32,
+    project_key: &ProjectKey,
+    recipient: &Recipient,
+    label: Option<String>,
+) -> Result<KeySlot> {
+    let ephemeral = Identity::generate();
+    let ephemeral_public = ephemeral.recipient();
+    let shared = ephemeral.secret().diffie_hellman(recipient.public_key());
+    if !shared.was_contributory() {
+        return Err(anyhow!("Invalid recipient public key").into());
+    }
+    
+    let wrap_key = recipient_wrap_key(
+        shared.as_bytes(),
+        ephemeral_public.public_key().as_bytes(),
+        recipient.public_key().as_bytes(),
+    )?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(wrap_key.as_ref()));
+    let aad = slot_associated_data(project_name, slot_id);
+    let wrapped = cipher.encrypt(&nonce, Payload { msg: project_key.key_bytes(), aad: &aad })
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    
+    Ok(KeySlot {
+        id: slot_id,
+        kind: CredentialKind::Recipient,
+        label,
+        salt: String::new(),
+        nonce: general_purpose::STANDARD.encode(nonce),
+        wrapped_key: general_purpose::STANDARD.encode(wrapped),
+        recipient: Some(recipient.to_string()),
+        ephemeral_key: Some(general_purpose::STANDARD.encode(ephemeral_public.public_key().as_bytes())),
+        created_at: chrono::Utc::now(),
+    })
+}
+
+/// Unwraps the data key from a recipient slot with the matching identity
+fn open_recipient_slot(project_name: &str, slot: &KeySlot, identity: &Identity) -> Result<ProjectKey> {
+    let recipient = identity.recipient();
+    if slot.recipient.as_deref() != Some(recipient.to_string().as_str()) {
+        return Err(Error::InvalidCredentials(format!("Slot {} is not wrapped for this identity", slot.id)));
+    }
+    
+    let ephemeral: [u8; 32] = general_purpose::STANDARD
+        .decode(slot.ephemeral_key.as_deref().unwrap_or_default())?
+        .try_into()
+        .map_err(|_| Error::Corrupt(format!("Slot {} has an invalid ephemeral key", slot.id)))?;
+    let shared = identity.secret().diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral));
+    
+    let wrap_key = recipient_wrap_key(shared.as_bytes(), &ephemeral, recipient.public_key().as_bytes())?;
+    let nonce_bytes = general_purpose::STANDARD.decode(&slot.nonce)?;
+    let wrapped = general_purpose::STANDARD.decode(&slot.wrapped_key)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(wrap_key.as_ref()));
+    let aad = slot_associated_data(project_name, slot.id);
+    let key = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &wrapped, aad: &aad })
+        .map_err(|_| Error::WrongPassword)?);
+    
+    ProjectKey::from_bytes(&key)
+}
+
+/// Unwraps the data key from a single slot
+pub fn open_slot(project_name: &str, slot: &KeySlot, credentials: &Credentials) -> Result<ProjectKey> {
+    if let Some(identity) = &credentials.identity {
+        return open_recipient_slot(project_name, slot, identity);
+    }
+    
+    let salt = general_purpose::STANDARD.decode(&slot.salt)?;
+    let nonce_bytes = general_purpose::STANDARD.decode(&slot.nonce)?;
+    let wrapped = general_purpose::STANDARD.decode(&slot.wrapped_key)?;
+    
+    let slot_key = derive_key(&credentials.key_material(), &salt)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(slot_key.as_ref()));
+    let aad = slot_associated_data(project_name, slot.id);
+    let key = Zeroizing::new(cipher.decrypt(Nonce::from_slice(&nonce_bytes), Payload { msg: &wrapped, aad: &aad })
+        .map_err(|_| Error::WrongPassword)?);
+    
+    ProjectKey::from_bytes(&key)
+}
+
+/// Recovers the data key by trying every slot that accepts the given kind of credentials
+///
+/// Returns the key together with the id of the slot that opened.
+pub fn unlock_key(encrypted: &EncryptedProject, credentials: &Credentials) -> Result<(ProjectKey, u32)> {
+    let kind = credentials.kind();
+    let mut candidates = encrypted.slots.iter().filter(|slot| slot.kind == kind).peekable();
+    
+    if candidates.peek().is_none() {
+        return Err(Error::InvalidCredentials(format!(
+            "Project '{}' has no key slot that accepts {}",
+            encrypted.project_name, describe_credentials(kind)
+        )));
+    }
+    
+    for slot in candidates {
+        if let Ok(key) = open_slot(&encrypted.project_name, slot, credentials) {
+            return Ok((key, slot.id));
+        }
+    }
+    Err(Error::WrongPassword)
+}
+
+/// Splits a data key into Shamir shares, any `threshold` of which reconstruct it
+pub fn split_project_key(project_key: &ProjectKey, threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    Ok(shamir::split(project_key.key_bytes(), threshold, shares)?)
+}
+
+/// Reconstructs a data key from Shamir shares
+pub fn combine_project_key(shares: &[Share]) -> Result<ProjectKey> {
+    let key = shamir::combine(shares)?;
+    ProjectKey::from_bytes(&key)
+}
+
+/// Encrypts a project protected by a single password slot
+pub fn encrypt_project(project: &Project, password: &str) -> Result<EncryptedProject> {
+    let key = ProjectKey::generate();
+    let slot = create_slot(&project.name, 1, &key, &Credentials::password(password), None)?;
+    encrypt_project_with_key(project, &key, vec![slot])
+}
+
+/// Encrypts a project's payload with its data key, storing the given slots alongside
+pub fn encrypt_project_with_key(project: &Project, project_key: &ProjectKey, slots: Vec<KeySlot>) -> Result<EncryptedProject> {
+    if slots.is_empty() {
+        return Err(anyhow!("A project needs at least one key slot").into());
+    }
+    
+    // Generate a fresh nonce for every encryption
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let key = Key::<Aes256Gcm>::from_slice(project_key.key_bytes());
     let cipher = Aes256Gcm::new(key);
     
     // Serialize project to JSON
-    let json_data = serde_json::to_string(project)?;
+    let json_data = Zeroizing::new(serde_json::to_string(project).map_err(anyhow::Error::from)?);
     
-    // Encrypt the data
-    let ciphertext = cipher.encrypt(&nonce, json_data.as_bytes())
+    // Encrypt the data, authenticating the project name and format version
+    let aad = associated_data(FORMAT_VERSION, &project.name);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: json_data.as_bytes(), aad: &aad })
         .map_err(|_| anyhow!("Encryption failed"))?;
     
+    let next_slot_id = slots.iter().map(|slot| slot.id + 1).max().unwrap_or(1);
     Ok(EncryptedProject {
+        version: FORMAT_VERSION,
+        project_name: project.name.clone(),
+        credentials: None,
+        slots,
+        next_slot_id,
         encrypted_data: general_purpose::STANDARD.encode(&ciphertext),
-        salt: general_purpose::STANDARD.encode(&salt),
-        nonce: general_purpose::STANDARD.encode(&nonce),
+        salt: String::new(),
+        nonce: general_purpose::STANDARD.encode(nonce),
     })
 }
 
 /// Decrypts a project with the given password
 pub fn decrypt_project(encrypted: &EncryptedProject, password: &str) -> Result<Project> {
-    // Decode base64 data
+    let credentials = Credentials::password(password);
+    if is_legacy(encrypted) {
+        return decrypt_legacy_project(encrypted, &credentials);
+    }
+    let (key, _) = unlock_key(encrypted, &credentials)?;
+    decrypt_project_with_key(encrypted, &key)
+}
+
+/// Returns true for files written before envelope encryption (format versions 1 and 2)
+pub fn is_legacy(encrypted: &EncryptedProject) -> bool {
+    encrypted.version < FORMAT_VERSION
+}
+
+/// Decrypts a version 1 or 2 file, whose payload key was derived directly from the credentials
+pub fn decrypt_legacy_project(encrypted: &EncryptedProject, credentials: &Credentials) -> Result<Project> {
+    let expected = encrypted.credentials.unwrap_or_default();
+    if credentials.kind() != expected {
+        return Err(Error::InvalidCredentials(format!(
+            "Project '{}' must be unlocked with {}",
+            encrypted.project_name, describe_credentials(expected)
+        )));
+    }
+    
     let salt = general_purpose::STANDARD.decode(&encrypted.salt)?;
+    let key = derive_key(&credentials.key_material(), &salt)?;
+    let aad = match encrypted.version {
+        1 => Vec::new(),
+        2 => associated_data(encrypted.version, &encrypted.project_name),
+        v => return Err(Error::UnsupportedVersion(v)),
+    };
+    decrypt_payload(encrypted, key.as_ref(), &aad)
+}
+
+/// Decrypts a project with its data key
+pub fn decrypt_project_with_key(encrypted: &EncryptedProject, project_key: &ProjectKey) -> Result<Project> {
+    if encrypted.version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(encrypted.version));
+    }
+    let aad = associated_data(encrypted.version, &encrypted.project_name);
+    // A data key that came out of a slot only fails on a damaged payload or one re-keyed since
+    decrypt_payload(encrypted, project_key.key_bytes(), &aad).map_err(|e| match e {
+        Error::WrongPassword => Error::StaleKey(format!(
+            "Decryption of project '{}' failed - the data is corrupted or was re-keyed",
+            encrypted.project_name
+        )),
+        e => e,
+    })
+}
+
+fn decrypt_payload(encrypted: &EncryptedProject, key_bytes: &[u8], aad: &[u8]) -> Result<Project> {
+    // Decode base64 data
     let nonce_bytes = general_purpose::STANDARD.decode(&encrypted.nonce)?;
     let ciphertext = general_purpose::STANDARD.decode(&encrypted.encrypted_data)?;
     
-    // Derive key from password and salt
-    let key_bytes = derive_key(password, &salt)?;
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
+    let key = Key::<Aes256Gcm>::from_slice(key_bytes);
     let cipher = Aes256Gcm::new(key);
     let nonce = Nonce::from_slice(&nonce_bytes);
     
     // Decrypt the data
-    let plaintext = cipher.decrypt(nonce, ciphertext.as_slice())
-        .map_err(|_| anyhow!("Decryption failed - wrong password or corrupted data"))?;
+    let plaintext = Zeroizing::new(cipher.decrypt(nonce, Payload { msg: ciphertext.as_slice(), aad })
+        .map_err(|_| Error::WrongPassword)?);
     
     // Deserialize back to Project
     let project: Project = serde_json::from_slice(&plaintext)?;
+    
+    if encrypted.version >= 2 && project.name != encrypted.project_name {
+        return Err(Error::Corrupt(format!(
+            "Project name mismatch: header says '{}' but payload contains '{}'",
+            encrypted.project_name, project.name
+        )));
+    }
     Ok(project)
 }
 
@@ -95,4 +509,116 @@ mod tests {
         
         assert!(decrypt_project(&encrypted, "wrong_password").is_err());
     }
+    
+    #[test]
+    fn test_tampered_project_name_fails() {
+        let project = Project::new("test_project".to_string());
+        let password = "test_password";
+        let mut encrypted = encrypt_project(&project, password).unwrap();
+        encrypted.project_name = "other_project".to_string();
+        
+        assert!(decrypt_project(&encrypted, password).is_err());
+    }
+    
+    #[test]
+    fn test_reencrypt_keeps_slots() {
+        let mut project = Project::new("test_project".to_string());
+        let password = "test_password";
+        let encrypted = encrypt_project(&project, password).unwrap();
+        
+        let (key, slot_id) = unlock_key(&encrypted, &Credentials::password(password)).unwrap();
+        assert_eq!(slot_id, 1);
+        project.add_secret("API_KEY".to_string(), "secret123".to_string());
+        let reencrypted = encrypt_project_with_key(&project, &key, encrypted.slots.clone()).unwrap();
+        
+        assert_ne!(encrypted.nonce, reencrypted.nonce);
+        let decrypted = decrypt_project(&reencrypted, password).unwrap();
+        assert_eq!(decrypted.secrets, project.secrets);
+    }
+    
+    #[test]
+    fn test_multiple_slots() {
+        let project = Project::new("test_project".to_string());
+        let keyfile = || Some(Zeroizing::new(b"keyfile-bytes".to_vec()));
+        let both = Credentials::new(Some(SecretString::from("pw")), keyfile()).unwrap();
+        let keyfile_only = Credentials::new(None, keyfile()).unwrap();
+        let recovery = Credentials::recovery_code(SecretString::from("abcd-efgh"));
+        
+        let key = ProjectKey::generate();
+        let slots = vec![
+            create_slot(&project.name, 1, &key, &both, None).unwrap(),
+            create_slot(&project.name, 2, &key, &recovery, Some("printed".to_string())).unwrap(),
+        ];
+        let encrypted = encrypt_project_with_key(&project, &key, slots).unwrap();
+        
+        assert_eq!(unlock_key(&encrypted, &both).unwrap().1, 1);
+        let normalized = Credentials::recovery_code(SecretString::from("ABCDEFGH"));
+        assert_eq!(unlock_key(&encrypted, &normalized).unwrap().1, 2);
+        
+        // Password alone, or the keyfile alone, must not unlock it
+        assert!(decrypt_project(&encrypted, "pw").is_err());
+        assert!(unlock_key(&encrypted, &keyfile_only).is_err());
+    }
+    
+    #[test]
+    fn test_decrypt_legacy_v1_file() {
+        let mut project = Project::new("legacy".to_string());
+        project.add_secret("API_KEY".to_string(), "secret123".to_string());
+        
+        // Version 1 files: PBKDF2(password) keyed the payload directly, without associated data
+        let salt = [3u8; 16];
+        let key = derive_key(b"pw", &salt).unwrap();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()))
+            .encrypt(&nonce, serde_json::to_string(&project).unwrap().as_bytes())
+            .unwrap();
+        let json = format!(
+            r#"{{"encrypted_data":"{}","salt":"{}","nonce":"{}"}}"#,
+            general_purpose::STANDARD.encode(ciphertext),
+            general_purpose::STANDARD.encode(salt),
+            general_purpose::STANDARD.encode(nonce),
+        );
+        let encrypted: EncryptedProject = serde_json::from_str(&json).unwrap();
+        
+        assert!(is_legacy(&encrypted));
+        let decrypted = decrypt_project(&encrypted, "pw").unwrap();
+        assert_eq!(decrypted.secrets, project.secrets);
+    }
+    
+    #[test]
+    fn test_recipient_slot() {
+        let project = Project::new("test_project".to_string());
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        
+        let key = ProjectKey::generate();
+        let slots = vec![
+            create_slot(&project.name, 1, &key, &Credentials::password("pw"), None).unwrap(),
+            create_recipient_slot(&project.name, 2, &key, &alice.recipient(), None).unwrap(),
+        ];
+        let encrypted = encrypt_project_with_key(&project, &key, slots).unwrap();
+        
+        let (unwrapped, slot_id) = unlock_key(&encrypted, &Credentials::identity(alice)).unwrap();
+        assert_eq!(slot_id, 2);
+        assert_eq!(unwrapped.key_bytes(), key.key_bytes());
+        assert!(unlock_key(&encrypted, &Credentials::identity(bob)).is_err());
+    }
+    
+    #[test]
+    fn test_slot_bound_to_project_name() {
+        let project = Project::new("test_project".to_string());
+        let encrypted = encrypt_project(&project, "pw").unwrap();
+        
+        assert!(open_slot("other_project", &encrypted.slots[0], &Credentials::password("pw")).is_err());
+    }
+    
+    #[test]
+    fn test_unsupported_version() {
+        let project = Project::new("test_project".to_string());
+        let mut encrypted = encrypt_project(&project, "pw").unwrap();
+        encrypted.version = FORMAT_VERSION + 1;
+        
+        let key = ProjectKey::generate();
+        assert!(matches!(decrypt_project_with_key(&encrypted, &key), Err(Error::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1));
+    }
 }
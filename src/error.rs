@@ -0,0 +1,84 @@
+use thiserror::Error;
+
+/// Errors returned by the storage and crypto layers
+///
+/// Backends report errors through `anyhow`; those that are one of these
+/// variants (such as `Locked`) come back out as themselves, and everything
+/// else is kept in `Other`.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The credentials didn't open any key slot
+    ///
+    /// AES-GCM can't tell a wrong password apart from a tampered slot, so
+    /// this also covers a damaged slot.
+    #[error("Decryption failed - wrong password or corrupted data")]
+    WrongPassword,
+
+    /// The credentials are of a kind the project doesn't accept, or are incomplete
+    #[error("{0}")]
+    InvalidCredentials(String),
+
+    /// The name can't be used for a project, e.g. because it contains a path separator
+    #[error("Invalid project name '{0}'")]
+    InvalidName(String),
+
+    /// There is no project with this name
+    #[error("Project '{0}' not found")]
+    NotFound(String),
+
+    /// The stored data can't be decoded, or doesn't match what the key unlocked
+    #[error("{0}")]
+    Corrupt(String),
+
+    /// A data key doesn't decrypt the project's payload
+    ///
+    /// Either the project was re-keyed since the key was unlocked, or the
+    /// payload was damaged; AES-GCM can't tell the two apart.
+    #[error("{0}")]
+    StaleKey(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The file was written in a format this version can't read
+    #[error("Unsupported project format version {0}")]
+    UnsupportedVersion(u32),
+
+    /// Another process holds the project's lock, or changed it concurrently
+    #[error("{0}")]
+    Locked(String),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        // Only unwrap bare I/O errors, so context added on the way up isn't lost
+        if error.chain().count() > 1 {
+            return Error::Other(error);
+        }
+        match error.downcast::<std::io::Error>() {
+            Ok(error) => Error::Io(error),
+            Err(error) => Error::Other(error),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Corrupt(format!("Invalid project data: {}", error))
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(error: base64::DecodeError) -> Self {
+        Error::Corrupt(format!("Invalid project data: {}", error))
+    }
+}
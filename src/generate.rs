@@ -0,0 +1,208 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use rand::{Rng, RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+use crate::secret::SecretString;
+
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Kind of value to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerateKind {
+    /// Random characters drawn from the selected character classes
+    Chars,
+    /// Random bytes, hex encoded
+    Hex,
+    /// Random bytes, standard base64 encoded
+    Base64,
+    /// Random (version 4) UUID
+    Uuid,
+    /// Diceware-style passphrase of random words
+    Diceware,
+    /// Random HMAC key suitable for signing JWTs, base64url encoded
+    Jwt,
+}
+
+impl GenerateKind {
+    pub fn parse(kind: &str) -> Result<Self> {
+        match kind.to_lowercase().as_str() {
+            "chars" => Ok(GenerateKind::Chars),
+            "hex" => Ok(GenerateKind::Hex),
+            "base64" => Ok(GenerateKind::Base64),
+            "uuid" => Ok(GenerateKind::Uuid),
+            "diceware" => Ok(GenerateKind::Diceware),
+            "jwt" => Ok(GenerateKind::Jwt),
+            _ => Err(anyhow!("Invalid kind. Use: chars, hex, base64, uuid, diceware, or jwt")),
+        }
+    }
+
+    /// Default length for the kind: characters, bytes or words depending on the kind
+    fn default_length(self) -> usize {
+        match self {
+            GenerateKind::Chars => 32,
+            GenerateKind::Hex | GenerateKind::Base64 => 32,
+            GenerateKind::Uuid => 16,
+            GenerateKind::Diceware => 6,
+            GenerateKind::Jwt => 64,
+        }
+    }
+}
+
+/// Options controlling secret generation
+#[derive(Debug, Clone)]
+pub struct GenerateOptions {
+    pub kind: GenerateKind,
+    /// Characters for `chars`, bytes for `hex`/`base64`/`jwt`, words for `diceware`
+    pub length: Option<usize>,
+    pub uppercase: bool,
+    pub lowercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+    /// Word separator for diceware passphrases
+    pub separator: String,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            kind: GenerateKind::Chars,
+            length: None,
+            uppercase: true,
+            lowercase: true,
+            digits: true,
+            symbols: false,
+            separator: "-".to_string(),
+        }
+    }
+}
+
+/// Generates a random secret using the OS CSPRNG
+pub fn generate_secret(options: &GenerateOptions) -> Result<SecretString> {
+    let length = options.length.unwrap_or_else(|| options.kind.default_length());
+    if length == 0 {
+        return Err(anyhow!("Length must be greater than zero"));
+    }
+    
+    let value = match options.kind {
+        GenerateKind::Chars => generate_chars(options, length)?,
+        GenerateKind::Hex => {
+            let bytes = random_bytes(length);
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+        GenerateKind::Base64 => general_purpose::STANDARD.encode(random_bytes(length)),
+        GenerateKind::Uuid => generate_uuid(),
+        GenerateKind::Diceware => {
+            let words = bip39::Language::English.word_list();
+            (0..length)
+                .map(|_| words[OsRng.gen_range(0..words.len())])
+                .collect::<Vec<_>>()
+                .join(&options.separator)
+        }
+        GenerateKind::Jwt => {
+            if length < 32 {
+                return Err(anyhow!("JWT HMAC keys should be at least 32 bytes"));
+            }
+            general_purpose::URL_SAFE_NO_PAD.encode(random_bytes(length))
+        }
+    };
+    
+    Ok(SecretString::new(value))
+}
+
+/// Generates a printable recovery code: 24 Crockford base32 characters (120 bits) in groups of four
+pub fn generate_recovery_code() -> SecretString {
+    const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    
+    let groups: Vec<String> = (0..6)
+        .map(|_| (0..4).map(|_| ALPHABET[OsRng.gen_range(0..ALPHABET.len())] as char).collect())
+        .collect();
+    SecretString::new(groups.join("-"))
+}
+
+fn random_bytes(length: usize) -> Zeroizing<Vec<u8>> {
+    let mut bytes = Zeroizing::new(vec![0u8; length]);
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Generates a character string containing at least one character from each selected class
+fn generate_chars(options: &GenerateOptions, length: usize) -> Result<String> {
+    let classes: Vec<&str> = [
+        (options.uppercase, UPPERCASE),
+        (options.lowercase, LOWERCASE),
+        (options.digits, DIGITS),
+        (options.symbols, SYMBOLS),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, class)| *class)
+    .collect();
+    
+    if classes.is_empty() {
+        return Err(anyhow!("At least one character class must be enabled"));
+    }
+    
+    let alphabet: Vec<char> = classes.concat().chars().collect();
+    let require_all = length >= classes.len();
+    
+    // Rejection sampling keeps the distribution uniform over valid strings
+    loop {
+        let value: String = (0..length)
+            .map(|_| alphabet[OsRng.gen_range(0..alphabet.len())])
+            .collect();
+        
+        if !require_all || classes.iter().all(|class| value.chars().any(|c| class.contains(c))) {
+            return Ok(value);
+        }
+    }
+}
+
+fn generate_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_chars_respects_classes() {
+        let options = GenerateOptions {
+            length: Some(40),
+            uppercase: false,
+            symbols: true,
+            ..Default::default()
+        };
+        let value = generate_secret(&options).unwrap();
+        let value = value.expose_secret();
+        
+        assert_eq!(value.chars().count(), 40);
+        assert!(!value.chars().any(|c| c.is_ascii_uppercase()));
+        assert!(value.chars().any(|c| c.is_ascii_lowercase()));
+        assert!(value.chars().any(|c| c.is_ascii_digit()));
+        assert!(value.chars().any(|c| SYMBOLS.contains(c)));
+    }
+
+    #[test]
+    fn test_generate_encodings() {
+        let hex = generate_secret(&GenerateOptions { kind: GenerateKind::Hex, length: Some(16), ..Default::default() }).unwrap();
+        assert_eq!(hex.expose_secret().len(), 32);
+        
+        let uuid = generate_secret(&GenerateOptions { kind: GenerateKind::Uuid, ..Default::default() }).unwrap();
+        assert_eq!(uuid.expose_secret().len(), 36);
+        assert_eq!(&uuid.expose_secret()[14..15], "4");
+        
+        let words = generate_secret(&GenerateOptions { kind: GenerateKind::Diceware, length: Some(5), ..Default::default() }).unwrap();
+        assert_eq!(words.expose_secret().split('-').count(), 5);
+    }
+}
@@ -0,0 +1,312 @@
+use anyhow::{Result, anyhow};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backend::{DirectoryBackend, StorageBackend, StorageLock};
+use crate::models::EncryptedProject;
+
+/// Branch used for new vault repositories
+const DEFAULT_BRANCH: &str = "main";
+/// Remote the vault syncs with
+const REMOTE: &str = "origin";
+/// Keeps lock, temporary and other layouts' files out of the history
+const GITIGNORE: &str = "*.lock\n.*.tmp\nvault.sqlite*\nrevisions/\n";
+
+/// Thin wrapper around the `git` command for a vault directory
+pub struct GitRepo {
+    dir: PathBuf,
+}
+
+impl GitRepo {
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Turns a directory of project files into a repository, committing what is there
+    pub fn init(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir)?;
+        let repo = Self::open(dir);
+        repo.git(&["init", "--quiet"])?;
+        repo.git(&["symbolic-ref", "HEAD", &format!("refs/heads/{}", DEFAULT_BRANCH)])?;
+        fs::write(dir.join(".gitignore"), GITIGNORE)?;
+        repo.git(&["add", "--", ".gitignore"])?;
+        repo.add_projects(&DirectoryBackend::new(dir)?.list()?)?;
+        repo.commit("Initialize vault")?;
+        Ok(repo)
+    }
+
+    /// Runs git in the vault directory and returns its trimmed stdout
+    fn git(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(args)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .output()
+            .map_err(|e| anyhow!("Could not run git: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "git {} failed: {}",
+                args.first().unwrap_or(&""),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Runs a git command that records an author, falling back to a generic
+    /// one if none is configured
+    fn git_as_author(&self, args: &[&str]) -> Result<String> {
+        let mut full_args = Vec::new();
+        if self.git(&["config", "user.email"]).is_err() {
+            full_args.extend(["-c", "user.name=secrets-manager", "-c", "user.email=secrets-manager@localhost"]);
+        }
+        full_args.extend(args);
+        self.git(&full_args)
+    }
+
+    /// Commits staged changes
+    pub fn commit(&self, message: &str) -> Result<()> {
+        self.git_as_author(&["commit", "--quiet", "--allow-empty", "-m", message])?;
+        Ok(())
+    }
+
+    /// Stages the files of some projects, including their deletion
+    ///
+    /// Only project files are ever staged, so anything else that ends up in
+    /// the vault directory stays out of the history.
+    pub fn add_projects<S: AsRef<str>>(&self, names: &[S]) -> Result<()> {
+        if names.is_empty() {
+            return Ok(());
+        }
+        let paths: Vec<String> = names.iter().map(|name| format!("{}.encrypted", name.as_ref())).collect();
+        let mut args = vec!["add", "--all", "--"];
+        args.extend(paths.iter().map(String::as_str));
+        self.git(&args)?;
+        Ok(())
+    }
+
+    pub fn remote_url(&self) -> Option<String> {
+        self.git(&["remote", "get-url", REMOTE]).ok()
+    }
+
+    pub fn set_remote(&self, url: &str) -> Result<()> {
+        if self.remote_url().is_some() {
+            self.git(&["remote", "set-url", REMOTE, url])?;
+        } else {
+            self.git(&["remote", "add", REMOTE, url])?;
+        }
+        Ok(())
+    }
+
+    pub fn fetch(&self) -> Result<()> {
+        self.git(&["fetch", "--quiet", REMOTE])?;
+        Ok(())
+    }
+
+    pub fn push(&self) -> Result<()> {
+        self.git(&["push", "--quiet", REMOTE, &format!("HEAD:refs/heads/{}", DEFAULT_BRANCH)])?;
+        Ok(())
+    }
+
+    /// The commit at the tip of the local branch
+    pub fn head(&self) -> Result<String> {
+        self.git(&["rev-parse", "HEAD"])
+    }
+
+    /// The commit at the tip of the remote branch, if it has been pushed to before
+    pub fn remote_head(&self) -> Option<String> {
+        self.git(&["rev-parse", "--verify", "--quiet", &format!("{}/{}", REMOTE, DEFAULT_BRANCH)]).ok()
+    }
+
+    /// The latest common ancestor of two commits, if they share history
+    pub fn merge_base(&self, a: &str, b: &str) -> Option<String> {
+        self.git(&["merge-base", a, b]).ok()
+    }
+
+    /// Number of commits on the local branch
+    pub fn commit_count(&self) -> Result<usize> {
+        Ok(self.git(&["rev-list", "--count", "HEAD"])?.parse()?)
+    }
+
+    /// Project files that differ between two commits, or all files of both when unrelated
+    pub fn changed_projects(&self, base: Option<&str>, ours: &str, theirs: &str) -> Result<Vec<String>> {
+        let listing = match base {
+            Some(base) => format!(
+                "{}\n{}",
+                self.git(&["diff", "--name-only", base, ours])?,
+                self.git(&["diff", "--name-only", base, theirs])?
+            ),
+            None => format!(
+                "{}\n{}",
+                self.git(&["ls-tree", "--name-only", ours])?,
+                self.git(&["ls-tree", "--name-only", theirs])?
+            ),
+        };
+        let mut names: Vec<String> = listing.lines()
+            .filter_map(|line| line.strip_suffix(".encrypted"))
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// Reads a project file as of a commit
+    pub fn show_project(&self, rev: &str, name: &str) -> Option<Vec<u8>> {
+        self.git(&["show", &format!("{}:{}.encrypted", rev, name)]).ok().map(String::into_bytes)
+    }
+
+    /// Moves the local branch forward to a descendant commit
+    pub fn fast_forward(&self, rev: &str) -> Result<()> {
+        self.git(&["merge", "--quiet", "--ff-only", rev])?;
+        Ok(())
+    }
+
+    /// Replaces the local branch with another commit, discarding local history
+    pub fn reset_to(&self, rev: &str) -> Result<()> {
+        self.git(&["reset", "--quiet", "--hard", rev])?;
+        Ok(())
+    }
+
+    /// Starts a merge commit that keeps our files; the caller writes the merged
+    /// projects and finishes with `commit`
+    pub fn start_merge(&self, rev: &str) -> Result<()> {
+        self.git_as_author(&["merge", "--quiet", "--no-ff", "--no-commit", "--allow-unrelated-histories", "-s", "ours", rev])?;
+        Ok(())
+    }
+
+    pub fn abort_merge(&self) {
+        let _ = self.git(&["merge", "--abort"]);
+    }
+}
+
+/// Whether a vault directory is a git repository
+pub fn is_repo(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+/// Stops tracking history in a vault directory, leaving the project files
+pub fn remove_repo(dir: &Path) -> Result<()> {
+    fs::remove_dir_all(dir.join(".git"))?;
+    let gitignore = dir.join(".gitignore");
+    if gitignore.exists() {
+        fs::remove_file(gitignore)?;
+    }
+    Ok(())
+}
+
+/// Project files in a git repository; every write and delete becomes a commit
+pub struct GitBackend {
+    files: DirectoryBackend,
+    repo: GitRepo,
+}
+
+impl GitBackend {
+    pub fn open(dir: &Path) -> Result<Self> {
+        if !is_repo(dir) {
+            return Err(anyhow!("'{}' is not a git repository", dir.display()));
+        }
+        Ok(Self { files: DirectoryBackend::new(dir)?, repo: GitRepo::open(dir) })
+    }
+
+    pub fn repo(&self) -> &GitRepo {
+        &self.repo
+    }
+}
+
+/// Slot kinds of a blob, read from its unencrypted header
+fn slot_kinds(data: &[u8]) -> Option<Vec<String>> {
+    let encrypted: EncryptedProject = serde_json::from_slice(data).ok()?;
+    let mut kinds: Vec<String> = encrypted.slots.iter().map(|slot| slot.kind.to_string()).collect();
+    kinds.sort();
+    Some(kinds)
+}
+
+/// Commit message for a write, built from the headers only so no secret ends up in the log
+fn describe_write(name: &str, old: Option<&[u8]>, new: &[u8]) -> String {
+    let Some(old) = old else {
+        return format!("Add project '{}'", name);
+    };
+    let (old_kinds, new_kinds) = (slot_kinds(old), slot_kinds(new));
+    if old_kinds != new_kinds {
+        if let Some(kinds) = new_kinds {
+            return format!("Update key slots of project '{}' ({})", name, kinds.join(", "));
+        }
+    }
+    format!("Update secrets of project '{}'", name)
+}
+
+impl StorageBackend for GitBackend {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.files.get(name)
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        let old = self.files.get(name)?;
+        self.files.put(name, data)?;
+        self.repo.add_projects(&[name])?;
+        self.repo.commit(&describe_write(name, old.as_deref(), data))
+    }
+
+    fn put_many(&self, blobs: &[(String, Vec<u8>)]) -> Result<()> {
+        for (name, data) in blobs {
+            self.files.put(name, data)?;
+        }
+        let names: Vec<&str> = blobs.iter().map(|(name, _)| name.as_str()).collect();
+        self.repo.add_projects(&names)?;
+        self.repo.commit(&format!("Import {} project(s)", blobs.len()))
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        self.files.list()
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        if !self.files.delete(name)? {
+            return Ok(false);
+        }
+        self.repo.add_projects(&[name])?;
+        self.repo.commit(&format!("Delete project '{}'", name))?;
+        Ok(true)
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        self.files.lock(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Project;
+    use crate::storage::SecretStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_writes_become_commits() {
+        let temp_dir = TempDir::new().unwrap();
+        let repo = GitRepo::init(temp_dir.path()).unwrap();
+        let storage = SecretStorage::with_backend(GitBackend::open(temp_dir.path()).unwrap());
+
+        let mut project = Project::new("api".to_string());
+        project.add_secret("TOKEN".to_string(), "hunter2".to_string());
+        storage.save_project(&project, "password").unwrap();
+        storage.delete_project("api").unwrap();
+
+        assert_eq!(repo.commit_count().unwrap(), 3);
+        let log = repo.git(&["log", "--format=%s"]).unwrap();
+        assert_eq!(log, "Delete project 'api'\nAdd project 'api'\nInitialize vault");
+        assert!(!repo.git(&["log", "-p"]).unwrap().contains("hunter2"));
+
+        // Stray files next to the projects are never committed
+        fs::write(temp_dir.path().join("notes.txt"), "hunter2").unwrap();
+        storage.save_project(&project, "password").unwrap();
+        assert_eq!(repo.git(&["ls-files"]).unwrap(), ".gitignore\napi.encrypted");
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{Result, anyhow};
+use bech32::{FromBase32, ToBase32, Variant};
+use rand::{RngCore, rngs::OsRng};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Bech32 prefix of public keys, shared with the `age` tool
+const RECIPIENT_HRP: &str = "age";
+/// Bech32 prefix of private keys, shared with the `age` tool
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+/// A teammate's X25519 public key
+///
+/// Keys are encoded exactly like `age` recipients (`age1...`), so keys made
+/// with `age-keygen` can be used directly.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    pub fn parse(encoded: &str) -> Result<Self> {
+        let (hrp, data, variant) = bech32::decode(encoded.trim())
+            .map_err(|e| anyhow!("Invalid recipient '{}': {}", encoded, e))?;
+        if hrp != RECIPIENT_HRP || variant != Variant::Bech32 {
+            return Err(anyhow!("Invalid recipient '{}': expected an age1... public key", encoded));
+        }
+        let bytes: [u8; 32] = Vec::<u8>::from_base32(&data)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid recipient '{}': wrong key length", encoded))?;
+        Ok(Self(PublicKey::from(bytes)))
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.0
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoded = bech32::encode(RECIPIENT_HRP, self.0.as_bytes().to_base32(), Variant::Bech32)
+            .map_err(|_| fmt::Error)?;
+        f.write_str(&encoded)
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({})", self)
+    }
+}
+
+/// An X25519 private key used to unlock projects shared with its recipient
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(bytes.as_mut());
+        Self(StaticSecret::from(*bytes))
+    }
+
+    pub fn parse(encoded: &str) -> Result<Self> {
+        let (hrp, data, variant) = bech32::decode(encoded.trim())
+            .map_err(|_| anyhow!("Invalid identity: expected an AGE-SECRET-KEY-1... private key"))?;
+        if hrp != IDENTITY_HRP || variant != Variant::Bech32 {
+            return Err(anyhow!("Invalid identity: expected an AGE-SECRET-KEY-1... private key"));
+        }
+        let bytes = Zeroizing::new(Vec::<u8>::from_base32(&data)?);
+        let bytes: [u8; 32] = bytes.as_slice()
+            .try_into()
+            .map_err(|_| anyhow!("Invalid identity: wrong key length"))?;
+        Ok(Self(StaticSecret::from(bytes)))
+    }
+
+    /// Reads the first identity from an identity file (`age-keygen` format: `#` comments, one key per line)
+    pub fn read_file(path: &Path) -> Result<Self> {
+        let content = Zeroizing::new(std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Could not read identity file '{}': {}", path.display(), e))?);
+        let line = content.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .ok_or_else(|| anyhow!("No identity found in '{}'", path.display()))?;
+        Self::parse(line)
+    }
+
+    /// Writes the identity to a new file readable only by the owner
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        use std::io::Write;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .map_err(|e| anyhow!("Could not create identity file '{}': {}", path.display(), e))?;
+
+        let content = Zeroizing::new(format!(
+            "# created: {}\n# public key: {}\n{}\n",
+            chrono::Utc::now().to_rfc3339(),
+            self.recipient(),
+            self.encode()?.as_str(),
+        ));
+        file.write_all(content.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    pub fn secret(&self) -> &StaticSecret {
+        &self.0
+    }
+
+    fn encode(&self) -> Result<Zeroizing<String>> {
+        let encoded = bech32::encode(IDENTITY_HRP, self.0.as_bytes().to_base32(), Variant::Bech32)?;
+        Ok(Zeroizing::new(encoded.to_uppercase()))
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({}, [REDACTED])", self.recipient())
+    }
+}
+
+/// Default identity file location, kept outside the vault so it is never synced with it
+pub fn default_identity_path() -> Result<PathBuf> {
+    let config_dir = dirs::config_dir()
+        .ok_or_else(|| anyhow!("Could not find config directory"))?;
+    Ok(config_dir.join("secrets-manager").join("identity.txt"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_identity_file_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("identity.txt");
+
+        let identity = Identity::generate();
+        identity.write_file(&path).unwrap();
+        let loaded = Identity::read_file(&path).unwrap();
+        assert_eq!(loaded.recipient(), identity.recipient());
+
+        let encoded = identity.recipient().to_string();
+        assert!(encoded.starts_with("age1"));
+        assert_eq!(Recipient::parse(&encoded).unwrap(), identity.recipient());
+        assert!(Identity::write_file(&identity, &path).is_err());
+    }
+
+    #[test]
+    fn test_parses_age_keygen_keys() {
+        // Test vector from the age specification
+        let identity = Identity::parse(
+            "AGE-SECRET-KEY-1GFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPYYSJZGFPQ4EGAEX",
+        ).unwrap();
+        assert_eq!(
+            identity.recipient().to_string(),
+            "age1zvkyg2lqzraa2lnjvqej32nkuu0ues2s82hzrye869xeexvn73equnujwj"
+        );
+    }
+}
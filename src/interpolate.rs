@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::models::Project;
+
+/// One piece of a secret value: literal text or a reference to another secret
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    /// `${KEY}`: a secret of the same project
+    Local(&'a str),
+    /// `${project:KEY}`: a secret of another project
+    Project(&'a str, &'a str),
+}
+
+/// Splits a value into text and `${...}` references
+///
+/// `$${` stands for a literal `${`. Anything in braces that isn't a key name,
+/// optionally prefixed with a project name and a colon, is kept as text, so
+/// values that merely contain `${` don't need escaping.
+pub fn parse(value: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            segments.push(Segment::Text(&rest[..start - 1]));
+            segments.push(Segment::Text(&rest[start..start + 2]));
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let reference = rest[start + 2..].find('}').and_then(|end| {
+            let inner = &rest[start + 2..start + 2 + end];
+            let segment = match inner.split_once(':') {
+                Some((project, key)) if is_project_name(project) && is_key(key) => Segment::Project(project, key),
+                None if is_key(inner) => Segment::Local(inner),
+                _ => return None,
+            };
+            Some((segment, start + 3 + end))
+        });
+        match reference {
+            Some((segment, end)) => {
+                segments.push(Segment::Text(&rest[..start]));
+                segments.push(segment);
+                rest = &rest[end..];
+            }
+            None => {
+                segments.push(Segment::Text(&rest[..start + 2]));
+                rest = &rest[start + 2..];
+            }
+        }
+    }
+    segments.push(Segment::Text(rest));
+    segments.retain(|segment| *segment != Segment::Text(""));
+    segments
+}
+
+fn is_key(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn is_project_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// Expands every reference in a project's secrets
+///
+/// `load` is called once for each other project referenced, directly or
+/// through other references, and returns its secrets. References that form
+/// a cycle, or point at secrets that don't exist, are errors.
+pub fn expand<F>(project: &Project, load: F) -> Result<Project>
+where
+    F: FnMut(&str) -> Result<Project>,
+{
+    let mut expander = Expander::new(load);
+    let mut result = project.clone();
+    // In key order, so the same broken reference is reported every time
+    let mut keys: Vec<&String> = project.secrets.keys().collect();
+    keys.sort();
+    for key in keys {
+        let expanded = expander.value(project, &project.name, key)?;
+        if let Some(value) = result.secrets.get_mut(key) {
+            value.zeroize();
+            value.push_str(&expanded);
+        }
+    }
+    Ok(result)
+}
+
+/// Expands the references in one secret, leaving the others alone
+pub fn expand_secret<F>(project: &Project, key: &str, load: F) -> Result<Zeroizing<String>>
+where
+    F: FnMut(&str) -> Result<Project>,
+{
+    Expander::new(load).value(project, &project.name, key)
+}
+
+struct Expander<F> {
+    /// Other projects loaded so far
+    projects: HashMap<String, Project>,
+    /// Values already expanded, by project and key
+    expanded: HashMap<(String, String), Zeroizing<String>>,
+    /// The secrets being expanded, innermost last
+    stack: Vec<(String, String)>,
+    load: F,
+}
+
+impl<F: FnMut(&str) -> Result<Project>> Expander<F> {
+    fn new(load: F) -> Self {
+        Self { projects: HashMap::new(), expanded: HashMap::new(), stack: Vec::new(), load }
+    }
+
+    fn value(&mut self, root: &Project, project_name: &str, key: &str) -> Result<Zeroizing<String>> {
+        let id = (project_name.to_string(), key.to_string());
+        if let Some(value) = self.expanded.get(&id) {
+            return Ok(value.clone());
+        }
+        if let Some(start) = self.stack.iter().position(|entry| *entry == id) {
+            let cycle: Vec<String> = self.stack[start..].iter().chain([&id]).map(|(project, key)| describe(root, project, key)).collect();
+            return Err(anyhow!("Secrets reference each other in a cycle: {}", cycle.join(" -> ")));
+        }
+
+        if project_name != root.name && !self.projects.contains_key(project_name) {
+            let project = (self.load)(project_name)?;
+            self.projects.insert(project_name.to_string(), project);
+        }
+        let project = if project_name == root.name { root } else { &self.projects[project_name] };
+        let raw = Zeroizing::new(project.get_secret(key).cloned().ok_or_else(|| match self.stack.last() {
+            Some((from_project, from_key)) => anyhow!(
+                "Secret '{}' refers to '{}', which doesn't exist",
+                describe(root, from_project, from_key), describe(root, project_name, key)
+            ),
+            None => anyhow!("Secret '{}' not found", describe(root, project_name, key)),
+        })?);
+
+        self.stack.push(id.clone());
+        let mut value = Zeroizing::new(String::with_capacity(raw.len()));
+        for segment in parse(&raw) {
+            match segment {
+                Segment::Text(text) => value.push_str(text),
+                Segment::Local(name) => value.push_str(&self.value(root, project_name, name)?),
+                Segment::Project(other, name) => value.push_str(&self.value(root, other, name)?),
+            }
+        }
+        self.stack.pop();
+
+        self.expanded.insert(id, value.clone());
+        Ok(value)
+    }
+}
+
+/// Names a secret in messages: just the key within the project being expanded
+fn describe(root: &Project, project_name: &str, key: &str) -> String {
+    if project_name == root.name {
+        key.to_string()
+    } else {
+        format!("{}:{}", project_name, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(name: &str, secrets: &[(&str, &str)]) -> Project {
+        let mut project = Project::new(name.to_string());
+        for (key, value) in secrets {
+            project.add_secret(key.to_string(), value.to_string());
+        }
+        project
+    }
+
+    #[test]
+    fn test_parse_references() {
+        assert_eq!(parse("a${B}c${shared-infra:D}"), vec![
+            Segment::Text("a"),
+            Segment::Local("B"),
+            Segment::Text("c"),
+            Segment::Project("shared-infra", "D"),
+        ]);
+        assert_eq!(parse("$${B}"), vec![Segment::Text("${"), Segment::Text("B}")]);
+        assert_eq!(parse("p@${ not a key }"), vec![Segment::Text("p@${"), Segment::Text(" not a key }")]);
+        assert_eq!(parse("${open"), vec![Segment::Text("${"), Segment::Text("open")]);
+    }
+
+    #[test]
+    fn test_expand_local_and_project_references() {
+        let api = project("api", &[
+            ("DB_USER", "app"),
+            ("DB_HOST", "${DB_USER}.db"),
+            ("DATABASE_URL", "postgres://${DB_USER}@${DB_HOST}/app"),
+            ("SENTRY_DSN", "${shared:SENTRY_DSN}"),
+        ]);
+        let mut loaded = Vec::new();
+        let expanded = expand(&api, |name| {
+            loaded.push(name.to_string());
+            Ok(project(name, &[("SENTRY_DSN", "https://sentry")]))
+        }).unwrap();
+        assert_eq!(expanded.get_secret("DATABASE_URL").unwrap(), "postgres://app@app.db/app");
+        assert_eq!(expanded.get_secret("SENTRY_DSN").unwrap(), "https://sentry");
+        assert_eq!(loaded, ["shared"]);
+
+        let cyclic = project("api", &[("A", "${B}"), ("B", "x${A}")]);
+        let err = expand(&cyclic, |_| unreachable!()).unwrap_err().to_string();
+        assert!(err.contains("A -> B -> A") || err.contains("B -> A -> B"), "{}", err);
+        let missing = project("api", &[("A", "${B}")]);
+        assert!(expand(&missing, |_| unreachable!()).unwrap_err().to_string().contains("refers to 'B'"));
+    }
+}
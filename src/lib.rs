@@ -1,6 +1,29 @@
+pub mod agent;
+pub mod backend;
 pub mod crypto;
+pub mod error;
+pub mod generate;
+pub mod git;
+pub mod identity;
+pub mod interpolate;
+pub mod loader;
+pub mod merge;
 pub mod storage;
 pub mod cli;
+pub mod console;
 pub mod models;
+pub mod revisions;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod schema;
+pub mod secret;
+pub mod server;
+pub mod shamir;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod template;
 
+pub use error::Error;
+pub use loader::{load_env, load_into, PasswordSource};
 pub use models::*;
+pub use secret::SecretString;
@@ -0,0 +1,252 @@
+use anyhow::anyhow;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::de::value::MapDeserializer;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::agent::AgentClient;
+use crate::backend::StorageBackend;
+use crate::console::{Prompter, Terminal};
+use crate::crypto::{self, Credentials};
+use crate::error::{Error, Result};
+use crate::identity::Identity;
+use crate::models::Project;
+use crate::secret::SecretString;
+use crate::storage::SecretStorage;
+
+/// Where a service gets the credentials to unlock its project at startup
+///
+/// Whatever the source, a key cached in the agent named by
+/// `SECRETS_MANAGER_AGENT_SOCK` is used first, so a developer who ran
+/// `secrets-manager unlock` isn't asked again.
+pub enum PasswordSource {
+    /// A password the caller already has
+    Password(SecretString),
+    /// The password in an environment variable with this name
+    Env(String),
+    /// The password in the first line of a file
+    File(PathBuf),
+    /// Ask on the terminal
+    Prompt,
+    /// A keyfile, for projects with a keyfile-only slot
+    Keyfile(PathBuf),
+    /// An identity file, for projects shared with its public key
+    Identity(PathBuf),
+    /// Any other combination, such as a password together with a keyfile
+    Credentials(Credentials),
+    /// Only the agent's cached key; fails if the project isn't unlocked there
+    Agent,
+}
+
+impl PasswordSource {
+    fn credentials(self, project_name: &str) -> Result<Credentials> {
+        let password = match self {
+            Self::Password(password) => password,
+            Self::Env(name) => std::env::var(&name)
+                .map(SecretString::new)
+                .map_err(|_| Error::InvalidCredentials(format!("{} is not set", name)))?,
+            Self::File(path) => {
+                let content = SecretString::new(std::fs::read_to_string(&path).map_err(|e| std::io::Error::new(
+                    e.kind(),
+                    format!("Could not read password file '{}': {}", path.display(), e),
+                ))?);
+                SecretString::from(content.expose_secret().lines().next().unwrap_or_default())
+            }
+            Self::Prompt => Terminal.read_secret(&format!("Enter master password for '{}': ", project_name))?,
+            Self::Keyfile(path) => return Credentials::new(None, Some(crypto::read_keyfile(&path)?)),
+            Self::Identity(path) => return Ok(Credentials::identity(Identity::read_file(&path)?)),
+            Self::Credentials(credentials) => return Ok(credentials),
+            Self::Agent => return Err(Error::InvalidCredentials(format!(
+                "Project '{}' isn't unlocked in the agent; run `secrets-manager unlock {}`",
+                project_name, project_name
+            ))),
+        };
+        if password.is_empty() {
+            return Err(Error::InvalidCredentials("Password cannot be empty".to_string()));
+        }
+        Ok(Credentials::password(password.expose_secret()))
+    }
+}
+
+/// Loads a project's secrets into the process environment
+///
+/// Variables that are already set are left alone, as with `.env` loaders, so
+/// the real environment can still override the vault. Call this at startup,
+/// before other threads read the environment.
+pub fn load_env(project_name: &str, source: PasswordSource) -> Result<()> {
+    let project = load_project(project_name, source)?;
+    for (key, value) in &project.secrets {
+        if std::env::var_os(key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
+    Ok(())
+}
+
+/// Loads a project's secrets into a struct
+///
+/// Keys are matched case-insensitively against field names, so `DATABASE_URL`
+/// fills `database_url`, and values are parsed into the field's type.
+/// `Option` fields may be missing.
+pub fn load_into<T: DeserializeOwned>(project_name: &str, source: PasswordSource) -> Result<T> {
+    let project = load_project(project_name, source)?;
+    from_secrets(&project.secrets)
+        .map_err(|e| anyhow!("Secrets of project '{}' don't fit: {}", project_name, e).into())
+}
+
+/// Loads and decrypts a project from the default vault
+pub fn load_project(project_name: &str, source: PasswordSource) -> Result<Project> {
+    open_project(&SecretStorage::new()?, AgentClient::from_env().as_ref(), project_name, source)
+}
+
+/// Loads and decrypts a project from any storage, trying the agent's cached key first
+pub fn open_project<B: StorageBackend>(
+    storage: &SecretStorage<B>,
+    agent: Option<&AgentClient>,
+    project_name: &str,
+    source: PasswordSource,
+) -> Result<Project> {
+    if let Some(Ok(Some(key))) = agent.map(|agent| agent.get_key(project_name)) {
+        // A stale key from before a re-key falls through to the credentials
+        if let Ok(project) = storage.load_project_with_key(project_name, &key) {
+            return Ok(project);
+        }
+    }
+
+    if let PasswordSource::Password(password) = &source {
+        return storage.load_project(project_name, password.expose_secret());
+    }
+    let encrypted = storage.load_encrypted(project_name)?;
+    let credentials = source.credentials(project_name)?;
+    if crypto::is_legacy(&encrypted) {
+        return crypto::decrypt_legacy_project(&encrypted, &credentials);
+    }
+    let (key, _) = crypto::unlock_key(&encrypted, &credentials)?;
+    storage.load_project_with_key(project_name, &key)
+}
+
+fn from_secrets<T: DeserializeOwned>(secrets: &HashMap<String, String>) -> std::result::Result<T, de::value::Error> {
+    T::deserialize(MapDeserializer::new(
+        secrets.iter().map(|(key, value)| (key.to_lowercase(), SecretValue(value))),
+    ))
+}
+
+/// A secret value, parsed into whatever type the target field has
+struct SecretValue<'a>(&'a str);
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for SecretValue<'_> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Self::Error> {
+                let value = self.0.trim().parse().map_err(|e| de::Error::custom(format!("'{}': {}", self.0, e)))?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for SecretValue<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_str(self.0)
+    }
+
+    parse_value! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+    use serde::Deserialize;
+
+    #[test]
+    fn test_from_secrets_parses_fields() {
+        #[derive(Deserialize)]
+        struct Config {
+            database_url: String,
+            port: u16,
+            debug: bool,
+            sentry_dsn: Option<String>,
+        }
+
+        let secrets = HashMap::from([
+            ("DATABASE_URL".to_string(), "postgres://localhost/app".to_string()),
+            ("PORT".to_string(), "8080".to_string()),
+            ("DEBUG".to_string(), "true".to_string()),
+            ("UNUSED".to_string(), "x".to_string()),
+        ]);
+        let config: Config = from_secrets(&secrets).unwrap();
+        assert_eq!((config.database_url.as_str(), config.port, config.debug), ("postgres://localhost/app", 8080, true));
+        assert!(config.sentry_dsn.is_none());
+
+        let bad_port = HashMap::from([("PORT".to_string(), "eighty".to_string())]);
+        assert!(from_secrets::<Config>(&bad_port).is_err());
+    }
+
+    #[test]
+    fn test_open_project_with_each_source() {
+        let storage = SecretStorage::with_backend(MemoryBackend::new());
+        let mut project = Project::new("api".to_string());
+        project.add_secret("TOKEN".to_string(), "abc".to_string());
+        storage.save_project(&project, "pw").unwrap();
+
+        let open = |source| open_project(&storage, None, "api", source);
+        assert_eq!(open(PasswordSource::Password(SecretString::from("pw"))).unwrap().secrets, project.secrets);
+        assert!(matches!(open(PasswordSource::Password(SecretString::from("no"))), Err(Error::WrongPassword)));
+        assert!(matches!(open(PasswordSource::Env("SECRETS_MANAGER_TEST_UNSET".to_string())), Err(Error::InvalidCredentials(_))));
+        assert!(matches!(open(PasswordSource::Agent), Err(Error::InvalidCredentials(_))));
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let password_file = dir.path().join("password");
+        std::fs::write(&password_file, "pw\n").unwrap();
+        assert_eq!(open(PasswordSource::File(password_file)).unwrap().secrets, project.secrets);
+    }
+}
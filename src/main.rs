@@ -1,11 +1,35 @@
 use clap::Parser;
-use secrets_manager::cli::{Cli, SecretManager};
-use anyhow::Result;
+use secrets_manager::cli::{self, Cli, OutputFormat, SecretManager};
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let manager = SecretManager::new()?;
-    
-    manager.handle_command(cli.command)?;
-    Ok(())
+fn main() {
+    let mut cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let args: Vec<String> = std::env::args().collect();
+            // Help and version requests also arrive as errors
+            if !e.use_stderr() || OutputFormat::from_args(&args) == OutputFormat::Text {
+                e.exit();
+            }
+            let rendered = e.to_string();
+            let message = rendered.lines().next().unwrap_or_default().trim_start_matches("error: ").to_string();
+            std::process::exit(cli::report_error(OutputFormat::Json, &anyhow::anyhow!(message), cli::EXIT_USAGE));
+        }
+    };
+
+    let output = cli.output_format();
+    match cli.apply_deprecated_output() {
+        Ok(Some(warning)) => eprintln!("⚠️  {}", warning),
+        Ok(None) => {}
+        Err(e) => std::process::exit(cli::report_error(output, &e, cli::EXIT_USAGE)),
+    }
+    let result = SecretManager::new().and_then(|manager| {
+        manager
+            .with_keyfile(cli.keyfile)
+            .with_identity(cli.identity)
+            .with_output(output)
+            .handle_command(cli.command)
+    });
+    if let Err(e) = result {
+        std::process::exit(cli::report_error(output, &e, cli::exit_code(&e)));
+    }
 }
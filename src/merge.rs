@@ -0,0 +1,182 @@
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use zeroize::Zeroize;
+
+/// Outcome of a three-way merge of a single value
+#[derive(Debug, PartialEq)]
+pub enum Merged<T> {
+    /// Both sides agree, or only one side changed; `None` means deleted
+    Resolved(Option<T>),
+    /// Both sides changed the value differently
+    Conflict,
+}
+
+/// Three-way merge of one value given its base, our and their versions
+pub fn three_way<T: PartialEq + Clone>(base: Option<&T>, ours: Option<&T>, theirs: Option<&T>) -> Merged<T> {
+    if ours == theirs || theirs == base {
+        Merged::Resolved(ours.cloned())
+    } else if ours == base {
+        Merged::Resolved(theirs.cloned())
+    } else {
+        Merged::Conflict
+    }
+}
+
+/// How a secret changed on the other side relative to the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Removed => write!(f, "removed"),
+            ChangeKind::Changed => write!(f, "changed"),
+        }
+    }
+}
+
+/// Describes one side of a conflict without revealing it: its length and a short hash
+pub fn fingerprint(value: Option<&str>) -> String {
+    match value {
+        Some(value) => {
+            let hash: String = Sha256::digest(value.as_bytes()).iter().take(4).map(|b| format!("{:02x}", b)).collect();
+            format!("{} character(s), sha256 {}", value.chars().count(), hash)
+        }
+        None => "deleted".to_string(),
+    }
+}
+
+/// A secret both sides changed differently; `None` means deleted on that side
+///
+/// Values are wiped from memory when the conflict is dropped.
+pub struct Conflict {
+    pub key: String,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+impl fmt::Debug for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Conflict").field("key", &self.key).finish_non_exhaustive()
+    }
+}
+
+impl Drop for Conflict {
+    fn drop(&mut self) {
+        for value in [&mut self.base, &mut self.ours, &mut self.theirs].into_iter().flatten() {
+            value.zeroize();
+        }
+    }
+}
+
+/// Key-level merge of two versions of a project's secrets
+pub struct SecretMerge {
+    /// Every key that merged cleanly; conflicting keys keep our value until resolved
+    pub merged: HashMap<String, String>,
+    /// Changes taken from their side, by key
+    pub applied: Vec<(String, ChangeKind)>,
+    /// Keys both sides changed differently
+    pub conflicts: Vec<Conflict>,
+}
+
+impl SecretMerge {
+    /// Applies the chosen value of a conflict; `None` removes the key
+    pub fn resolve(&mut self, key: &str, value: Option<String>) {
+        match value {
+            Some(value) => {
+                self.merged.insert(key.to_string(), value);
+            }
+            None => {
+                if let Some(mut old) = self.merged.remove(key) {
+                    old.zeroize();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SecretMerge {
+    fn drop(&mut self) {
+        for value in self.merged.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// Merges their secrets into ours against a common base, key by key
+pub fn merge_secrets(
+    base: &HashMap<String, String>,
+    ours: &HashMap<String, String>,
+    theirs: &HashMap<String, String>,
+) -> SecretMerge {
+    let keys: BTreeSet<&String> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+
+    let mut result = SecretMerge { merged: ours.clone(), applied: Vec::new(), conflicts: Vec::new() };
+    for key in keys {
+        let (b, o, t) = (base.get(key), ours.get(key), theirs.get(key));
+        match three_way(b, o, t) {
+            Merged::Resolved(value) => {
+                if value.as_ref() != o {
+                    let kind = match (b, t) {
+                        (None, _) => ChangeKind::Added,
+                        (_, None) => ChangeKind::Removed,
+                        _ => ChangeKind::Changed,
+                    };
+                    result.applied.push((key.clone(), kind));
+                    result.resolve(key, value);
+                }
+            }
+            Merged::Conflict => result.conflicts.push(Conflict {
+                key: key.clone(),
+                base: b.cloned(),
+                ours: o.cloned(),
+                theirs: t.cloned(),
+            }),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_merge_secrets() {
+        let base = map(&[("KEEP", "1"), ("OURS", "1"), ("THEIRS", "1"), ("GONE", "1"), ("BOTH", "1")]);
+        let ours = map(&[("KEEP", "1"), ("OURS", "2"), ("THEIRS", "1"), ("GONE", "1"), ("BOTH", "2"), ("NEW", "a")]);
+        let theirs = map(&[("KEEP", "1"), ("OURS", "1"), ("THEIRS", "2"), ("BOTH", "3"), ("NEW", "a")]);
+
+        let result = merge_secrets(&base, &ours, &theirs);
+        assert_eq!(result.merged, map(&[("KEEP", "1"), ("OURS", "2"), ("THEIRS", "2"), ("BOTH", "2"), ("NEW", "a")]));
+        assert_eq!(result.applied, vec![
+            ("GONE".to_string(), ChangeKind::Removed),
+            ("THEIRS".to_string(), ChangeKind::Changed),
+        ]);
+        assert_eq!(result.conflicts.len(), 1);
+        assert_eq!(result.conflicts[0].key, "BOTH");
+        assert_eq!(result.conflicts[0].theirs.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn test_merge_without_base_keeps_one_sided_keys() {
+        let ours = map(&[("A", "1"), ("SAME", "x"), ("DIFF", "1")]);
+        let theirs = map(&[("B", "2"), ("SAME", "x"), ("DIFF", "2")]);
+
+        let result = merge_secrets(&HashMap::new(), &ours, &theirs);
+        assert_eq!(result.merged, map(&[("A", "1"), ("B", "2"), ("SAME", "x"), ("DIFF", "1")]));
+        assert_eq!(result.applied, vec![("B".to_string(), ChangeKind::Added)]);
+        assert_eq!(result.conflicts.len(), 1);
+    }
+}
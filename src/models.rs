@@ -1,13 +1,30 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use zeroize::Zeroize;
 
 /// Represents a project with its associated secrets
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Secret values are wiped from memory when the project is dropped, and the
+/// `Debug` output only lists key names.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Project {
     pub name: String,
     pub secrets: HashMap<String, String>,
+    /// Named sets of secrets such as `dev` or `prod`, kept apart from the top-level secrets
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub environments: BTreeMap<String, Environment>,
+    /// Projects whose secrets this one builds on; later ones override earlier ones,
+    /// and the project's own secrets override them all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extends: Vec<String>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub updated_at: chrono::DateTime<chrono::Utc>,
+    /// Version of the stored file this was read from, which saving it is conditional on
+    ///
+    /// Set by `SecretStorage` for backends that hand out versions; never stored.
+    #[serde(skip)]
+    pub stored_version: Option<String>,
 }
 
 impl Project {
@@ -16,22 +33,20 @@ impl Project {
         Self {
             name,
             secrets: HashMap::new(),
+            environments: BTreeMap::new(),
+            extends: Vec::new(),
             created_at: now,
             updated_at: now,
+            stored_version: None,
         }
     }
 
     pub fn add_secret(&mut self, key: String, value: String) {
-        self.secrets.insert(key, value);
-        self.updated_at = chrono::Utc::now();
+        self.add_secret_to(None, key, value);
     }
 
     pub fn remove_secret(&mut self, key: &str) -> Option<String> {
-        let result = self.secrets.remove(key);
-        if result.is_some() {
-            self.updated_at = chrono::Utc::now();
-        }
-        result
+        self.remove_secret_from(None, key)
     }
 
     pub fn get_secret(&self, key: &str) -> Option<&String> {
@@ -41,16 +56,290 @@ impl Project {
     pub fn list_secrets(&self) -> Vec<&String> {
         self.secrets.keys().collect()
     }
+
+    /// Stores a secret in an environment, or at the top level for `None`
+    ///
+    /// The environment is created, without a base, if it doesn't exist yet.
+    pub fn add_secret_to(&mut self, environment: Option<&str>, key: String, value: String) {
+        let secrets = match environment {
+            Some(name) => &mut self.environments.entry(name.to_string()).or_default().secrets,
+            None => &mut self.secrets,
+        };
+        secrets.insert(key, value);
+        self.updated_at = chrono::Utc::now();
+    }
+
+    /// Removes a secret stored in an environment itself; inherited secrets are left alone
+    pub fn remove_secret_from(&mut self, environment: Option<&str>, key: &str) -> Option<String> {
+        let secrets = match environment {
+            Some(name) => &mut self.environments.get_mut(name)?.secrets,
+            None => &mut self.secrets,
+        };
+        let result = secrets.remove(key);
+        if result.is_some() {
+            self.updated_at = chrono::Utc::now();
+        }
+        result
+    }
+
+    /// A secret stored in an environment itself, or at the top level for `None`
+    pub fn own_secret(&self, environment: Option<&str>, key: &str) -> Option<&String> {
+        match environment {
+            Some(name) => self.environments.get(name)?.secrets.get(key),
+            None => self.secrets.get(key),
+        }
+    }
+
+    /// Adds an empty environment, optionally inheriting the secrets of an existing one
+    pub fn add_environment(&mut self, name: &str, inherits: Option<&str>) -> anyhow::Result<()> {
+        if name.is_empty() {
+            return Err(anyhow::anyhow!("Environment name cannot be empty"));
+        }
+        if self.environments.contains_key(name) {
+            return Err(anyhow::anyhow!("Environment '{}' already exists in project '{}'", name, self.name));
+        }
+        if let Some(base) = inherits {
+            self.environment(base)?;
+        }
+        let environment = Environment { inherits: inherits.map(str::to_string), secrets: HashMap::new() };
+        self.environments.insert(name.to_string(), environment);
+        self.updated_at = chrono::Utc::now();
+        Ok(())
+    }
+
+    /// Removes an environment that no other environment inherits from
+    pub fn remove_environment(&mut self, name: &str) -> anyhow::Result<()> {
+        self.environment(name)?;
+        if let Some(child) = self.environments.iter().find(|(_, env)| env.inherits.as_deref() == Some(name)) {
+            return Err(anyhow::anyhow!("Environment '{}' inherits from '{}'; remove it first", child.0, name));
+        }
+        self.environments.remove(name);
+        self.updated_at = chrono::Utc::now();
+        Ok(())
+    }
+
+    pub fn environment(&self, name: &str) -> anyhow::Result<&Environment> {
+        self.environments.get(name).ok_or_else(|| {
+            anyhow::anyhow!("Environment '{}' not found in project '{}'", name, self.name)
+        })
+    }
+
+    /// An environment followed by the environments it inherits from, nearest first
+    pub fn environment_chain(&self, name: &str) -> anyhow::Result<Vec<(&str, &Environment)>> {
+        let mut chain: Vec<(&str, &Environment)> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut next = Some(name);
+        while let Some(name) = next {
+            if !seen.insert(name) {
+                return Err(anyhow::anyhow!("Environment '{}' of project '{}' inherits from itself", name, self.name));
+            }
+            let (name, environment) = self.environments.get_key_value(name).ok_or_else(|| match chain.last() {
+                Some((child, _)) => anyhow::anyhow!("Environment '{}' inherits from missing environment '{}'", child, name),
+                None => anyhow::anyhow!("Environment '{}' not found in project '{}'", name, self.name),
+            })?;
+            chain.push((name.as_str(), environment));
+            next = environment.inherits.as_deref();
+        }
+        Ok(chain)
+    }
+
+    /// The project as seen from one environment: its secrets laid over those it inherits
+    ///
+    /// `None` gives the top-level secrets. The result has no environments of its
+    /// own; projects it extends are not looked at.
+    pub fn in_environment(&self, environment: Option<&str>) -> anyhow::Result<Project> {
+        let mut secrets = HashMap::new();
+        match environment {
+            Some(name) => {
+                for (_, environment) in self.environment_chain(name)?.into_iter().rev() {
+                    secrets.extend(environment.secrets.iter().map(|(k, v)| (k.clone(), v.clone())));
+                }
+            }
+            None => secrets.clone_from(&self.secrets),
+        }
+        Ok(Project {
+            name: self.name.clone(),
+            secrets,
+            environments: BTreeMap::new(),
+            extends: self.extends.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            stored_version: None,
+        })
+    }
+}
+
+impl fmt::Debug for Project {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut keys: Vec<&String> = self.secrets.keys().collect();
+        keys.sort();
+        f.debug_struct("Project")
+            .field("name", &self.name)
+            .field("secrets", &keys)
+            .field("environments", &self.environments.keys().collect::<Vec<_>>())
+            .field("extends", &self.extends)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        for value in self.secrets.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// A named set of secrets within a project, such as `dev`, `staging` or `prod`
+///
+/// Secret values are wiped from memory when the environment is dropped.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Environment {
+    /// Environment whose secrets this one starts from; its own secrets override them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inherits: Option<String>,
+    #[serde(default)]
+    pub secrets: HashMap<String, String>,
+}
+
+impl fmt::Debug for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut keys: Vec<&String> = self.secrets.keys().collect();
+        keys.sort();
+        f.debug_struct("Environment")
+            .field("inherits", &self.inherits)
+            .field("secrets", &keys)
+            .finish()
+    }
+}
+
+impl Drop for Environment {
+    fn drop(&mut self) {
+        for value in self.secrets.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+/// What a key slot (or a legacy project file) has to be unlocked with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    #[default]
+    Password,
+    Keyfile,
+    PasswordAndKeyfile,
+    RecoveryCode,
+    /// Wrapped for an X25519 public key; unlocked with the matching identity file
+    Recipient,
+}
+
+impl CredentialKind {
+    pub fn parse(kind: &str) -> anyhow::Result<Self> {
+        match kind.to_lowercase().as_str() {
+            "password" => Ok(CredentialKind::Password),
+            "keyfile" => Ok(CredentialKind::Keyfile),
+            "password+keyfile" => Ok(CredentialKind::PasswordAndKeyfile),
+            "recovery" => Ok(CredentialKind::RecoveryCode),
+            _ => Err(anyhow::anyhow!("Invalid slot type. Use: password, keyfile, password+keyfile, or recovery")),
+        }
+    }
+
+    pub fn needs_password(self) -> bool {
+        matches!(self, CredentialKind::Password | CredentialKind::PasswordAndKeyfile)
+    }
+
+    pub fn needs_keyfile(self) -> bool {
+        matches!(self, CredentialKind::Keyfile | CredentialKind::PasswordAndKeyfile)
+    }
+}
+
+impl std::fmt::Display for CredentialKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CredentialKind::Password => "password",
+            CredentialKind::Keyfile => "keyfile",
+            CredentialKind::PasswordAndKeyfile => "password+keyfile",
+            CredentialKind::RecoveryCode => "recovery",
+            CredentialKind::Recipient => "recipient",
+        })
+    }
+}
+
+/// Current on-disk format version of `EncryptedProject`
+///
+/// Version 3 encrypts the payload with a random data key that is wrapped by
+/// one or more key slots. Versions 1 and 2 derived the payload key directly
+/// from the credentials and are upgraded the first time they are unlocked.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// A copy of the project's data key, wrapped with a key derived from one set of credentials
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub id: u32,
+    pub kind: CredentialKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// KDF salt (empty for recipient slots)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub salt: String,
+    pub nonce: String,
+    pub wrapped_key: String,
+    /// Public key the data key is wrapped for (recipient slots only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Ephemeral X25519 public key used for the wrap (recipient slots only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral_key: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 /// Encrypted data structure for storage
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EncryptedProject {
+    /// Format version; files written before versioning was introduced default to 1
+    #[serde(default = "legacy_format_version")]
+    pub version: u32,
+    /// Project name, authenticated as associated data from version 2 onwards
+    #[serde(default)]
+    pub project_name: String,
+    /// Credentials required to derive the key (versions 1 and 2 only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<CredentialKind>,
+    /// Slots wrapping the data key (version 3 onwards)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slots: Vec<KeySlot>,
+    /// Lowest id a new slot may get, so ids of removed slots are never reused
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub next_slot_id: u32,
     pub encrypted_data: String,
+    /// KDF salt (versions 1 and 2 only; slots carry their own salts)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub salt: String,
     pub nonce: String,
 }
 
+fn legacy_format_version() -> u32 {
+    1
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl EncryptedProject {
+    /// The id for the next new key slot
+    ///
+    /// Files written before the counter was stored fall back to one past
+    /// the highest slot id.
+    pub fn new_slot_id(&self) -> u32 {
+        let after_slots = self.slots.iter().map(|slot| slot.id + 1).max().unwrap_or(1);
+        after_slots.max(self.next_slot_id)
+    }
+}
+
 /// Export format options
 #[derive(Debug, Clone)]
 pub enum ExportFormat {
@@ -58,3 +347,63 @@ pub enum ExportFormat {
     EnvFile,
     Json,
 }
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> anyhow::Result<Self> {
+        match format.to_lowercase().as_str() {
+            "shell" => Ok(ExportFormat::Shell),
+            "env" => Ok(ExportFormat::EnvFile),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(anyhow::anyhow!("Invalid format. Use: shell, env, or json")),
+        }
+    }
+
+    /// Renders a project's secrets in this format
+    pub fn format(&self, project: &Project) -> anyhow::Result<String> {
+        match self {
+            ExportFormat::Shell => {
+                let mut output = String::new();
+                for (key, value) in &project.secrets {
+                    output.push_str(&format!("export {}='{}'\n", key, value));
+                }
+                Ok(output)
+            }
+            ExportFormat::EnvFile => {
+                let mut output = String::new();
+                for (key, value) in &project.secrets {
+                    output.push_str(&format!("{}={}\n", key, value));
+                }
+                Ok(output)
+            }
+            ExportFormat::Json => {
+                serde_json::to_string_pretty(&project.secrets).map_err(|e| e.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environments_inherit_and_override() {
+        let mut project = Project::new("api".to_string());
+        project.add_environment("base", None).unwrap();
+        project.add_environment("prod", Some("base")).unwrap();
+        assert!(project.add_environment("dev", Some("missing")).is_err());
+        project.add_secret_to(Some("base"), "SENTRY_DSN".to_string(), "https://sentry".to_string());
+        project.add_secret_to(Some("base"), "LOG_LEVEL".to_string(), "debug".to_string());
+        project.add_secret_to(Some("prod"), "LOG_LEVEL".to_string(), "warn".to_string());
+
+        let prod = project.in_environment(Some("prod")).unwrap();
+        assert_eq!(prod.get_secret("LOG_LEVEL").unwrap(), "warn");
+        assert_eq!(prod.get_secret("SENTRY_DSN").unwrap(), "https://sentry");
+        assert!(project.in_environment(None).unwrap().secrets.is_empty());
+        assert!(project.remove_secret_from(Some("prod"), "SENTRY_DSN").is_none());
+        assert!(project.remove_environment("base").is_err());
+
+        project.environments.get_mut("base").unwrap().inherits = Some("prod".to_string());
+        assert!(project.in_environment(Some("prod")).is_err());
+    }
+}
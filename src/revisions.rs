@@ -0,0 +1,340 @@
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::backend::{DirectoryBackend, Fork, StorageBackend, StorageLock};
+
+/// Directory inside the vault holding one sub-directory of revisions per project
+pub const REVISIONS_DIR: &str = "revisions";
+/// Generations kept behind every head when compacting
+const KEEP_GENERATIONS: usize = 5;
+/// Unreadable revision files younger than this may still be arriving
+const INCOMPLETE_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One immutable revision of a project, named after the hash of its contents
+#[derive(Serialize, Deserialize)]
+struct Revision {
+    /// Revisions this one supersedes; several after a merge
+    parents: Vec<String>,
+    written_at: chrono::DateTime<chrono::Utc>,
+    /// Base64 encrypted project, or `None` once the project is deleted
+    data: Option<String>,
+}
+
+impl Revision {
+    fn blob(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.as_ref().map(|data| general_purpose::STANDARD.decode(data)).transpose()?)
+    }
+}
+
+fn revision_id(content: &[u8]) -> String {
+    Sha256::digest(content).iter().take(16).map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Revisions of one project that are complete and untampered, keyed by id
+struct History {
+    revisions: HashMap<String, Revision>,
+}
+
+impl History {
+    /// Revisions no other revision supersedes, newest first
+    fn heads(&self) -> Vec<&String> {
+        let superseded: HashSet<&String> = self.revisions.values().flat_map(|rev| &rev.parents).collect();
+        let mut heads: Vec<&String> = self.revisions.keys().filter(|id| !superseded.contains(id)).collect();
+        heads.sort_by(|a, b| {
+            let (ra, rb) = (&self.revisions[*a], &self.revisions[*b]);
+            rb.written_at.cmp(&ra.written_at).then_with(|| b.cmp(a))
+        });
+        heads
+    }
+
+    /// The head `get` reads: the newest one still holding the project
+    ///
+    /// A deletion only wins once no concurrent head has data; until then it
+    /// is one of the forks, so an edit made elsewhere can still be merged.
+    fn current(&self) -> Option<&String> {
+        let heads = self.heads();
+        heads.iter().find(|id| self.revisions[**id].data.is_some()).or(heads.first()).copied()
+    }
+
+    /// Ids reachable from `id` through parents, with their distance
+    fn ancestors(&self, id: &str) -> HashMap<String, usize> {
+        let mut seen = HashMap::new();
+        let mut queue = VecDeque::from([(id.to_string(), 0)]);
+        while let Some((id, depth)) = queue.pop_front() {
+            if seen.contains_key(&id) {
+                continue;
+            }
+            if let Some(rev) = self.revisions.get(&id) {
+                queue.extend(rev.parents.iter().map(|parent| (parent.clone(), depth + 1)));
+            }
+            seen.insert(id, depth);
+        }
+        seen
+    }
+
+    /// The nearest revision both heads descend from, if it is still around
+    fn common_ancestor(&self, a: &str, b: &str) -> Option<&Revision> {
+        let ours = self.ancestors(a);
+        self.ancestors(b)
+            .into_iter()
+            .filter(|(id, _)| ours.contains_key(id))
+            .min_by_key(|(id, depth)| (*depth, id.clone()))
+            .and_then(|(id, _)| self.revisions.get(&id))
+    }
+}
+
+/// Append-only storage that is safe to keep in Dropbox, Syncthing and similar folders
+///
+/// Every write adds a new content-addressed file instead of rewriting one, so
+/// sync tools never see two versions of the same file. Reads skip files that
+/// are incomplete, and concurrent writes from two machines show up as forks
+/// rather than one silently overwriting the other.
+pub struct RevisionBackend {
+    dir: PathBuf,
+}
+
+impl RevisionBackend {
+    pub fn open(vault_dir: &Path) -> Result<Self> {
+        let dir = vault_dir.join(REVISIONS_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn project_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn history(&self, name: &str) -> Result<History> {
+        let mut revisions = HashMap::new();
+        let dir = self.project_dir(name);
+        if !dir.exists() {
+            return Ok(History { revisions });
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("rev") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+            // Files still being synced fail the hash check and are ignored
+            let Ok(content) = fs::read(&path) else { continue };
+            if revision_id(&content) != id {
+                continue;
+            }
+            if let Ok(revision) = serde_json::from_slice::<Revision>(&content) {
+                revisions.insert(id.to_string(), revision);
+            }
+        }
+        Ok(History { revisions })
+    }
+
+    fn write_revision(&self, name: &str, data: Option<&[u8]>, parents: Vec<String>) -> Result<()> {
+        let revision = Revision {
+            parents,
+            written_at: chrono::Utc::now(),
+            data: data.map(|data| general_purpose::STANDARD.encode(data)),
+        };
+        let content = serde_json::to_vec(&revision)?;
+        let id = revision_id(&content);
+
+        let dir = self.project_dir(name);
+        fs::create_dir_all(&dir)?;
+        let temp_path = dir.join(format!(".{}.tmp", id));
+        fs::write(&temp_path, &content)?;
+        fs::rename(&temp_path, dir.join(format!("{}.rev", id)))?;
+
+        self.compact(name)
+    }
+
+    /// Removes revisions far behind every head, and incomplete files that never finished syncing
+    fn compact(&self, name: &str) -> Result<()> {
+        let history = self.history(name)?;
+        let mut keep = HashSet::new();
+        for head in history.heads() {
+            keep.extend(
+                history.ancestors(head)
+                    .into_iter()
+                    .filter(|(_, depth)| *depth <= KEEP_GENERATIONS)
+                    .map(|(id, _)| id),
+            );
+        }
+
+        for entry in fs::read_dir(self.project_dir(name))? {
+            let path = entry?.path();
+            let Some(id) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+            if path.extension().and_then(|s| s.to_str()) != Some("rev") || keep.contains(id) {
+                continue;
+            }
+            let stale = history.revisions.contains_key(id) || fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age > INCOMPLETE_GRACE);
+            if stale {
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+
+    fn put_superseding(&self, name: &str, data: Option<&[u8]>, forks: &[String]) -> Result<()> {
+        let history = self.history(name)?;
+        let mut parents: Vec<String> = history.current().cloned().into_iter().collect();
+        parents.extend(forks.iter().filter(|id| history.revisions.contains_key(*id)).cloned());
+        self.write_revision(name, data, parents)
+    }
+}
+
+impl StorageBackend for RevisionBackend {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let history = self.history(name)?;
+        match history.current() {
+            Some(head) => history.revisions[head].blob(),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.put_superseding(name, Some(data), &[])
+    }
+
+    fn put_merged(&self, name: &str, data: &[u8], forks: &[String]) -> Result<()> {
+        self.put_superseding(name, Some(data), forks)
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let mut projects = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.is_dir() {
+                continue;
+            }
+            if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
+                if self.get(name)?.is_some() {
+                    projects.push(name.to_string());
+                }
+            }
+        }
+        projects.sort();
+        Ok(projects)
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        if self.get(name)?.is_none() {
+            return Ok(false);
+        }
+        // A tombstone lets the deletion sync like any other change
+        self.put_superseding(name, None, &[])?;
+        Ok(true)
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        // Lock files would sync to other machines, so only lock locally
+        let digest = revision_id(self.project_dir(name).to_string_lossy().as_bytes());
+        let lock_dir = std::env::temp_dir().join("secrets-manager-locks");
+        DirectoryBackend::new(lock_dir)?.lock(&digest)
+    }
+
+    fn forks(&self, name: &str) -> Result<Vec<Fork>> {
+        let history = self.history(name)?;
+        let Some(latest) = history.current() else {
+            return Ok(Vec::new());
+        };
+        history.heads().into_iter()
+            .filter(|id| *id != latest)
+            .map(|id| {
+                Ok(Fork {
+                    id: id.to_string(),
+                    data: history.revisions[id].blob()?,
+                    base: match history.common_ancestor(latest, id) {
+                        Some(revision) => revision.blob()?,
+                        None => None,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// Whether a vault directory uses revision storage
+pub fn is_revision_vault(dir: &Path) -> bool {
+    dir.join(REVISIONS_DIR).is_dir()
+}
+
+/// Removes a vault's revision storage
+pub fn remove_revisions(dir: &Path) -> Result<()> {
+    fs::remove_dir_all(dir.join(REVISIONS_DIR))
+        .map_err(|e| anyhow!("Could not remove revisions in '{}': {}", dir.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_concurrent_writes_show_up_as_forks() {
+        let temp_dir = TempDir::new().unwrap();
+        let backend = RevisionBackend::open(temp_dir.path()).unwrap();
+
+        backend.put("api", b"base").unwrap();
+        let base_head = backend.history("api").unwrap().heads()[0].clone();
+        backend.put("api", b"ours").unwrap();
+        // Another machine wrote on top of the same base before syncing
+        backend.write_revision("api", Some(b"theirs"), vec![base_head]).unwrap();
+
+        let forks = backend.forks("api").unwrap();
+        assert_eq!(forks.len(), 1);
+        assert_eq!(backend.get("api").unwrap().unwrap(), b"theirs");
+        assert_eq!(forks[0].data.as_deref(), Some(&b"ours"[..]));
+        assert_eq!(forks[0].base.as_deref(), Some(&b"base"[..]));
+
+        backend.put_merged("api", b"merged", &[forks[0].id.clone()]).unwrap();
+        assert!(backend.forks("api").unwrap().is_empty());
+        assert_eq!(backend.get("api").unwrap().unwrap(), b"merged");
+    }
+
+    #[test]
+    fn test_incomplete_revisions_are_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let backend = RevisionBackend::open(temp_dir.path()).unwrap();
+        backend.put("api", b"one").unwrap();
+
+        // A half-synced file whose contents don't match its name
+        fs::write(backend.project_dir("api").join("0123456789abcdef0123456789abcdef.rev"), b"{\"par").unwrap();
+        assert_eq!(backend.get("api").unwrap().unwrap(), b"one");
+
+        backend.delete("api").unwrap();
+        assert!(backend.get("api").unwrap().is_none());
+        assert!(backend.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deletion_does_not_hide_a_concurrent_edit() {
+        let temp_dir = TempDir::new().unwrap();
+        let backend = RevisionBackend::open(temp_dir.path()).unwrap();
+
+        backend.put("api", b"base").unwrap();
+        let base_head = backend.history("api").unwrap().heads()[0].clone();
+        backend.put("api", b"edited").unwrap();
+        // Another machine deleted the project, later, on top of the same base
+        backend.write_revision("api", None, vec![base_head]).unwrap();
+
+        assert_eq!(backend.get("api").unwrap().unwrap(), b"edited");
+        assert_eq!(backend.list().unwrap(), vec!["api".to_string()]);
+        let forks = backend.forks("api").unwrap();
+        assert_eq!(forks.len(), 1);
+        assert!(forks[0].data.is_none());
+        assert_eq!(forks[0].base.as_deref(), Some(&b"base"[..]));
+
+        backend.put_merged("api", b"kept", &[forks[0].id.clone()]).unwrap();
+        assert!(backend.forks("api").unwrap().is_empty());
+        assert_eq!(backend.get("api").unwrap().unwrap(), b"kept");
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// A string holding sensitive material (passwords, secret values) that is
+/// wiped from memory when dropped and never printed by `Debug`
+#[derive(Clone, Default, Zeroize, ZeroizeOnDrop)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// Returns the underlying secret; callers should avoid copying it
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(value: &str) -> Self {
+        Self(value.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let secret = SecretString::from("hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        assert_eq!(secret.expose_secret(), "hunter2");
+    }
+}
@@ -0,0 +1,445 @@
+use anyhow::{Result, anyhow};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+use crate::agent::AgentClient;
+use crate::backend::StorageBackend;
+use crate::crypto::{self, Credentials, ProjectKey};
+use crate::error::Error;
+use crate::models::{ExportFormat, Project};
+use crate::secret::SecretString;
+use crate::storage::{self, SecretStorage};
+
+/// Environment variable holding the bearer token clients must present
+pub const API_TOKEN_ENV: &str = "SECRETS_MANAGER_API_TOKEN";
+/// Environment variable the `serve` command prints the server address under
+pub const API_ADDR_ENV: &str = "SECRETS_MANAGER_API";
+/// Largest request body accepted
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// Where the API server accepts connections
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    /// A loopback TCP address such as `127.0.0.1:7878`
+    Tcp(String),
+    /// A Unix socket only the owner can reach
+    Unix(PathBuf),
+}
+
+/// A response ready to send: status code, content type and body
+pub struct ApiResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Zeroizing<String>,
+}
+
+impl ApiResponse {
+    /// Serializes straight into the body, which is wiped once sent
+    fn json(status: u16, value: &impl Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: Zeroizing::new(serde_json::to_string(value).unwrap_or_default()),
+        }
+    }
+}
+
+/// A failed request, reported to the client as `{"error": "..."}`
+struct ApiError {
+    status: u16,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self { status, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(500, e.to_string())
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::WrongPassword => 403,
+            Error::InvalidCredentials(_) | Error::InvalidName(_) => 400,
+            Error::NotFound(_) => 404,
+            Error::Locked(_) => 409,
+            _ => 500,
+        };
+        Self::new(status, e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(400, format!("Invalid JSON body: {}", e))
+    }
+}
+
+type ApiResult = std::result::Result<ApiResponse, ApiError>;
+
+#[derive(Deserialize)]
+struct UnlockBody {
+    password: Option<String>,
+    keyfile: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct SetBody {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct SecretsBody<'a> {
+    project: &'a str,
+    secrets: &'a HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct SecretBody<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+/// Small JSON API over a `SecretStorage`, for local tools that would
+/// rather make HTTP calls than run the CLI
+///
+/// Every request needs `Authorization: Bearer <token>`. Projects have to be
+/// unlocked through the API (or be cached in the agent) before their
+/// secrets can be read or written; unlocked keys live only in this process.
+pub struct ApiServer<B: StorageBackend = Box<dyn StorageBackend>> {
+    storage: SecretStorage<B>,
+    token: SecretString,
+    agent: Option<AgentClient>,
+    keys: Mutex<HashMap<String, ProjectKey>>,
+}
+
+impl<B: StorageBackend> ApiServer<B> {
+    pub fn new(storage: SecretStorage<B>, token: SecretString) -> Self {
+        Self { storage, token, agent: None, keys: Mutex::new(HashMap::new()) }
+    }
+
+    /// Sets an agent whose cached keys unlock projects without a password
+    pub fn with_agent(mut self, agent: Option<AgentClient>) -> Self {
+        self.agent = agent;
+        self
+    }
+
+    /// Serves requests in the foreground until the process is killed
+    ///
+    /// `ready` is called once the server listens, so clients told about it
+    /// from there can connect straight away.
+    pub fn run(&self, addr: &ListenAddr, ready: impl FnOnce()) -> Result<()> {
+        let server = match addr {
+            ListenAddr::Tcp(addr) => tiny_http::Server::http(addr.as_str())
+                .map_err(|e| anyhow!("Could not listen on {}: {}", addr, e))?,
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::PermissionsExt;
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let server = tiny_http::Server::http_unix(path)
+                    .map_err(|e| anyhow!("Could not listen on '{}': {}", path.display(), e))?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+                server
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => return Err(anyhow!("Unix sockets are only supported on Unix platforms")),
+        };
+        ready();
+
+        for mut request in server.incoming_requests() {
+            let authorization = request.headers().iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str().to_string());
+            let mut body = Zeroizing::new(Vec::new());
+            let response = match request.as_reader().take(MAX_BODY + 1).read_to_end(&mut body) {
+                Ok(_) if body.len() as u64 > MAX_BODY => ApiResponse::json(413, &json!({ "error": "Request body too large" })),
+                Ok(_) => self.handle(request.method().as_str(), request.url(), authorization.as_deref(), &body),
+                Err(e) => ApiResponse::json(400, &json!({ "error": format!("Could not read request: {}", e) })),
+            };
+
+            let content_type = tiny_http::Header::from_bytes("Content-Type", response.content_type)
+                .expect("static header is valid");
+            let _ = request.respond(
+                tiny_http::Response::from_data(response.body.as_bytes())
+                    .with_status_code(response.status)
+                    .with_header(content_type),
+            );
+        }
+        Ok(())
+    }
+
+    /// Answers one request; `url` is the request target including any query string
+    pub fn handle(&self, method: &str, url: &str, authorization: Option<&str>, body: &[u8]) -> ApiResponse {
+        let result = if self.authorized(authorization) {
+            self.route(method, url, body)
+        } else {
+            Err(ApiError::new(401, "Missing or wrong bearer token"))
+        };
+        result.unwrap_or_else(|e| ApiResponse::json(e.status, &json!({ "error": e.message })))
+    }
+
+    fn authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        let expected = self.token.expose_secret().as_bytes();
+        // Compare in constant time so the token can't be guessed byte by byte
+        token.len() == expected.len()
+            && token.bytes().zip(expected).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    fn route(&self, method: &str, url: &str, body: &[u8]) -> ApiResult {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let segments: Vec<String> = path.trim_matches('/').split('/').map(percent_decode).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        match (method, segments.as_slice()) {
+            ("GET", ["v1", "projects"]) => {
+                Ok(ApiResponse::json(200, &json!({ "projects": self.storage.list_projects()? })))
+            }
+            ("POST", ["v1", "projects", project, "unlock"]) => self.unlock(project, body),
+            ("POST", ["v1", "projects", project, "lock"]) => {
+                self.keys().remove(*project);
+                Ok(ApiResponse::json(200, &json!({ "project": project, "locked": true })))
+            }
+            ("GET", ["v1", "projects", project, "secrets"]) => {
+                let (project, _) = self.open(project)?;
+                Ok(ApiResponse::json(200, &SecretsBody { project: &project.name, secrets: &project.secrets }))
+            }
+            ("GET", ["v1", "projects", project, "secrets", key]) => {
+                let (project, _) = self.open(project)?;
+                let value = project.get_secret(key).ok_or_else(|| {
+                    ApiError::new(404, format!("Secret '{}' not found in project '{}'", key, project.name))
+                })?;
+                Ok(ApiResponse::json(200, &SecretBody { key, value }))
+            }
+            ("PUT", ["v1", "projects", project, "secrets", key]) => {
+                let body: SetBody = serde_json::from_slice(body)?;
+                let (mut project, project_key) = self.open(project)?;
+                project.add_secret(key.to_string(), body.value);
+                self.storage.save_project_with_key(&project, &project_key)?;
+                Ok(ApiResponse::json(200, &json!({ "project": project.name, "key": key })))
+            }
+            ("DELETE", ["v1", "projects", project, "secrets", key]) => {
+                let (mut project, project_key) = self.open(project)?;
+                if project.remove_secret(key).is_none() {
+                    return Err(ApiError::new(404, format!("Secret '{}' not found in project '{}'", key, project.name)));
+                }
+                self.storage.save_project_with_key(&project, &project_key)?;
+                Ok(ApiResponse::json(200, &json!({ "project": project.name, "key": key, "deleted": true })))
+            }
+            ("GET", ["v1", "projects", project, "export"]) => {
+                let format = query.split('&')
+                    .find_map(|pair| pair.strip_prefix("format="))
+                    .unwrap_or("env");
+                let format = ExportFormat::parse(format).map_err(|e| ApiError::new(400, e.to_string()))?;
+                let (project, _) = self.open(project)?;
+                Ok(ApiResponse {
+                    status: 200,
+                    content_type: match format {
+                        ExportFormat::Json => "application/json",
+                        _ => "text/plain; charset=utf-8",
+                    },
+                    body: Zeroizing::new(format.format(&project)?),
+                })
+            }
+            (_, ["v1", "projects"])
+            | (_, ["v1", "projects", _, "unlock" | "lock" | "secrets" | "export"])
+            | (_, ["v1", "projects", _, "secrets", _]) => Err(ApiError::new(405, "Method not allowed")),
+            _ => Err(ApiError::new(404, format!("No such endpoint: {}", path))),
+        }
+    }
+
+    fn keys(&self) -> std::sync::MutexGuard<'_, HashMap<String, ProjectKey>> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_exists(&self, project_name: &str) -> std::result::Result<(), ApiError> {
+        storage::validate_name(project_name)?;
+        if self.storage.project_exists(project_name) {
+            Ok(())
+        } else {
+            Err(ApiError::new(404, format!("Project '{}' not found", project_name)))
+        }
+    }
+
+    /// Unlocks a project with a password and/or keyfile and keeps its key for later requests
+    fn unlock(&self, project_name: &str, body: &[u8]) -> ApiResult {
+        self.check_exists(project_name)?;
+        let body: UnlockBody = serde_json::from_slice(body)?;
+        let keyfile = body.keyfile.as_deref().map(crypto::read_keyfile).transpose()?;
+        let credentials = Credentials::new(body.password.map(SecretString::from), keyfile)?;
+
+        let encrypted = self.storage.load_encrypted(project_name)?;
+        if crypto::is_legacy(&encrypted) {
+            return Err(ApiError::new(409, format!(
+                "Project '{}' uses an old file format; open it once with the CLI to upgrade it",
+                project_name
+            )));
+        }
+        let (key, slot) = crypto::unlock_key(&encrypted, &credentials).map_err(|e| match e {
+            // Credentials of the wrong kind are as much a failed unlock as a wrong password
+            Error::InvalidCredentials(message) => ApiError::new(403, message),
+            e => e.into(),
+        })?;
+        self.keys().insert(project_name.to_string(), key);
+        Ok(ApiResponse::json(200, &json!({ "project": project_name, "unlocked": true, "slot": slot })))
+    }
+
+    /// Loads a project with the key unlocked earlier, or the agent's cached one
+    fn open(&self, project_name: &str) -> std::result::Result<(Project, ProjectKey), ApiError> {
+        self.check_exists(project_name)?;
+
+        let cached = self.keys().get(project_name).cloned();
+        let key = match cached {
+            Some(key) => Some(key),
+            None => self.agent.as_ref().and_then(|agent| agent.get_key(project_name).ok().flatten()),
+        };
+        let Some(key) = key else {
+            return Err(ApiError::new(403, format!(
+                "Project '{}' is locked; POST /v1/projects/{}/unlock first",
+                project_name, project_name
+            )));
+        };
+
+        match self.storage.load_project_with_key(project_name, &key) {
+            Ok(project) => Ok((project, key)),
+            // The project was re-keyed since it was unlocked
+            Err(Error::StaleKey(_)) => {
+                self.keys().remove(project_name);
+                Err(ApiError::new(403, format!(
+                    "The key for project '{}' no longer works; unlock it again",
+                    project_name
+                )))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A random token for a server started without `SECRETS_MANAGER_API_TOKEN`
+pub fn generate_token() -> SecretString {
+    let mut bytes = Zeroizing::new([0u8; 32]);
+    OsRng.fill_bytes(bytes.as_mut());
+    SecretString::new(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Decodes `%XX` escapes in a path segment
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    const AUTH: Option<&str> = Some("Bearer test-token");
+
+    fn server() -> ApiServer<MemoryBackend> {
+        let storage = SecretStorage::with_backend(MemoryBackend::new());
+        let mut project = Project::new("api".to_string());
+        project.add_secret("TOKEN".to_string(), "abc".to_string());
+        storage.save_project(&project, "password").unwrap();
+        ApiServer::new(storage, SecretString::from("test-token"))
+    }
+
+    fn call(server: &ApiServer<MemoryBackend>, method: &str, url: &str, body: &str) -> (u16, serde_json::Value) {
+        let response = server.handle(method, url, AUTH, body.as_bytes());
+        (response.status, serde_json::from_str(&response.body).unwrap_or(serde_json::Value::Null))
+    }
+
+    #[test]
+    fn test_requests_need_the_token() {
+        let server = server();
+        assert_eq!(server.handle("GET", "/v1/projects", None, b"").status, 401);
+        assert_eq!(server.handle("GET", "/v1/projects", Some("Bearer wrong-token"), b"").status, 401);
+        assert_eq!(call(&server, "GET", "/v1/projects", ""), (200, json!({ "projects": ["api"] })));
+    }
+
+    #[test]
+    fn test_unlock_then_get_set_delete_export() {
+        let server = server();
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets/TOKEN", "").0, 403);
+        assert_eq!(call(&server, "POST", "/v1/projects/api/unlock", r#"{"password":"nope"}"#).0, 403);
+        assert_eq!(call(&server, "POST", "/v1/projects/api/unlock", r#"{"password":"password"}"#).0, 200);
+
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets/TOKEN", "").1["value"], "abc");
+        assert_eq!(call(&server, "PUT", "/v1/projects/api/secrets/DB_URL", r#"{"value":"postgres://x"}"#).0, 200);
+        assert_eq!(call(&server, "DELETE", "/v1/projects/api/secrets/TOKEN", "").0, 200);
+        assert_eq!(call(&server, "DELETE", "/v1/projects/api/secrets/TOKEN", "").0, 404);
+        assert_eq!(
+            call(&server, "GET", "/v1/projects/api/secrets", "").1["secrets"],
+            json!({ "DB_URL": "postgres://x" })
+        );
+
+        let export = server.handle("GET", "/v1/projects/api/export?format=env", AUTH, b"");
+        assert_eq!(export.body.as_str(), "DB_URL=postgres://x\n");
+        assert_eq!(server.storage.load_project("api", "password").unwrap().secrets.len(), 1);
+
+        assert_eq!(call(&server, "POST", "/v1/projects/api/lock", "").0, 200);
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets", "").0, 403);
+        assert_eq!(call(&server, "GET", "/v1/projects/missing/secrets", "").0, 404);
+    }
+
+    #[test]
+    fn test_project_names_are_checked() {
+        let server = server();
+        assert_eq!(call(&server, "GET", "/v1/projects/..%2F..%2Fx/secrets", "").0, 400);
+        assert_eq!(call(&server, "POST", "/v1/projects/a%5Cb/unlock", r#"{"password":"password"}"#).0, 400);
+        assert_eq!(call(&server, "GET", "/v1/projects/%2E%2E/export", "").0, 400);
+    }
+
+    #[test]
+    fn test_only_a_stale_key_is_forgotten() {
+        let server = server();
+        call(&server, "POST", "/v1/projects/api/unlock", r#"{"password":"password"}"#);
+
+        // A damaged file is reported as such and the key is kept
+        let stored = server.storage.backend().get("api").unwrap().unwrap();
+        server.storage.backend().put("api", b"not json").unwrap();
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets", "").0, 500);
+        server.storage.backend().put("api", &stored).unwrap();
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets", "").0, 200);
+
+        // Re-keying the project makes the key useless
+        let project = server.storage.load_project("api", "password").unwrap();
+        server.storage.save_project(&project, "password").unwrap();
+        assert_eq!(call(&server, "GET", "/v1/projects/api/secrets", "").0, 403);
+        assert!(server.keys().is_empty());
+    }
+}
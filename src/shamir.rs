@@ -0,0 +1,276 @@
+use anyhow::{Result, anyhow};
+use rand::{Rng, RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
+
+/// Prefix of encoded shares; bumped if the encoding ever changes
+const SHARE_PREFIX: &str = "SMS1";
+/// Crockford base32, the same alphabet as recovery codes
+const ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// Length of the checksum appended to every share
+const CHECKSUM_LEN: usize = 4;
+
+/// One share of a secret split with Shamir's scheme over GF(256)
+///
+/// Encoded shares carry the threshold, their index, an identifier of the
+/// split they belong to, and a checksum so typos are caught on input.
+#[derive(Clone)]
+pub struct Share {
+    pub threshold: u8,
+    pub index: u8,
+    pub split_id: [u8; 4],
+    value: Zeroizing<Vec<u8>>,
+}
+
+impl Share {
+    /// Encodes the share as a printable string such as `SMS1-3ZK8Q-...`
+    pub fn encode(&self) -> Zeroizing<String> {
+        let mut body = Zeroizing::new(vec![self.threshold, self.index]);
+        body.extend_from_slice(&self.split_id);
+        body.extend_from_slice(&self.value);
+        let checksum = checksum(&body);
+        body.extend_from_slice(&checksum);
+
+        let encoded = Zeroizing::new(base32_encode(&body));
+        let groups: Vec<&str> = encoded.as_bytes()
+            .chunks(5)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+            .collect();
+        Zeroizing::new(format!("{}-{}", SHARE_PREFIX, groups.join("-")))
+    }
+
+    /// Parses and checksums an encoded share; dashes, spaces and case are ignored
+    pub fn decode(encoded: &str) -> Result<Self> {
+        let normalized: Zeroizing<String> = Zeroizing::new(encoded
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .map(|c| c.to_ascii_uppercase())
+            .collect());
+        let data = normalized.strip_prefix(SHARE_PREFIX)
+            .ok_or_else(|| anyhow!("Not a share: expected it to start with {}", SHARE_PREFIX))?;
+
+        let body = base32_decode(data)?;
+        if body.len() < 2 + 4 + 1 + CHECKSUM_LEN {
+            return Err(anyhow!("Share is too short - is part of it missing?"));
+        }
+        let (payload, expected) = body.split_at(body.len() - CHECKSUM_LEN);
+        if checksum(payload) != expected {
+            return Err(anyhow!("Share checksum mismatch - please check it for typos"));
+        }
+
+        let threshold = payload[0];
+        let index = payload[1];
+        if threshold < 2 || index == 0 {
+            return Err(anyhow!("Share has an invalid threshold or index"));
+        }
+        Ok(Self {
+            threshold,
+            index,
+            split_id: [payload[2], payload[3], payload[4], payload[5]],
+            value: Zeroizing::new(payload[6..].to_vec()),
+        })
+    }
+}
+
+impl std::fmt::Debug for Share {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Share")
+            .field("threshold", &self.threshold)
+            .field("index", &self.index)
+            .field("value", &"[REDACTED]")
+            .finish()
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(data);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Splits a secret into `shares` shares, any `threshold` of which reconstruct it
+pub fn split(secret: &[u8], threshold: u8, shares: u8) -> Result<Vec<Share>> {
+    if threshold < 2 {
+        return Err(anyhow!("Threshold must be at least 2"));
+    }
+    if shares < threshold {
+        return Err(anyhow!("Number of shares must be at least the threshold"));
+    }
+
+    let mut split_id = [0u8; 4];
+    OsRng.fill_bytes(&mut split_id);
+
+    let mut values: Vec<Zeroizing<Vec<u8>>> = (0..shares)
+        .map(|_| Zeroizing::new(Vec::with_capacity(secret.len())))
+        .collect();
+
+    for &byte in secret {
+        // Random polynomial of degree threshold - 1 whose constant term is the secret byte
+        let mut coefficients = Zeroizing::new(vec![byte]);
+        for degree in 1..threshold {
+            let coefficient = if degree == threshold - 1 {
+                OsRng.gen_range(1..=255u8)
+            } else {
+                OsRng.gen()
+            };
+            coefficients.push(coefficient);
+        }
+
+        for (i, value) in values.iter_mut().enumerate() {
+            value.push(evaluate(&coefficients, i as u8 + 1));
+        }
+    }
+
+    Ok(values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| Share { threshold, index: i as u8 + 1, split_id, value })
+        .collect())
+}
+
+/// Reconstructs the secret from at least `threshold` shares of the same split
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>> {
+    let first = shares.first().ok_or_else(|| anyhow!("No shares given"))?;
+    for share in shares {
+        if share.split_id != first.split_id || share.threshold != first.threshold {
+            return Err(anyhow!("Shares come from different splits"));
+        }
+        if share.value.len() != first.value.len() {
+            return Err(anyhow!("Shares have different lengths"));
+        }
+    }
+
+    let mut unique: Vec<&Share> = Vec::new();
+    for share in shares {
+        if !unique.iter().any(|s| s.index == share.index) {
+            unique.push(share);
+        }
+    }
+    if unique.len() < first.threshold as usize {
+        return Err(anyhow!(
+            "{} distinct shares given but {} are required",
+            unique.len(), first.threshold
+        ));
+    }
+    let unique = &unique[..first.threshold as usize];
+
+    // Lagrange interpolation at x = 0
+    let mut secret = Zeroizing::new(vec![0u8; first.value.len()]);
+    for (i, share) in unique.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, other) in unique.iter().enumerate() {
+            if i != j {
+                basis = gf_mul(basis, gf_div(other.index, other.index ^ share.index));
+            }
+        }
+        for (byte, value) in secret.iter_mut().zip(share.value.iter()) {
+            *byte ^= gf_mul(basis, *value);
+        }
+    }
+    Ok(secret)
+}
+
+/// Evaluates a polynomial at `x` using Horner's method
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients.iter().rev().fold(0, |acc, &c| gf_mul(acc, x) ^ c)
+}
+
+/// Multiplication in GF(2^8) with the AES polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80;
+        a <<= 1;
+        if carry != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    // b^254 is the multiplicative inverse of b
+    let mut inverse = 1u8;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, b);
+    }
+    gf_mul(a, inverse)
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut output = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    output
+}
+
+fn base32_decode(data: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let mut output = Zeroizing::new(Vec::new());
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.chars() {
+        // Crockford base32 reads the easily confused letters as digits
+        let c = match c {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+        let value = ALPHABET.iter()
+            .position(|&a| a as char == c)
+            .ok_or_else(|| anyhow!("Share contains an invalid character '{}'", c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_combine() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        let encoded: Vec<_> = shares.iter().map(|s| s.encode()).collect();
+        let decoded: Vec<Share> = [&encoded[4], &encoded[0], &encoded[2]]
+            .iter()
+            .map(|s| Share::decode(&s.to_lowercase()).unwrap())
+            .collect();
+        assert_eq!(combine(&decoded).unwrap().as_slice(), secret);
+
+        // Two shares are not enough
+        assert!(combine(&decoded[..2]).is_err());
+    }
+
+    #[test]
+    fn test_typo_is_detected() {
+        let shares = split(&[42; 32], 2, 3).unwrap();
+        let mut encoded: Vec<char> = shares[0].encode().chars().collect();
+        encoded[12] = if encoded[12] == 'A' { 'B' } else { 'A' };
+        let encoded: String = encoded.into_iter().collect();
+
+        let err = Share::decode(&encoded).unwrap_err();
+        assert!(err.to_string().contains("checksum"));
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{Result, anyhow};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::{StorageBackend, StorageLock};
+use crate::error::Error;
+use crate::models::EncryptedProject;
+
+/// How long to wait for another writer before giving up on a lock
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keeps every project in a single SQLite database
+///
+/// Besides the encrypted blob, each row carries a few non-secret columns
+/// (format version, slot kinds, last write) so vaults can be inspected
+/// with plain SQL without unlocking anything.
+pub struct SqliteBackend {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path)
+            .map_err(|e| anyhow!("Could not open vault '{}': {}", path.display(), e))?;
+        conn.busy_timeout(LOCK_TIMEOUT)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS projects (
+                name TEXT PRIMARY KEY,
+                format_version INTEGER,
+                slot_kinds TEXT,
+                updated_at TEXT NOT NULL,
+                data BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS locks (
+                name TEXT PRIMARY KEY,
+                pid INTEGER NOT NULL,
+                locked_at TEXT NOT NULL
+            );",
+        )?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Non-secret columns read from the blob's unencrypted header, if it parses
+fn index_columns(data: &[u8]) -> (Option<u32>, Option<String>) {
+    match serde_json::from_slice::<EncryptedProject>(data) {
+        Ok(encrypted) => {
+            let mut kinds: Vec<String> = encrypted.slots.iter().map(|slot| slot.kind.to_string()).collect();
+            kinds.sort();
+            kinds.dedup();
+            (Some(encrypted.version), Some(kinds.join(",")))
+        }
+        Err(_) => (None, None),
+    }
+}
+
+fn upsert(conn: &Connection, name: &str, data: &[u8]) -> Result<()> {
+    let (version, slot_kinds) = index_columns(data);
+    conn.execute(
+        "INSERT INTO projects (name, format_version, slot_kinds, updated_at, data)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(name) DO UPDATE SET
+            format_version = excluded.format_version,
+            slot_kinds = excluded.slot_kinds,
+            updated_at = excluded.updated_at,
+            data = excluded.data",
+        params![name, version, slot_kinds, chrono::Utc::now().to_rfc3339(), data],
+    )?;
+    Ok(())
+}
+
+impl StorageBackend for SqliteBackend {
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let conn = self.conn();
+        Ok(conn
+            .query_row("SELECT data FROM projects WHERE name = ?1", params![name], |row| row.get(0))
+            .optional()?)
+    }
+
+    fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        upsert(&self.conn(), name, data)
+    }
+
+    fn put_many(&self, blobs: &[(String, Vec<u8>)]) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        for (name, data) in blobs {
+            upsert(&tx, name, data)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name FROM projects ORDER BY name")?;
+        let names = stmt.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(names)
+    }
+
+    fn delete(&self, name: &str) -> Result<bool> {
+        let deleted = self.conn().execute("DELETE FROM projects WHERE name = ?1", params![name])?;
+        Ok(deleted > 0)
+    }
+
+    fn lock(&self, name: &str) -> Result<StorageLock> {
+        let started = Instant::now();
+        loop {
+            let inserted = self.conn().execute(
+                "INSERT OR IGNORE INTO locks (name, pid, locked_at) VALUES (?1, ?2, ?3)",
+                params![name, std::process::id(), chrono::Utc::now().to_rfc3339()],
+            )?;
+            if inserted > 0 {
+                let conn = Arc::clone(&self.conn);
+                let name = name.to_string();
+                return Ok(StorageLock::new(move || {
+                    let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+                    let _ = conn.execute("DELETE FROM locks WHERE name = ?1", params![name]);
+                }));
+            }
+            if started.elapsed() >= LOCK_TIMEOUT {
+                return Err(Error::Locked(format!(
+                    "Project '{}' is locked by another process (delete its row from the vault's locks table if it is stale)",
+                    name
+                )).into());
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Project;
+    use crate::storage::SecretStorage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_sqlite_backend_storage() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecretStorage::with_backend(SqliteBackend::open(&temp_dir.path().join("vault.sqlite")).unwrap());
+
+        let mut project = Project::new("api".to_string());
+        project.add_secret("TOKEN".to_string(), "abc".to_string());
+        storage.save_project(&project, "password").unwrap();
+        storage.save_project(&Project::new("web".to_string()), "password").unwrap();
+
+        assert_eq!(storage.list_projects().unwrap(), vec!["api".to_string(), "web".to_string()]);
+        assert_eq!(storage.load_project("api", "password").unwrap().secrets, project.secrets);
+
+        let kinds: String = storage.backend().conn()
+            .query_row("SELECT slot_kinds FROM projects WHERE name = 'api'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(kinds, "password");
+
+        storage.delete_project("api").unwrap();
+        assert!(!storage.project_exists("api"));
+    }
+
+    #[test]
+    fn test_migrate_between_layouts() {
+        use crate::backend::{self, StorageLayout};
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let files = SecretStorage::with_backend(StorageLayout::Files.open(dir).unwrap());
+        files.save_project(&Project::new("api".to_string()), "password").unwrap();
+
+        assert_eq!(backend::migrate(dir, StorageLayout::Sqlite).unwrap(), 1);
+        assert_eq!(StorageLayout::detect(dir), StorageLayout::Sqlite);
+        assert!(!dir.join("api.encrypted").exists());
+        let sqlite = SecretStorage::with_backend(StorageLayout::Sqlite.open(dir).unwrap());
+        assert!(sqlite.load_project("api", "password").is_ok());
+        drop(sqlite);
+
+        assert!(backend::migrate(dir, StorageLayout::Sqlite).is_err());
+        assert_eq!(backend::migrate(dir, StorageLayout::Files).unwrap(), 1);
+        assert_eq!(StorageLayout::detect(dir), StorageLayout::Files);
+        assert!(files.load_project("api", "password").is_ok());
+    }
+}
@@ -1,112 +1,203 @@
-use std::path::PathBuf;
-use std::fs;
-use anyhow::{Result, anyhow};
-use dirs;
+use crate::backend::{self, Fork, StorageBackend, StorageLock};
+use crate::error::{Error, Result};
+use crate::models::{Project, EncryptedProject, KeySlot};
+use crate::crypto::{
+    encrypt_project, decrypt_project, encrypt_project_with_key, decrypt_project_with_key, ProjectKey,
+};
 
-use crate::models::{Project, EncryptedProject};
-use crate::crypto::{encrypt_project, decrypt_project};
-
-/// Manages encrypted storage of projects
-pub struct SecretStorage {
-    storage_dir: PathBuf,
+/// Manages encrypted storage of projects on top of a `StorageBackend`
+pub struct SecretStorage<B: StorageBackend = Box<dyn StorageBackend>> {
+    backend: B,
 }
 
 impl SecretStorage {
-    /// Creates a new SecretStorage instance
+    /// Opens the default `~/.secrets_manager` vault in whichever layout it uses
     pub fn new() -> Result<Self> {
-        let storage_dir = Self::get_storage_dir()?;
-        fs::create_dir_all(&storage_dir)?;
-        Ok(Self { storage_dir })
+        Ok(Self::with_backend(backend::open_default()?))
     }
-    
-    /// Gets the storage directory path
-    fn get_storage_dir() -> Result<PathBuf> {
-        let home_dir = dirs::home_dir()
-            .ok_or_else(|| anyhow!("Could not find home directory"))?;
-        Ok(home_dir.join(".secrets_manager"))
+}
+
+impl<B: StorageBackend> SecretStorage<B> {
+    /// Creates a storage on top of any backend
+    pub fn with_backend(backend: B) -> Self {
+        Self { backend }
     }
     
-    /// Gets the file path for a project
-    fn get_project_path(&self, project_name: &str) -> PathBuf {
-        self.storage_dir.join(format!("{}.encrypted", project_name))
+    pub fn backend(&self) -> &B {
+        &self.backend
     }
     
-    /// Saves a project with encryption
+    /// Saves a project protected by a single password slot
+    ///
+    /// Like every save, this fails with `Error::Locked` on backends with
+    /// versions if the project changed since it was read.
     pub fn save_project(&self, project: &Project, password: &str) -> Result<()> {
         let encrypted = encrypt_project(project, password)?;
-        let project_path = self.get_project_path(&project.name);
-        
-        let json_data = serde_json::to_string_pretty(&encrypted)?;
-        fs::write(project_path, json_data)?;
-        Ok(())
+        self.save_encrypted(encrypted, project.stored_version.as_deref())
+    }
+    
+    /// Saves a project with its data key, keeping the key slots already stored
+    pub fn save_project_with_key(&self, project: &Project, key: &ProjectKey) -> Result<()> {
+        validate_name(&project.name)?;
+        let _lock = self.lock(&project.name)?;
+        // The write stays conditional on the version the project was read at,
+        // so slots changed since then make it fail rather than be dropped
+        let slots = self.load_encrypted(&project.name)?.slots;
+        let encrypted = encrypt_project_with_key(project, key, slots)?;
+        self.write_encrypted(encrypted, project.stored_version.as_deref())
+    }
+    
+    /// Saves a project with its data key and an explicit set of key slots
+    pub fn save_project_with_slots(&self, project: &Project, key: &ProjectKey, slots: Vec<KeySlot>) -> Result<()> {
+        let encrypted = encrypt_project_with_key(project, key, slots)?;
+        self.save_encrypted(encrypted, project.stored_version.as_deref())
+    }
+    
+    /// Saves a merge of the stored project with some of its forks, superseding them
+    pub fn save_merged(&self, project: &Project, key: &ProjectKey, slots: Vec<KeySlot>, forks: &[String]) -> Result<()> {
+        validate_name(&project.name)?;
+        let mut encrypted = encrypt_project_with_key(project, key, slots)?;
+        let _lock = self.lock(&project.name)?;
+        self.keep_slot_ids(&mut encrypted);
+        let json_data = serde_json::to_string_pretty(&encrypted).map_err(anyhow::Error::from)?;
+        Ok(self.backend.put_merged(&project.name, json_data.as_bytes(), forks)?)
+    }
+    
+    /// Versions of a project written concurrently with the stored one
+    pub fn forks(&self, project_name: &str) -> Result<Vec<Fork>> {
+        validate_name(project_name)?;
+        Ok(self.backend.forks(project_name)?)
+    }
+    
+    /// Writes an encrypted project under the backend's lock for it
+    fn save_encrypted(&self, encrypted: EncryptedProject, version: Option<&str>) -> Result<()> {
+        validate_name(&encrypted.project_name)?;
+        let _lock = self.lock(&encrypted.project_name)?;
+        self.write_encrypted(encrypted, version)
+    }
+    
+    /// Carries the stored slot counter over, so ids of slots removed since stay retired
+    ///
+    /// A stored file that can't be read is about to be replaced anyway.
+    fn keep_slot_ids(&self, encrypted: &mut EncryptedProject) {
+        if let Ok(stored) = self.load_encrypted(&encrypted.project_name) {
+            encrypted.next_slot_id = encrypted.new_slot_id().max(stored.new_slot_id());
+        }
+    }
+    
+    fn write_encrypted(&self, mut encrypted: EncryptedProject, version: Option<&str>) -> Result<()> {
+        self.keep_slot_ids(&mut encrypted);
+        let json_data = serde_json::to_string_pretty(&encrypted).map_err(anyhow::Error::from)?;
+        Ok(self.backend.put_if(&encrypted.project_name, json_data.as_bytes(), version)?)
+    }
+    
+    /// Takes the backend's exclusive lock on a project
+    pub fn lock(&self, project_name: &str) -> Result<StorageLock> {
+        validate_name(project_name)?;
+        Ok(self.backend.lock(project_name)?)
     }
     
     /// Loads a project with decryption
     pub fn load_project(&self, project_name: &str, password: &str) -> Result<Project> {
-        let project_path = self.get_project_path(project_name);
+        let (encrypted, version) = self.load_encrypted_with_version(project_name)?;
+        let mut project = decrypt_project(&encrypted, password)?;
+        Self::check_project_name(project_name, &project)?;
+        project.stored_version = version;
+        Ok(project)
+    }
+    
+    /// Loads a project with its data key
+    pub fn load_project_with_key(&self, project_name: &str, key: &ProjectKey) -> Result<Project> {
+        let (encrypted, version) = self.load_encrypted_with_version(project_name)?;
+        let mut project = decrypt_project_with_key(&encrypted, key)?;
+        Self::check_project_name(project_name, &project)?;
+        project.stored_version = version;
+        Ok(project)
+    }
+    
+    /// Reads the encrypted form of a project without decrypting it
+    pub fn load_encrypted(&self, project_name: &str) -> Result<EncryptedProject> {
+        Ok(self.load_encrypted_with_version(project_name)?.0)
+    }
+    
+    /// Reads the encrypted form of a project and the backend's version of it, if any
+    pub fn load_encrypted_with_version(&self, project_name: &str) -> Result<(EncryptedProject, Option<String>)> {
+        validate_name(project_name)?;
+        let (json_data, version) = self.backend.get_versioned(project_name)?
+            .ok_or_else(|| Error::NotFound(project_name.to_string()))?;
+        let encrypted: EncryptedProject = serde_json::from_slice(&json_data).map_err(|e| Error::Corrupt(format!(
+            "Project '{}' is not a valid project file: {}",
+            project_name, e
+        )))?;
         
-        if !project_path.exists() {
-            return Err(anyhow!("Project '{}' not found", project_name));
+        // Reject files that were renamed or copied under a different project name
+        if encrypted.version >= 2 && encrypted.project_name != project_name {
+            return Err(Error::Corrupt(format!(
+                "File '{}.encrypted' belongs to project '{}' (was it renamed?)",
+                project_name, encrypted.project_name
+            )));
         }
-        
-        let json_data = fs::read_to_string(project_path)?;
-        let encrypted: EncryptedProject = serde_json::from_str(&json_data)?;
-        
-        decrypt_project(&encrypted, password)
+        Ok((encrypted, version))
+    }
+    
+    /// Legacy files carry no authenticated header, so compare against the decrypted name too
+    fn check_project_name(project_name: &str, project: &Project) -> Result<()> {
+        if project.name != project_name {
+            return Err(Error::Corrupt(format!(
+                "File '{}.encrypted' belongs to project '{}' (was it renamed?)",
+                project_name, project.name
+            )));
+        }
+        Ok(())
     }
     
     /// Lists all available projects
     pub fn list_projects(&self) -> Result<Vec<String>> {
-        let mut projects = Vec::new();
-        
-        if !self.storage_dir.exists() {
-            return Ok(projects);
-        }
-        
-        for entry in fs::read_dir(&self.storage_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("encrypted") {
-                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                    projects.push(stem.to_string());
-                }
-            }
-        }
-        
-        projects.sort();
-        Ok(projects)
+        Ok(self.backend.list()?)
     }
     
     /// Deletes a project
     pub fn delete_project(&self, project_name: &str) -> Result<()> {
-        let project_path = self.get_project_path(project_name);
-        
-        if !project_path.exists() {
-            return Err(anyhow!("Project '{}' not found", project_name));
+        validate_name(project_name)?;
+        let _lock = self.lock(project_name)?;
+        if !self.backend.delete(project_name)? {
+            return Err(Error::NotFound(project_name.to_string()));
         }
-        
-        fs::remove_file(project_path)?;
         Ok(())
     }
     
     /// Checks if a project exists
     pub fn project_exists(&self, project_name: &str) -> bool {
-        self.get_project_path(project_name).exists()
+        validate_name(project_name).is_ok() && matches!(self.backend.get(project_name), Ok(Some(_)))
     }
 }
 
+/// Rejects project names that can't be stored as a single file or object
+///
+/// Backends turn names into paths and keys, so a name must not be able to
+/// point outside the vault.
+pub fn validate_name(name: &str) -> Result<()> {
+    let unsafe_name = name.is_empty()
+        || name == "."
+        || name.contains("..")
+        || name.contains(['/', '\\', '\0']);
+    if unsafe_name {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::{DirectoryBackend, MemoryBackend};
+    use std::fs;
     use tempfile::TempDir;
 
     #[test]
     fn test_save_and_load_project() {
         let temp_dir = TempDir::new().unwrap();
-        let storage = SecretStorage {
-            storage_dir: temp_dir.path().to_path_buf(),
-        };
+        let storage = SecretStorage::with_backend(DirectoryBackend::new(temp_dir.path()).unwrap());
         
         let mut project = Project::new("test_project".to_string());
         project.add_secret("API_KEY".to_string(), "secret123".to_string());
@@ -119,12 +210,24 @@ mod tests {
         assert_eq!(project.secrets, loaded.secrets);
     }
     
+    #[test]
+    fn test_memory_backend_storage() {
+        let storage = SecretStorage::with_backend(MemoryBackend::new());
+        
+        let mut project = Project::new("api".to_string());
+        project.add_secret("TOKEN".to_string(), "abc".to_string());
+        storage.save_project(&project, "password").unwrap();
+        
+        assert!(storage.project_exists("api"));
+        assert_eq!(storage.load_project("api", "password").unwrap().secrets, project.secrets);
+        storage.delete_project("api").unwrap();
+        assert!(storage.list_projects().unwrap().is_empty());
+    }
+    
     #[test]
     fn test_list_projects() {
         let temp_dir = TempDir::new().unwrap();
-        let storage = SecretStorage {
-            storage_dir: temp_dir.path().to_path_buf(),
-        };
+        let storage = SecretStorage::with_backend(DirectoryBackend::new(temp_dir.path()).unwrap());
         
         let project1 = Project::new("project1".to_string());
         let project2 = Project::new("project2".to_string());
@@ -137,4 +240,45 @@ mod tests {
         assert!(projects.contains(&"project1".to_string()));
         assert!(projects.contains(&"project2".to_string()));
     }
+    
+    #[test]
+    fn test_renamed_project_file_is_rejected() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecretStorage::with_backend(DirectoryBackend::new(temp_dir.path()).unwrap());
+        
+        let project = Project::new("a".to_string());
+        storage.save_project(&project, "password").unwrap();
+        fs::rename(temp_dir.path().join("a.encrypted"), temp_dir.path().join("b.encrypted")).unwrap();
+        
+        let err = storage.load_project("b", "password").unwrap_err();
+        assert!(err.to_string().contains("renamed"));
+    }
+    
+    #[test]
+    fn test_errors_are_typed() {
+        let storage = SecretStorage::with_backend(MemoryBackend::new());
+        storage.save_project(&Project::new("api".to_string()), "password").unwrap();
+        
+        assert!(matches!(storage.load_project("api", "wrong"), Err(Error::WrongPassword)));
+        assert!(matches!(storage.load_project("nope", "password"), Err(Error::NotFound(name)) if name == "nope"));
+        
+        let _lock = storage.lock("api").unwrap();
+        assert!(matches!(storage.lock("api"), Err(Error::Locked(_))));
+        
+        storage.backend().put("broken", b"not json").unwrap();
+        assert!(matches!(storage.load_encrypted("broken"), Err(Error::Corrupt(_))));
+    }
+    
+    #[test]
+    fn test_names_cannot_leave_the_vault() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = SecretStorage::with_backend(DirectoryBackend::new(temp_dir.path().join("vault")).unwrap());
+        
+        for name in ["../outside", "a/b", "a\\b", "..", "", "nul\0"] {
+            assert!(matches!(storage.save_project(&Project::new(name.to_string()), "password"), Err(Error::InvalidName(_))), "{}", name);
+            assert!(matches!(storage.load_encrypted(name), Err(Error::InvalidName(_))), "{}", name);
+            assert!(!storage.project_exists(name));
+        }
+        assert!(!temp_dir.path().join("outside.encrypted").exists());
+    }
 }
@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose, Engine as _};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// A way to encode a value as it is written into a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// Standard base64 with padding
+    Base64,
+    /// Percent-encoding of everything but unreserved URL characters
+    Url,
+    /// Escaped for use inside a JSON string, without the surrounding quotes
+    Json,
+}
+
+impl Filter {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "base64" => Ok(Filter::Base64),
+            "url" => Ok(Filter::Url),
+            "json" => Ok(Filter::Json),
+            _ => Err(anyhow!("Unknown filter '{}'. Use: base64, url, or json", name)),
+        }
+    }
+
+    pub fn apply(self, value: &str) -> Result<Zeroizing<String>> {
+        Ok(Zeroizing::new(match self {
+            Filter::Base64 => general_purpose::STANDARD.encode(value),
+            Filter::Url => {
+                let mut encoded = String::with_capacity(value.len());
+                for byte in value.bytes() {
+                    if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+                        encoded.push(char::from(byte));
+                    } else {
+                        encoded.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                encoded
+            }
+            Filter::Json => {
+                let quoted = Zeroizing::new(serde_json::to_string(value)?);
+                quoted[1..quoted.len() - 1].to_string()
+            }
+        }))
+    }
+}
+
+/// Fills `{{ KEY }}` placeholders in a template with secrets
+///
+/// A placeholder can pass its value through filters, applied left to right:
+/// `{{ KEY | json }}`. Braces that don't start with a key name, such as
+/// `{{ .Values.port }}`, are copied as they are, but a key name that isn't
+/// one of the secrets is an error.
+pub fn render(template: &str, secrets: &HashMap<String, String>) -> Result<Zeroizing<String>> {
+    let mut output = Zeroizing::new(String::with_capacity(template.len()));
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let line = template.len() - rest.len() + start;
+        let line = template[..line].matches('\n').count() + 1;
+        let Some(end) = rest[start + 2..].find("}}") else {
+            output.push_str(&rest[start..]);
+            return Ok(output);
+        };
+
+        let inner = &rest[start + 2..start + 2 + end];
+        let mut parts = inner.split('|').map(str::trim);
+        let key = parts.next().unwrap_or_default();
+        if !is_key(key) {
+            output.push_str(&rest[start..start + 4 + end]);
+            rest = &rest[start + 4 + end..];
+            continue;
+        }
+
+        let mut value = Zeroizing::new(
+            secrets.get(key).ok_or_else(|| anyhow!("Unknown secret '{}' on line {}", key, line))?.clone(),
+        );
+        for name in parts {
+            let filter = Filter::parse(name).map_err(|e| anyhow!("{} on line {}", e, line))?;
+            value = filter.apply(&value)?;
+        }
+        output.push_str(&value);
+        rest = &rest[start + 4 + end..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn is_key(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_placeholders_and_filters() {
+        let secrets = HashMap::from([
+            ("DB_PASS".to_string(), "p@ss w\"rd$1".to_string()),
+            ("PORT".to_string(), "8080".to_string()),
+        ]);
+        let template = "port: {{PORT}}\nurl: x://u:{{ DB_PASS | url }}@h\njson: \"{{ DB_PASS | json }}\"\nb64: {{ DB_PASS|base64 }}\nhelm: {{ .Values.x }}\n";
+        assert_eq!(
+            render(template, &secrets).unwrap().as_str(),
+            "port: 8080\nurl: x://u:p%40ss%20w%22rd%241@h\njson: \"p@ss w\\\"rd$1\"\nb64: cEBzcyB3InJkJDE=\nhelm: {{ .Values.x }}\n",
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_unknown_keys_and_filters() {
+        let secrets = HashMap::from([("PORT".to_string(), "8080".to_string())]);
+        let err = render("a\n{{ MISSING }}", &secrets).unwrap_err().to_string();
+        assert_eq!(err, "Unknown secret 'MISSING' on line 2");
+        assert!(render("{{ PORT | rot13 }}", &secrets).unwrap_err().to_string().contains("Unknown filter 'rot13'"));
+    }
+}